@@ -0,0 +1,244 @@
+use crate::error::PngError;
+use crate::PngResult;
+use log::debug;
+use once_cell::sync::Lazy;
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions, Permissions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+/// Temporary files that have been created and not yet renamed or removed
+static TEMP_FILES: Lazy<Mutex<HashSet<PathBuf>>> = Lazy::new(Default::default);
+
+/// Ask running optimizations to stop as soon as possible
+///
+/// Optimizations in progress and any started afterwards return `PngError::Interrupted`
+/// without writing their output. Temporary files are removed, and files being replaced
+/// are left untouched. This is intended to be called from a Ctrl-C handler.
+pub fn interrupt() {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Whether `interrupt` has been called
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Remove the temporary files of all writes in progress
+///
+/// This is intended to be called before exiting from a Ctrl-C handler without waiting for the
+/// optimizations to stop, once `interrupt` has been called. No temporary files are created
+/// after `interrupt`, so none are left behind.
+pub fn remove_temp_files() {
+    for path in temp_files().drain() {
+        if let Err(e) = fs::remove_file(&path) {
+            debug!("unable to remove {}: {}", path.display(), e);
+        }
+    }
+}
+
+fn temp_files() -> MutexGuard<'static, HashSet<PathBuf>> {
+    // The set is valid even if a thread panicked while holding the lock
+    TEMP_FILES.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+pub(crate) fn check_interrupted() -> PngResult<()> {
+    if is_interrupted() {
+        Err(PngError::Interrupted)
+    } else {
+        Ok(())
+    }
+}
+
+/// A file written next to its destination and renamed over it once complete,
+/// so the destination never holds partial data
+///
+/// The file is removed if it is dropped before being persisted.
+///
+/// The rename replaces only the link at the destination: other hardlinks to the file keep the
+/// old contents, which is why `optimize` writes files with several links in place by default.
+#[derive(Debug)]
+pub(crate) struct TempFile {
+    file: File,
+    path: PathBuf,
+    dest: PathBuf,
+    permissions: Option<Permissions>,
+    persisted: bool,
+}
+
+impl TempFile {
+    pub fn new(dest: &Path) -> PngResult<TempFile> {
+        // Replace the file a symlink points to rather than the link itself
+        let dest = match fs::symlink_metadata(dest) {
+            Ok(meta) if meta.file_type().is_symlink() => {
+                fs::canonicalize(dest).map_err(|e| PngError::io(Some(dest), e))?
+            }
+            _ => dest.to_path_buf(),
+        };
+        TempFile::replacing(dest)
+    }
+
+    /// A temporary file that replaces `dest` itself, even if it is a symlink
+    pub fn replacing(dest: PathBuf) -> PngResult<TempFile> {
+        // Keep the permissions of the file being replaced
+        let permissions = fs::metadata(&dest).ok().map(|meta| meta.permissions());
+
+        let dir = match dest.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let name = dest
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        // Hold the lock until the file is registered, so `remove_temp_files` can't miss it
+        let mut temp_files = temp_files();
+        check_interrupted()?;
+        loop {
+            let path = dir.join(format!(
+                ".{}.{}-{}.tmp",
+                name,
+                process::id(),
+                TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+            ));
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => {
+                    temp_files.insert(path.clone());
+                    return Ok(TempFile {
+                        file,
+                        path,
+                        dest,
+                        permissions,
+                        persisted: false,
+                    });
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(PngError::io(Some(&dest), e)),
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The file that will be replaced
+    pub fn dest(&self) -> &Path {
+        &self.dest
+    }
+
+    pub fn write_all(&mut self, data: &[u8]) -> PngResult<()> {
+        self.file
+            .write_all(data)
+            .map_err(|e| PngError::io(Some(&self.path), e))
+    }
+
+    /// Use these permissions instead of those of the file being replaced
+    pub fn set_permissions(&mut self, permissions: Permissions) {
+        self.permissions = Some(permissions);
+    }
+
+    /// Flush the data to disk and rename the file over the destination
+    pub fn persist(mut self) -> PngResult<()> {
+        if let Some(permissions) = self.permissions.take() {
+            copy_permissions(permissions, &self.file, &self.path)?;
+        }
+        self.file
+            .sync_all()
+            .map_err(|e| PngError::io(Some(&self.path), e))?;
+        check_interrupted()?;
+        fs::rename(&self.path, &self.dest).map_err(|e| PngError::io(Some(&self.dest), e))?;
+        self.persisted = true;
+        temp_files().remove(&self.path);
+        sync_parent_dir(&self.dest);
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.persisted && temp_files().remove(&self.path) {
+            if let Err(e) = fs::remove_file(&self.path) {
+                debug!("unable to remove {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+/// Overwrite the contents of an existing file, keeping the file itself and all of its links
+///
+/// Unlike `TempFile`, the file is left partially written if this fails.
+pub(crate) fn write_in_place(dest: &Path, data: &[u8]) -> PngResult<()> {
+    check_interrupted()?;
+    let mut file = OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .open(dest)
+        .map_err(|e| PngError::io(Some(dest), e))?;
+    file.write_all(data)
+        .and_then(|_| file.sync_all())
+        .map_err(|e| PngError::io(Some(dest), e))
+}
+
+/// Write a file atomically, keeping the permissions of the file being replaced
+pub(crate) fn write_file(dest: &Path, data: &[u8]) -> PngResult<()> {
+    let mut temp = TempFile::new(dest)?;
+    temp.write_all(data)?;
+    temp.persist()
+}
+
+#[cfg(not(unix))]
+fn copy_permissions(permissions: Permissions, file: &File, path: &Path) -> PngResult<()> {
+    let readonly = permissions.readonly();
+    file.set_permissions(permissions)
+        .map_err(|e| PngError::io(Some(path), e))?;
+    let found = file
+        .metadata()
+        .map_err(|e| PngError::io(Some(path), e))?
+        .permissions()
+        .readonly();
+    if found != readonly {
+        debug!("readonly expected: {}, found: {}", readonly, found);
+        return Err(PngError::PermissionsNotPreserved(path.to_owned()));
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn copy_permissions(permissions: Permissions, file: &File, path: &Path) -> PngResult<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = permissions.mode();
+    file.set_permissions(permissions)
+        .map_err(|e| PngError::io(Some(path), e))?;
+    let found = file
+        .metadata()
+        .map_err(|e| PngError::io(Some(path), e))?
+        .permissions()
+        .mode();
+    if found != mode {
+        debug!("permissions expected: {:04o}, found: {:04o}", mode, found);
+        return Err(PngError::PermissionsNotPreserved(path.to_owned()));
+    }
+    Ok(())
+}
+
+/// Make the rename durable, a failure here only risks losing the rename on a crash
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    if let Err(e) = File::open(dir).and_then(|dir| dir.sync_all()) {
+        debug!("unable to sync {}: {}", dir.display(), e);
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_parent_dir(_: &Path) {}
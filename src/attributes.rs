@@ -0,0 +1,156 @@
+use crate::atomicwrite::TempFile;
+#[cfg(target_os = "linux")]
+use log::debug;
+use log::warn;
+use std::fs::Metadata;
+use std::path::Path;
+
+/// Extended attributes carried over with `preserve_attrs`: user attributes (such as download
+/// provenance), the SELinux label and the POSIX ACL
+#[cfg(target_os = "linux")]
+const PRESERVED_XATTRS: [&str; 3] = ["user.", "security.selinux", "system.posix_acl_access"];
+
+/// Copy the owner, group and extended attributes of the input file to the output before it is
+/// put in place, warning about those that can't be copied
+///
+/// Permissions and file times are copied separately.
+#[cfg_attr(not(unix), allow(unused_variables))]
+pub(crate) fn copy_attributes(input: &Path, metadata: &Metadata, temp: &TempFile) {
+    #[cfg(unix)]
+    copy_owner(metadata, temp);
+    #[cfg(target_os = "linux")]
+    copy_xattrs(input, temp);
+}
+
+#[cfg(unix)]
+fn copy_owner(metadata: &Metadata, temp: &TempFile) {
+    use std::io::ErrorKind;
+    use std::os::unix::fs::MetadataExt;
+
+    let current = match temp.path().metadata() {
+        Ok(current) => current,
+        Err(e) => {
+            warn!(
+                "{}: Unable to preserve the owner: {}",
+                temp.dest().display(),
+                e
+            );
+            return;
+        }
+    };
+    let mut group_done = metadata.gid() == current.gid();
+    if metadata.uid() != current.uid() {
+        let gid = if group_done {
+            None
+        } else {
+            Some(metadata.gid())
+        };
+        match chown(temp.path(), Some(metadata.uid()), gid) {
+            Ok(()) => group_done = true,
+            Err(e) if e.kind() == ErrorKind::PermissionDenied => warn!(
+                "{}: Unable to preserve the owner (uid {}), changing the owner of a file requires root",
+                temp.dest().display(),
+                metadata.uid()
+            ),
+            Err(e) => warn!(
+                "{}: Unable to preserve the owner: {}",
+                temp.dest().display(),
+                e
+            ),
+        }
+    }
+    if !group_done {
+        // Without root the group can still be changed, to one the user is a member of
+        match chown(temp.path(), None, Some(metadata.gid())) {
+            Ok(()) => (),
+            Err(e) if e.kind() == ErrorKind::PermissionDenied => warn!(
+                "{}: Unable to preserve the group (gid {}), the user is not a member of it",
+                temp.dest().display(),
+                metadata.gid()
+            ),
+            Err(e) => warn!(
+                "{}: Unable to preserve the group: {}",
+                temp.dest().display(),
+                e
+            ),
+        }
+    }
+}
+
+/// Change the owner and group of a file, leaving them as they are where `None`
+#[cfg(unix)]
+fn chown(path: &Path, uid: Option<libc::uid_t>, gid: Option<libc::gid_t>) -> std::io::Result<()> {
+    use std::ffi::CString;
+    use std::io::{Error, ErrorKind};
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    // -1 leaves the owner or group unchanged
+    let uid = uid.unwrap_or(libc::uid_t::MAX);
+    let gid = gid.unwrap_or(libc::gid_t::MAX);
+    // SAFETY: the path is a valid NUL-terminated string for the duration of the call
+    if unsafe { libc::chown(path.as_ptr(), uid, gid) } == 0 {
+        Ok(())
+    } else {
+        Err(Error::last_os_error())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn copy_xattrs(input: &Path, temp: &TempFile) {
+    use std::io::ErrorKind;
+
+    let names = match xattr::list(input) {
+        Ok(names) => names,
+        Err(e) => {
+            debug!(
+                "{}: unable to list extended attributes: {}",
+                input.display(),
+                e
+            );
+            return;
+        }
+    };
+    for name in names {
+        let name_str = name.to_string_lossy();
+        if !PRESERVED_XATTRS.iter().any(|&preserved| {
+            name_str == preserved || (preserved.ends_with('.') && name_str.starts_with(preserved))
+        }) {
+            debug!("not preserving extended attribute {}", name_str);
+            continue;
+        }
+        let value = match xattr::get(input, &name) {
+            Ok(Some(value)) => value,
+            Ok(None) => continue,
+            Err(e) => {
+                warn!(
+                    "{}: Unable to read extended attribute {}: {}",
+                    input.display(),
+                    name_str,
+                    e
+                );
+                continue;
+            }
+        };
+        match xattr::set(temp.path(), &name, &value) {
+            Ok(()) => debug!("preserved extended attribute {}", name_str),
+            Err(e) if e.kind() == ErrorKind::PermissionDenied => warn!(
+                "{}: Unable to preserve extended attribute {}, the user is not permitted to set it",
+                temp.dest().display(),
+                name_str
+            ),
+            Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => warn!(
+                "{}: Unable to preserve extended attribute {}, the file system does not support it",
+                temp.dest().display(),
+                name_str
+            ),
+            Err(e) => warn!(
+                "{}: Unable to preserve extended attribute {}: {}",
+                temp.dest().display(),
+                name_str,
+                e
+            ),
+        }
+    }
+}
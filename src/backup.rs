@@ -0,0 +1,228 @@
+use crate::atomicwrite::TempFile;
+use crate::deflate::crc32;
+use crate::error::PngError;
+use crate::headers::{file_header_is_valid, parse_next_header};
+use crate::{InFile, PngResult};
+use log::{debug, info, warn};
+use std::env;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Where to keep backups of the files replaced by `optimize`, and how many
+pub struct Backup {
+    /// Directory to keep the backups in, laid out as the files are relative to the current
+    /// directory (or by their full path, for files outside of it).
+    ///
+    /// `None` keeps each backup next to its file.
+    ///
+    /// Default: `None`
+    pub dir: Option<PathBuf>,
+    /// Appended to the file name to name the backup, as in `image.png.bak`
+    ///
+    /// Earlier versions named backups `image.bak.png`, which `restore_backup` does not find.
+    ///
+    /// Default: `.bak`
+    pub suffix: String,
+    /// How many backups to keep of each file
+    ///
+    /// Older backups are numbered from the newest, as in `image.png.bak.1`, and the oldest is
+    /// removed once there are more.
+    ///
+    /// Default: `1`
+    pub keep: usize,
+}
+
+impl Default for Backup {
+    fn default() -> Backup {
+        Backup {
+            dir: None,
+            suffix: ".bak".to_owned(),
+            keep: 1,
+        }
+    }
+}
+
+impl Backup {
+    /// Path of the `n`th newest backup of the file at `path`, counting from 0
+    fn numbered(&self, path: &Path, n: usize) -> PngResult<PathBuf> {
+        let mut name = path
+            .file_name()
+            .ok_or_else(|| PngError::NotAFile(path.to_owned()))?
+            .to_owned();
+        name.push(&self.suffix);
+        if n > 0 {
+            name.push(format!(".{}", n));
+        }
+        let dir = match self.dir {
+            Some(ref dir) => dir.join(relative_dir(path)?),
+            None => path.parent().map(Path::to_path_buf).unwrap_or_default(),
+        };
+        Ok(dir.join(name))
+    }
+}
+
+/// The directory of a file relative to the current directory, or the full path of the
+/// directory if it is outside of it
+fn relative_dir(path: &Path) -> PngResult<PathBuf> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let parent = fs::canonicalize(parent).map_err(|e| PngError::io(Some(parent), e))?;
+    let relative = env::current_dir()
+        .and_then(fs::canonicalize)
+        .ok()
+        .and_then(|current| parent.strip_prefix(current).ok().map(Path::to_path_buf));
+    Ok(relative.unwrap_or_else(|| {
+        parent
+            .components()
+            .filter(|c| matches!(c, Component::Normal(_)))
+            .collect()
+    }))
+}
+
+/// Path of the checksum kept next to the backup at `path`
+fn checksum_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".crc32");
+    PathBuf::from(name)
+}
+
+/// Move a backup and its checksum, returning `false` if there is no backup at `from`
+fn move_backup(from: &Path, to: &Path) -> PngResult<bool> {
+    match fs::rename(from, to) {
+        Ok(()) => (),
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(PngError::io(Some(from), e)),
+    }
+    let checksum = checksum_path(from);
+    match fs::rename(&checksum, checksum_path(to)) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(true),
+        Err(e) => Err(PngError::io(Some(&checksum), e)),
+    }
+}
+
+/// Remove a backup and its checksum, returning `false` if there is no backup at `path`
+fn remove_backup(path: &Path) -> PngResult<bool> {
+    match fs::remove_file(path) {
+        Ok(()) => debug!("removed {}", path.display()),
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(PngError::io(Some(path), e)),
+    }
+    let checksum = checksum_path(path);
+    match fs::remove_file(&checksum) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(true),
+        Err(e) => Err(PngError::io(Some(&checksum), e)),
+    }
+}
+
+/// Path of the newest backup of the file at `path`
+pub fn backup_path(path: &Path, backup: &Backup) -> PngResult<PathBuf> {
+    backup.numbered(path, 0)
+}
+
+/// Back up the file at `path` before it is replaced, if it exists, moving older backups along
+///
+/// The CRC-32 of each backup is kept next to it, in `image.png.bak.crc32`.
+pub(crate) fn perform_backup(path: &Path, backup: &Backup) -> PngResult<()> {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(PngError::io(Some(path), e)),
+    };
+    let data = fs::read(path).map_err(|e| PngError::io(Some(path), e))?;
+    let newest = backup.numbered(path, 0)?;
+    if let Some(dir) = newest.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir).map_err(|e| PngError::io(Some(dir), e))?;
+    }
+
+    // Remove backups left over from keeping more of them
+    let mut n = backup.keep;
+    while remove_backup(&backup.numbered(path, n)?)? {
+        n += 1;
+    }
+    for n in (1..backup.keep).rev() {
+        move_backup(&backup.numbered(path, n - 1)?, &backup.numbered(path, n)?)?;
+    }
+
+    let mut temp = TempFile::new(&newest)?;
+    temp.write_all(&data)?;
+    temp.set_permissions(metadata.permissions());
+    temp.persist()?;
+    let checksum = checksum_path(&newest);
+    let mut temp = TempFile::new(&checksum)?;
+    temp.write_all(format!("{:08x}\n", crc32(&data)).as_bytes())?;
+    temp.persist()?;
+    info!("Backup: {}", newest.display());
+    Ok(())
+}
+
+/// Put the newest backup of a file back in its place
+///
+/// The backup must match the checksum kept next to it, if there is one, and have a valid PNG
+/// signature and valid CRCs for all of its chunks. The restored file must read back with the
+/// same CRC-32 as the backup before it replaces the file.
+/// The backup is then removed and older backups move up, so restoring again goes back
+/// another step.
+pub fn restore_backup(input: &InFile, backup: &Backup) -> PngResult<()> {
+    let path = input
+        .path()
+        .ok_or(PngError::FileRequired("Restoring a backup"))?;
+    info!("Restoring: {}", input);
+
+    let newest = backup.numbered(path, 0)?;
+    let data = fs::read(&newest).map_err(|e| PngError::io(Some(&newest), e))?;
+    let checksum = checksum_path(&newest);
+    match fs::read_to_string(&checksum) {
+        Ok(expected) => {
+            if u32::from_str_radix(expected.trim(), 16).ok() != Some(crc32(&data)) {
+                return Err(PngError::BackupChecksumMismatch(newest));
+            }
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            warn!(
+                "{}: no checksum, only checking the chunks",
+                newest.display()
+            );
+        }
+        Err(e) => return Err(PngError::io(Some(&checksum), e)),
+    }
+    check_chunks(&data).map_err(|e| PngError::BackupDamaged {
+        path: newest.clone(),
+        source: Box::new(e),
+    })?;
+    let permissions = fs::metadata(&newest)
+        .map_err(|e| PngError::io(Some(&newest), e))?
+        .permissions();
+
+    let mut temp = TempFile::new(path)?;
+    temp.write_all(&data)?;
+    let written = fs::read(temp.path()).map_err(|e| PngError::io(Some(temp.path()), e))?;
+    if written.len() != data.len() || crc32(&written) != crc32(&data) {
+        return Err(PngError::BackupMismatch(path.to_owned()));
+    }
+    temp.set_permissions(permissions);
+    temp.persist()?;
+    info!("Output: {}", path.display());
+
+    remove_backup(&newest)?;
+    let mut n = 1;
+    while move_backup(&backup.numbered(path, n)?, &backup.numbered(path, n - 1)?)? {
+        n += 1;
+    }
+    Ok(())
+}
+
+/// Check the PNG signature and the CRC of every chunk up to IEND
+fn check_chunks(data: &[u8]) -> PngResult<()> {
+    if !data.get(0..8).map_or(false, file_header_is_valid) {
+        return Err(PngError::NotPNG);
+    }
+    let mut offset = 8;
+    while parse_next_header(data, &mut offset, false)?.is_some() {}
+    Ok(())
+}
@@ -0,0 +1,323 @@
+//! A record of optimized files, so unchanged files can be skipped on later runs
+//!
+//! Files are identified by the SHA-256 hash of their contents, and results by the SHA-256 hash
+//! of the options that affect the output, apart from the compression effort. A file is skipped
+//! if it is the result of an earlier run with the same options and the same or a stronger effort.
+
+use log::warn;
+use oxipng::{C2PAPolicy, Deflaters, Headers, Interlacing, MinSavings, Options, TrailingData};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// The first line of the cache file, it is discarded by other versions of oxipng
+const HEADER: &str = concat!("oxipng-cache ", env!("CARGO_PKG_VERSION"), " sha256");
+
+/// A SHA-256 hash
+type Sha256Sum = [u8; 32];
+
+/// How hard oxipng tried to compress a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Effort {
+    deflate: Deflaters,
+    /// Bit mask of the filters tried
+    filters: u16,
+    fast_evaluation: bool,
+}
+
+impl Effort {
+    fn new(opts: &Options) -> Effort {
+        Effort {
+            deflate: opts.deflate,
+            filters: opts.filter.iter().fold(0, |mask, &f| mask | 1 << f as u8),
+            fast_evaluation: opts.fast_evaluation,
+        }
+    }
+
+    /// Whether a result with this effort is at least as good as one with `other`
+    fn covers(&self, other: &Effort) -> bool {
+        let deflate = match (self.deflate, other.deflate) {
+            (
+                Deflaters::Libdeflater { compression: a },
+                Deflaters::Libdeflater { compression: b },
+            ) => a >= b,
+            #[cfg(feature = "zopfli")]
+            (Deflaters::Zopfli { iterations: a }, Deflaters::Zopfli { iterations: b }) => a >= b,
+            #[cfg(feature = "zopfli")]
+            (Deflaters::Zopfli { .. }, Deflaters::Libdeflater { .. }) => true,
+            #[cfg(feature = "zopfli")]
+            (Deflaters::Libdeflater { .. }, Deflaters::Zopfli { .. }) => false,
+        };
+        deflate
+            && self.filters & other.filters == other.filters
+            && (other.fast_evaluation || !self.fast_evaluation)
+    }
+
+    fn to_field(self) -> String {
+        let deflate = match self.deflate {
+            Deflaters::Libdeflater { compression } => format!("l{}", compression),
+            #[cfg(feature = "zopfli")]
+            Deflaters::Zopfli { iterations } => format!("z{}", iterations),
+        };
+        format!(
+            "{}:{:x}:{}",
+            deflate,
+            self.filters,
+            if self.fast_evaluation { "fast" } else { "full" }
+        )
+    }
+
+    fn from_field(field: &str) -> Option<Effort> {
+        let mut parts = field.split(':');
+        let mut deflate = parts.next()?.chars();
+        let deflate = match (deflate.next()?, deflate.as_str()) {
+            ('l', level) => Deflaters::Libdeflater {
+                compression: level.parse().ok()?,
+            },
+            #[cfg(feature = "zopfli")]
+            ('z', iterations) => Deflaters::Zopfli {
+                iterations: iterations.parse().ok()?,
+            },
+            _ => return None,
+        };
+        let filters = u16::from_str_radix(parts.next()?, 16).ok()?;
+        let fast_evaluation = match parts.next()? {
+            "fast" => true,
+            "full" => false,
+            _ => return None,
+        };
+        Some(Effort {
+            deflate,
+            filters,
+            fast_evaluation,
+        })
+    }
+}
+
+/// Identifies the contents of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct ContentKey {
+    len: u64,
+    hash: Sha256Sum,
+}
+
+impl ContentKey {
+    fn new(data: &[u8]) -> ContentKey {
+        ContentKey {
+            len: data.len() as u64,
+            hash: Sha256::digest(data).into(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Cache {
+    path: PathBuf,
+    entries: Mutex<HashMap<ContentKey, Vec<(Sha256Sum, Effort)>>>,
+    modified: AtomicBool,
+}
+
+impl Cache {
+    /// Load the cache file, starting afresh if it doesn't exist or `clear` is set
+    pub fn load(path: PathBuf, clear: bool) -> Result<Cache, String> {
+        let cache = Cache {
+            path,
+            entries: Mutex::new(HashMap::new()),
+            modified: AtomicBool::new(clear),
+        };
+        if clear {
+            return Ok(cache);
+        }
+        let contents = match fs::read_to_string(&cache.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(cache),
+            Err(e) => return Err(format!("{}: {}", cache.path.display(), e)),
+        };
+        let mut lines = contents.lines();
+        if lines.next() != Some(HEADER) {
+            warn!(
+                "{}: cache is from another version of oxipng, discarding it",
+                cache.path.display()
+            );
+            cache.modified.store(true, Ordering::Relaxed);
+            return Ok(cache);
+        }
+        for line in lines {
+            match parse_entry(line) {
+                Some((key, options_hash, effort)) => cache.insert(key, options_hash, effort),
+                None => warn!("{}: ignoring invalid cache entry", cache.path.display()),
+            }
+        }
+        cache.modified.store(false, Ordering::Relaxed);
+        Ok(cache)
+    }
+
+    /// Whether optimizing `input` into `output` can be skipped, as `input` is the result of an
+    /// earlier run with `opts` and `output` is either the same file or a copy of it
+    pub fn is_optimized(&self, input: &Path, output: &Path, opts: &Options) -> bool {
+        let data = match fs::read(input) {
+            Ok(data) => data,
+            Err(_) => return false,
+        };
+        let key = ContentKey::new(&data);
+        if !self.contains(key, options_hash(opts), Effort::new(opts)) {
+            return false;
+        }
+        output == input || fs::read(output).map_or(false, |o| o == data)
+    }
+
+    /// Record the result of an optimization with `opts`
+    pub fn record(&self, output: &Path, opts: &Options) {
+        let key = match fs::read(output) {
+            Ok(data) => ContentKey::new(&data),
+            Err(_) => return,
+        };
+        let (options_hash, effort) = (options_hash(opts), Effort::new(opts));
+        if !self.contains(key, options_hash, effort) {
+            self.insert(key, options_hash, effort);
+        }
+    }
+
+    fn contains(&self, key: ContentKey, options_hash: Sha256Sum, effort: Effort) -> bool {
+        let entries = self.entries.lock().unwrap();
+        entries.get(&key).map_or(false, |results| {
+            results
+                .iter()
+                .any(|&(hash, old)| hash == options_hash && old.covers(&effort))
+        })
+    }
+
+    fn insert(&self, key: ContentKey, options_hash: Sha256Sum, effort: Effort) {
+        let mut entries = self.entries.lock().unwrap();
+        let results = entries.entry(key).or_default();
+        // Drop results that the new one supersedes
+        results.retain(|&(hash, old)| hash != options_hash || !effort.covers(&old));
+        results.push((options_hash, effort));
+        self.modified.store(true, Ordering::Relaxed);
+    }
+
+    /// Write the cache file if it has changed
+    pub fn save(&self) -> Result<(), String> {
+        if !self.modified.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let mut contents = String::from(HEADER);
+        contents.push('\n');
+        for (key, results) in self.entries.lock().unwrap().iter() {
+            for (options_hash, effort) in results {
+                contents.push_str(&format!(
+                    "{} {} {} {}\n",
+                    to_hex(&key.hash),
+                    key.len,
+                    to_hex(options_hash),
+                    effort.to_field()
+                ));
+            }
+        }
+        // Replace the file in one go, so an interrupted run never leaves a damaged cache
+        let temp = self.path.with_extension(format!("{}.tmp", process::id()));
+        fs::write(&temp, contents)
+            .and_then(|_| fs::rename(&temp, &self.path))
+            .map_err(|e| {
+                fs::remove_file(&temp).ok();
+                format!("{}: {}", self.path.display(), e)
+            })
+    }
+}
+
+fn parse_entry(line: &str) -> Option<(ContentKey, Sha256Sum, Effort)> {
+    let mut fields = line.split(' ');
+    let hash = from_hex(fields.next()?)?;
+    let len = fields.next()?.parse().ok()?;
+    let options_hash = from_hex(fields.next()?)?;
+    let effort = Effort::from_field(fields.next()?)?;
+    if fields.next().is_some() {
+        return None;
+    }
+    Some((ContentKey { len, hash }, options_hash, effort))
+}
+
+fn to_hex(hash: &Sha256Sum) -> String {
+    hash.iter().fold(String::with_capacity(64), |mut hex, b| {
+        let _ = write!(hex, "{:02x}", b);
+        hex
+    })
+}
+
+fn from_hex(hex: &str) -> Option<Sha256Sum> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut hash = [0; 32];
+    for (byte, digits) in hash.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok()?;
+    }
+    Some(hash)
+}
+
+/// Hash the options that change the output other than by compressing it better
+fn options_hash(opts: &Options) -> Sha256Sum {
+    Sha256::digest(canonical_options(opts).as_bytes()).into()
+}
+
+/// The options that change the output other than by compressing it better, written out in a
+/// fixed format so the hash of them stays the same between builds
+///
+/// This includes the minimum savings, as a file left untouched because of them may still be
+/// optimized without them.
+fn canonical_options(opts: &Options) -> String {
+    let interlace = match opts.interlace {
+        None => "keep",
+        Some(Interlacing::None) => "0",
+        Some(Interlacing::Adam7) => "1",
+    };
+    let strip = match opts.strip {
+        Headers::None => "none".to_owned(),
+        Headers::Strip(ref chunks) => format!("strip:{}", chunks.join(",")),
+        Headers::Safe => "safe".to_owned(),
+        Headers::Keep(ref chunks) => {
+            let chunks: Vec<&str> = chunks.iter().map(String::as_str).collect();
+            format!("keep:{}", chunks.join(","))
+        }
+        Headers::All => "all".to_owned(),
+    };
+    let c2pa = match opts.c2pa {
+        C2PAPolicy::Skip => "skip",
+        C2PAPolicy::Strip => "strip",
+        C2PAPolicy::Keep => "keep",
+    };
+    let trailing_data = match opts.trailing_data {
+        TrailingData::Keep => "keep",
+        TrailingData::Strip => "strip",
+        TrailingData::Error => "error",
+    };
+    let min_savings = match opts.min_savings {
+        None => "none".to_owned(),
+        Some(MinSavings::Bytes(bytes)) => format!("{}b", bytes),
+        Some(MinSavings::Percent(percent)) => format!("{:x}%", percent.to_bits()),
+    };
+    let flag = |enabled: bool| if enabled { '1' } else { '0' };
+    format!(
+        "interlace={} strip={} c2pa={} trailing_data={} min_savings={} \
+         fix_errors={} convert_raw_profiles={} optimize_alpha={} reductions={}{}{}{}{}",
+        interlace,
+        strip,
+        c2pa,
+        trailing_data,
+        min_savings,
+        flag(opts.fix_errors),
+        flag(opts.convert_raw_profiles),
+        flag(opts.optimize_alpha),
+        flag(opts.bit_depth_reduction),
+        flag(opts.color_type_reduction),
+        flag(opts.palette_reduction),
+        flag(opts.grayscale_reduction),
+        flag(opts.idat_recoding),
+    )
+}
@@ -0,0 +1,594 @@
+//! Settings from the command line and from `oxipng.toml` configuration files
+//!
+//! A configuration file has default settings, named profiles and settings for the files
+//! matching a glob, relative to the directory of the configuration file:
+//!
+//! ```toml
+//! [options]
+//! opt = 3
+//! strip = "safe"
+//!
+//! [profiles.web]
+//! opt = 4
+//! strip = "all"
+//!
+//! [profiles.archive]
+//! opt = "max"
+//! zopfli = true
+//!
+//! [overrides."icons/**"]
+//! strip = "all"
+//!
+//! [overrides."photos/**"]
+//! keep = ["iCCP", "sRGB", "cICP", "gAMA", "cHRM"]
+//! ```
+//!
+//! The settings are applied in this order, each taking precedence over the ones before: the
+//! `[options]`, the profile picked with `--profile`, the overrides matching the file in the
+//! order they are written, and the command line flags.
+
+use crate::cli::files::{absolute, parse_size};
+use ignore::overrides::{Override, OverrideBuilder};
+use log::debug;
+use oxipng::{
+    Backup, C2PAPolicy, Headers, InFile, Interlacing, Limits, LinkPolicy, MinSavings, Options,
+    OptionsBuilder, RowFilter, TrailingData, VerifyMode,
+};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+#[cfg(feature = "zopfli")]
+use std::num::NonZeroU8;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const CONFIG_NAME: &str = "oxipng.toml";
+
+/// An optimization preset, from 0 to 6 or `max`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Preset {
+    Level(u8),
+    Name(String),
+}
+
+/// Chunk names, as a list or a comma-separated string
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Chunks {
+    List(Vec<String>),
+    Joined(String),
+}
+
+impl Chunks {
+    fn names(&self) -> Vec<String> {
+        match self {
+            Chunks::List(names) => names.iter().map(|x| x.trim().to_owned()).collect(),
+            Chunks::Joined(names) => names.split(',').map(|x| x.trim().to_owned()).collect(),
+        }
+    }
+}
+
+/// Optimization settings, named after the command line flags
+///
+/// Unset settings are left to the settings below them, and in the end to the defaults of
+/// `Options`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Settings {
+    pub opt: Option<Preset>,
+    pub filters: Option<Vec<u8>>,
+    pub fast: Option<bool>,
+    pub zc: Option<u8>,
+    pub zopfli: Option<bool>,
+    pub interlace: Option<u8>,
+    pub alpha: Option<bool>,
+    /// All of the bit depth, color type, palette and grayscale reductions
+    pub reductions: Option<bool>,
+    pub bit_depth_reduction: Option<bool>,
+    pub color_type_reduction: Option<bool>,
+    pub palette_reduction: Option<bool>,
+    pub grayscale_reduction: Option<bool>,
+    pub idat_recoding: Option<bool>,
+    pub strip: Option<Chunks>,
+    pub keep: Option<Chunks>,
+    pub convert_profiles: Option<bool>,
+    pub sidecar: Option<bool>,
+    pub c2pa: Option<String>,
+    pub trailing_data: Option<String>,
+    pub fix: Option<bool>,
+    pub force: Option<bool>,
+    pub min_savings: Option<String>,
+    pub preserve: Option<bool>,
+    pub symlinks: Option<String>,
+    pub hardlinks: Option<String>,
+    pub backup: Option<bool>,
+    /// Relative to the configuration file
+    pub backup_dir: Option<PathBuf>,
+    pub backup_suffix: Option<String>,
+    pub backup_keep: Option<usize>,
+    pub verify: Option<String>,
+    pub verify_fallback: Option<bool>,
+    pub max_pixels: Option<u64>,
+    pub max_decoded_size: Option<u64>,
+    pub max_chunk_size: Option<u32>,
+    pub max_memory: Option<u64>,
+    /// In seconds
+    pub timeout: Option<u64>,
+    /// Only set from the command line
+    #[serde(skip)]
+    pub check: Option<bool>,
+    /// Only set from the command line
+    #[serde(skip)]
+    pub pretend: Option<bool>,
+}
+
+impl Settings {
+    /// Take the settings that are set in `other`
+    fn merge(&mut self, other: &Settings) {
+        // Settings that replace each other
+        if other.strip.is_some() || other.keep.is_some() {
+            self.strip = None;
+            self.keep = None;
+        }
+        if other.zc.is_some() || other.zopfli.is_some() {
+            self.zc = None;
+            self.zopfli = None;
+        }
+        if other.reductions.is_some() {
+            self.bit_depth_reduction = None;
+            self.color_type_reduction = None;
+            self.palette_reduction = None;
+            self.grayscale_reduction = None;
+        }
+        macro_rules! take {
+            ($($field:ident),*) => {
+                $(
+                    if other.$field.is_some() {
+                        self.$field = other.$field.clone();
+                    }
+                )*
+            };
+        }
+        take!(
+            opt,
+            filters,
+            fast,
+            zc,
+            zopfli,
+            interlace,
+            alpha,
+            reductions,
+            bit_depth_reduction,
+            color_type_reduction,
+            palette_reduction,
+            grayscale_reduction,
+            idat_recoding,
+            strip,
+            keep,
+            convert_profiles,
+            sidecar,
+            c2pa,
+            trailing_data,
+            fix,
+            force,
+            min_savings,
+            preserve,
+            symlinks,
+            hardlinks,
+            backup,
+            backup_dir,
+            backup_suffix,
+            backup_keep,
+            verify,
+            verify_fallback,
+            max_pixels,
+            max_decoded_size,
+            max_chunk_size,
+            max_memory,
+            timeout,
+            check,
+            pretend
+        );
+    }
+
+    /// Check for settings that can't be used together
+    fn check_conflicts(&self) -> Result<(), String> {
+        if self.strip.is_some() && self.keep.is_some() {
+            return Err("strip and keep can't be used together".to_owned());
+        }
+        if self.zc.is_some() && self.zopfli.is_some() {
+            return Err("zc and zopfli can't be used together".to_owned());
+        }
+        Ok(())
+    }
+
+    pub fn to_options(&self) -> Result<Options, String> {
+        let mut builder = OptionsBuilder::new();
+        match self.opt {
+            Some(Preset::Level(level)) => builder = builder.preset(level),
+            Some(Preset::Name(ref name)) if name == "max" => builder = builder.max_compression(),
+            Some(Preset::Name(ref name)) => {
+                return Err(format!(
+                    "Optimization preset {} does not exist, expected 0-6 or max",
+                    name
+                ))
+            }
+            None => (),
+        }
+        if let Some(ref filters) = self.filters {
+            let filters = filters
+                .iter()
+                .map(|&f| RowFilter::try_from(f))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| format!("Invalid filters, expected 0-{}", RowFilter::LAST))?;
+            builder = builder.filters(filters);
+        }
+        if let Some(fast) = self.fast {
+            builder = builder.fast_evaluation(fast);
+        }
+        if let Some(level) = self.zc {
+            builder = builder.compression(level);
+        }
+        #[cfg(feature = "zopfli")]
+        if self.zopfli == Some(true) {
+            if let Some(iterations) = NonZeroU8::new(15) {
+                builder = builder.zopfli(iterations);
+            }
+        }
+        if let Some(interlace) = self.interlace {
+            let interlace = Interlacing::try_from(interlace)
+                .map_err(|_| format!("Invalid interlace type {}, expected 0 or 1", interlace))?;
+            builder = builder.interlace(Some(interlace));
+        }
+        if let Some(reductions) = self.reductions {
+            builder = builder.reductions(reductions);
+        }
+        if let Some(enabled) = self.bit_depth_reduction {
+            builder = builder.bit_depth_reduction(enabled);
+        }
+        if let Some(enabled) = self.color_type_reduction {
+            builder = builder.color_type_reduction(enabled);
+        }
+        if let Some(enabled) = self.palette_reduction {
+            builder = builder.palette_reduction(enabled);
+        }
+        if let Some(enabled) = self.grayscale_reduction {
+            builder = builder.grayscale_reduction(enabled);
+        }
+        if let Some(enabled) = self.idat_recoding {
+            builder = builder.idat_recoding(enabled);
+        }
+        if let Some(ref chunks) = self.keep {
+            builder = builder.strip(Headers::Keep(chunks.names().into_iter().collect()));
+        }
+        if let Some(ref chunks) = self.strip {
+            builder = builder.strip(parse_strip(chunks.names())?);
+        }
+        if let Some(enabled) = self.convert_profiles {
+            builder = builder.convert_raw_profiles(enabled);
+        }
+        if let Some(enabled) = self.sidecar {
+            builder = builder.sidecar(enabled);
+        }
+        if let Some(ref policy) = self.c2pa {
+            builder = builder.c2pa(match policy.as_str() {
+                "skip" => C2PAPolicy::Skip,
+                "strip" => C2PAPolicy::Strip,
+                "keep" => C2PAPolicy::Keep,
+                _ => return Err(invalid_value("c2pa policy", policy, "skip, strip or keep")),
+            });
+        }
+        if let Some(ref mode) = self.trailing_data {
+            builder = builder.trailing_data(match mode.as_str() {
+                "keep" => TrailingData::Keep,
+                "strip" => TrailingData::Strip,
+                "error" => TrailingData::Error,
+                _ => {
+                    return Err(invalid_value(
+                        "trailing data mode",
+                        mode,
+                        "keep, strip or error",
+                    ))
+                }
+            });
+        }
+        if let Some(enabled) = self.fix {
+            builder = builder.fix_errors(enabled);
+        }
+        if let Some(enabled) = self.force {
+            builder = builder.force(enabled);
+        }
+        if let Some(ref min_savings) = self.min_savings {
+            builder = builder.min_savings(Some(parse_min_savings(min_savings)?));
+        }
+        if let Some(enabled) = self.preserve {
+            builder = builder.preserve_attrs(enabled);
+        }
+        if let Some(ref policy) = self.symlinks {
+            builder = builder.symlinks(parse_link_policy("symlink policy", policy)?);
+        }
+        if let Some(ref policy) = self.hardlinks {
+            builder = builder.hardlinks(parse_link_policy("hardlink policy", policy)?);
+        }
+        if let Some(enabled) = self.backup {
+            builder = builder.backup(enabled);
+        }
+        builder = builder.backup_config(self.to_backup());
+        if let Some(ref mode) = self.verify {
+            builder = builder.verify(match mode.as_str() {
+                "off" => VerifyMode::Off,
+                "visible" => VerifyMode::Visible,
+                "strict" => VerifyMode::Strict,
+                _ => return Err(invalid_value("verify mode", mode, "off, visible or strict")),
+            });
+        }
+        if let Some(enabled) = self.verify_fallback {
+            builder = builder.verify_fallback(enabled);
+        }
+        builder = builder.limits(Limits {
+            max_pixels: self.max_pixels,
+            max_decoded_bytes: self.max_decoded_size,
+            max_chunk_size: self.max_chunk_size,
+            max_memory: self.max_memory,
+        });
+        if let Some(secs) = self.timeout {
+            builder = builder.timeout(Some(Duration::from_secs(secs)));
+        }
+        if let Some(enabled) = self.check {
+            builder = builder.check(enabled);
+        }
+        if let Some(enabled) = self.pretend {
+            builder = builder.pretend(enabled);
+        }
+        builder.build().map_err(|e| e.to_string())
+    }
+
+    fn to_backup(&self) -> Backup {
+        let default = Backup::default();
+        Backup {
+            dir: self.backup_dir.clone(),
+            suffix: self.backup_suffix.clone().unwrap_or(default.suffix),
+            keep: self.backup_keep.unwrap_or(default.keep),
+        }
+    }
+}
+
+fn invalid_value(what: &str, value: &str, expected: &str) -> String {
+    format!("Invalid {} {}, expected {}", what, value, expected)
+}
+
+fn parse_link_policy(what: &str, policy: &str) -> Result<LinkPolicy, String> {
+    match policy {
+        "write-through" => Ok(LinkPolicy::WriteThrough),
+        "replace" => Ok(LinkPolicy::Replace),
+        "skip" => Ok(LinkPolicy::Skip),
+        _ => Err(invalid_value(
+            what,
+            policy,
+            "write-through, replace or skip",
+        )),
+    }
+}
+
+/// Parse a size in bytes, or a percentage such as `2%`
+fn parse_min_savings(value: &str) -> Result<MinSavings, String> {
+    match value.strip_suffix('%') {
+        Some(percent) => percent
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|percent| (0.0..=100.0).contains(percent))
+            .map(MinSavings::Percent)
+            .ok_or_else(|| invalid_value("minimum savings", value, "e.g. 1k or 2%")),
+        None => parse_size(value).map(MinSavings::Bytes),
+    }
+}
+
+/// Parse the chunks to strip, or `safe`, `all` or `none`
+fn parse_strip(names: Vec<String>) -> Result<Headers, String> {
+    if names.len() == 1 {
+        match names[0].as_str() {
+            "safe" => return Ok(Headers::Safe),
+            "all" => return Ok(Headers::All),
+            "none" => return Ok(Headers::None),
+            _ => (),
+        }
+    } else if names
+        .iter()
+        .any(|x| x == "safe" || x == "all" || x == "none")
+    {
+        return Err("'safe', 'all' or 'none' for strip should be used by themselves".to_owned());
+    }
+    const FORBIDDEN_CHUNKS: [[u8; 4]; 5] = [*b"IHDR", *b"IDAT", *b"tRNS", *b"PLTE", *b"IEND"];
+    for name in &names {
+        if FORBIDDEN_CHUNKS
+            .iter()
+            .any(|chunk| chunk == name.as_bytes())
+        {
+            return Err(format!("{} chunk is not allowed to be stripped", name));
+        }
+    }
+    Ok(Headers::Strip(names))
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    options: Settings,
+    profiles: HashMap<String, Settings>,
+    /// Kept as a table to keep the overrides in order
+    overrides: toml::value::Table,
+}
+
+/// The settings for each file
+#[derive(Debug)]
+pub struct Config {
+    /// The default settings and the profile
+    base: Settings,
+    /// Settings for the files matching a glob
+    overrides: Vec<(String, Override, Settings)>,
+    /// Settings from the command line, which take precedence
+    cli: Settings,
+}
+
+impl Config {
+    /// Load the configuration file at `path`, or else the first `oxipng.toml` found in the
+    /// working directory or its parents
+    pub fn load(
+        path: Option<&Path>,
+        profile: Option<&str>,
+        cli: Settings,
+    ) -> Result<Config, String> {
+        let mut config = Config {
+            base: Settings::default(),
+            overrides: Vec::new(),
+            cli,
+        };
+        let path = match path {
+            Some(path) => Some(path.to_owned()),
+            None => find_config(),
+        };
+        if let Some(path) = path {
+            config
+                .read(&path, profile)
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+        } else if let Some(profile) = profile {
+            return Err(format!(
+                "Profile {} can't be used, no {} was found",
+                profile, CONFIG_NAME
+            ));
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Settings only from the command line
+    pub fn without_file(cli: Settings) -> Result<Config, String> {
+        let config = Config {
+            base: Settings::default(),
+            overrides: Vec::new(),
+            cli,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn read(&mut self, path: &Path, profile: Option<&str>) -> Result<(), String> {
+        let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let file: ConfigFile = toml::from_str(&contents).map_err(|e| e.to_string())?;
+        debug!("Using settings from {}", path.display());
+
+        file.options.check_conflicts()?;
+        self.base = file.options;
+        for (name, settings) in &file.profiles {
+            settings
+                .check_conflicts()
+                .map_err(|e| format!("profile {}: {}", name, e))?;
+        }
+        if let Some(name) = profile {
+            let settings = file
+                .profiles
+                .get(name)
+                .ok_or_else(|| format!("no profile named {}", name))?;
+            self.base.merge(settings);
+        }
+
+        let root = resolve(path.parent().unwrap_or_else(|| Path::new("")));
+        self.base.backup_dir = self.base.backup_dir.take().map(|dir| root.join(dir));
+        for (glob, settings) in file.overrides {
+            let settings: Settings = settings
+                .try_into()
+                .and_then(|settings: Settings| {
+                    settings
+                        .check_conflicts()
+                        .map_err(serde::de::Error::custom)?;
+                    Ok(Settings {
+                        backup_dir: settings.backup_dir.as_ref().map(|dir| root.join(dir)),
+                        ..settings
+                    })
+                })
+                .map_err(|e| format!("override {}: {}", glob, e))?;
+            let mut matcher = OverrideBuilder::new(&root);
+            matcher
+                .add(&glob)
+                .map_err(|e| format!("override {}: {}", glob, e))?;
+            let matcher = matcher
+                .build()
+                .map_err(|e| format!("override {}: {}", glob, e))?;
+            self.overrides.push((glob, matcher, settings));
+        }
+        Ok(())
+    }
+
+    /// The settings for a file matching no overrides, then for each override on its own
+    fn combinations(&self) -> impl Iterator<Item = (Option<&str>, Settings)> + '_ {
+        let overrides = self
+            .overrides
+            .iter()
+            .map(|(glob, _, overrides)| (Some(glob.as_str()), Some(overrides)));
+        std::iter::once((None, None))
+            .chain(overrides)
+            .map(move |(glob, overrides)| {
+                let mut settings = self.base.clone();
+                if let Some(overrides) = overrides {
+                    settings.merge(overrides);
+                }
+                settings.merge(&self.cli);
+                (glob, settings)
+            })
+    }
+
+    /// Check that each combination of settings makes valid options
+    fn validate(&self) -> Result<(), String> {
+        for (glob, settings) in self.combinations() {
+            settings.to_options().map_err(|e| match glob {
+                Some(glob) => format!("override {}: {}", glob, e),
+                None => e,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Every backup configuration in use, from the base settings or an override
+    pub fn backups(&self) -> Vec<Backup> {
+        let mut backups = Vec::new();
+        for (_, settings) in self.combinations() {
+            if settings.backup == Some(true) {
+                let backup = settings.to_backup();
+                if !backups.contains(&backup) {
+                    backups.push(backup);
+                }
+            }
+        }
+        backups
+    }
+
+    /// The options to use for a file
+    pub fn options_for(&self, input: &InFile) -> Result<Options, String> {
+        let mut settings = self.base.clone();
+        if let InFile::Path(path) = input {
+            let path = resolve(path);
+            for (_, matcher, overrides) in &self.overrides {
+                if matcher.matched(&path, false).is_whitelist() {
+                    settings.merge(overrides);
+                }
+            }
+        }
+        settings.merge(&self.cli);
+        settings.to_options()
+    }
+}
+
+/// The path to match globs against, with `..` and symlinks resolved where possible
+fn resolve(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| absolute(path))
+}
+
+fn find_config() -> Option<PathBuf> {
+    let dir = env::current_dir().ok()?;
+    dir.ancestors()
+        .map(|dir| dir.join(CONFIG_NAME))
+        .find(|path| path.is_file())
+}
@@ -0,0 +1,345 @@
+//! Finding the files to optimize among the paths given on the command line
+
+use crate::cli::outputs::Outputs;
+use ignore::overrides::{Override, OverrideBuilder};
+use ignore::WalkBuilder;
+use log::{info, warn};
+use oxipng::{InFile, OutFile};
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Which files to pick up
+///
+/// The extension, glob and ignore file rules only apply to files found in directories, files
+/// given on the command line are always used. The size and modification time rules apply to all.
+#[derive(Debug, Clone)]
+pub struct FileFilter {
+    /// Recurse into directories given on the command line
+    pub recursive: bool,
+    /// Lowercase extensions to look for, any extension if empty
+    pub extensions: Vec<String>,
+    /// Only use files matching one of these globs, if there are any
+    pub include: Vec<String>,
+    /// Skip files and directories matching these globs
+    pub exclude: Vec<String>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub modified_since: Option<SystemTime>,
+    /// Skip files listed in `.gitignore`, `.ignore` and related files
+    pub ignore_files: bool,
+    /// Recurse into symlinked directories and use symlinked files
+    pub follow_symlinks: bool,
+    /// How deep to recurse, 1 only uses the files directly in the directory
+    pub max_depth: Option<usize>,
+}
+
+impl Default for FileFilter {
+    fn default() -> FileFilter {
+        FileFilter {
+            recursive: false,
+            extensions: vec!["png".to_owned(), "apng".to_owned()],
+            include: Vec::new(),
+            exclude: Vec::new(),
+            min_size: None,
+            max_size: None,
+            modified_since: None,
+            ignore_files: false,
+            follow_symlinks: false,
+            max_depth: None,
+        }
+    }
+}
+
+impl FileFilter {
+    /// Check that the globs are valid
+    pub fn validate(&self) -> Result<(), String> {
+        self.overrides(Path::new(".")).map(|_| ())
+    }
+
+    /// The globs, relative to `root`
+    fn overrides(&self, root: &Path) -> Result<Override, String> {
+        let mut builder = OverrideBuilder::new(root);
+        for glob in &self.include {
+            builder.add(glob).map_err(|e| e.to_string())?;
+        }
+        for glob in &self.exclude {
+            builder
+                .add(&format!("!{}", glob))
+                .map_err(|e| e.to_string())?;
+        }
+        builder.build().map_err(|e| e.to_string())
+    }
+
+    pub fn matches_extension(&self, path: &Path) -> bool {
+        self.extensions.is_empty()
+            || path.extension().map_or(false, |ext| {
+                self.extensions
+                    .contains(&ext.to_string_lossy().to_ascii_lowercase())
+            })
+    }
+
+    fn matches_metadata(&self, path: &Path) -> bool {
+        if self.min_size.is_none() && self.max_size.is_none() && self.modified_since.is_none() {
+            return true;
+        }
+        let meta = match fs::metadata(path) {
+            Ok(meta) => meta,
+            Err(e) => {
+                warn!("{}: {}", path.display(), e);
+                return false;
+            }
+        };
+        if self.min_size.map_or(false, |min| meta.len() < min)
+            || self.max_size.map_or(false, |max| meta.len() > max)
+        {
+            return false;
+        }
+        match (self.modified_since, meta.modified()) {
+            (Some(since), Ok(modified)) => modified >= since,
+            _ => true,
+        }
+    }
+
+    /// Pair each file with its output, reading stdin if `-` is the only path
+    pub fn collect(&self, paths: Vec<PathBuf>, outputs: &Outputs) -> Vec<(InFile, OutFile)> {
+        let mut in_out_pairs = Vec::new();
+        let allow_stdin = paths.len() == 1;
+        for input in paths {
+            if allow_stdin && input.to_str() == Some("-") {
+                in_out_pairs.push((InFile::StdIn, outputs.file.clone()));
+            } else if input.is_dir() {
+                if self.recursive {
+                    self.walk(&input, outputs, None, &mut in_out_pairs);
+                } else {
+                    warn!("{} is a directory, skipping", input.display());
+                }
+            } else if self.matches_metadata(&input) {
+                let output = outputs.output_for(&input, Path::new(""));
+                in_out_pairs.push((InFile::Path(input), output));
+            }
+        }
+        in_out_pairs
+    }
+
+    /// Pair each of the `changed` files with its output, if `collect` would pick it up from
+    /// `paths`
+    ///
+    /// Only the directories leading to the changed files are read, rather than walking all of
+    /// `paths` again. The changed files must be absolute paths.
+    pub fn collect_changed(
+        &self,
+        paths: &[PathBuf],
+        changed: &HashSet<PathBuf>,
+        outputs: &Outputs,
+    ) -> Vec<(InFile, OutFile)> {
+        let mut in_out_pairs = Vec::new();
+        for input in paths {
+            if input.is_dir() {
+                if self.recursive {
+                    self.walk(input, outputs, Some(changed), &mut in_out_pairs);
+                }
+            } else if changed.contains(&absolute(input)) && self.matches_metadata(input) {
+                let output = outputs.output_for(input, Path::new(""));
+                in_out_pairs.push((InFile::Path(input.clone()), output));
+            }
+        }
+        in_out_pairs
+    }
+
+    /// Walk `root` for files, only entering directories that lead to one of `only` if given
+    fn walk(
+        &self,
+        root: &Path,
+        outputs: &Outputs,
+        only: Option<&HashSet<PathBuf>>,
+        in_out_pairs: &mut Vec<(InFile, OutFile)>,
+    ) {
+        let overrides = match self.overrides(root) {
+            Ok(overrides) => overrides,
+            Err(e) => {
+                warn!("{}: {}", root.display(), e);
+                return;
+            }
+        };
+        let mut builder = WalkBuilder::new(root);
+        builder
+            .standard_filters(false)
+            .overrides(overrides)
+            .follow_links(self.follow_symlinks)
+            .max_depth(self.max_depth)
+            .sort_by_file_name(|a, b| a.cmp(b));
+        if let Some(only) = only {
+            let only = only.clone();
+            builder.filter_entry(move |entry| {
+                let path = absolute(entry.path());
+                only.iter().any(|changed| changed.starts_with(&path))
+            });
+        }
+        if self.ignore_files {
+            builder
+                .ignore(true)
+                .git_ignore(true)
+                .git_global(true)
+                .git_exclude(true)
+                .parents(true);
+        }
+        for entry in builder.build() {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    warn!("{}", e);
+                    continue;
+                }
+            };
+            let path = entry.path();
+            // Followed links report the type of the file they point to
+            let is_file = match entry.file_type() {
+                Some(file_type) if file_type.is_symlink() => {
+                    // The file may be outside of the directory being walked
+                    if path.is_file() && self.matches_extension(path) {
+                        info!("{} is a symlink, skipping", path.display());
+                    }
+                    false
+                }
+                Some(file_type) => file_type.is_file(),
+                None => false,
+            };
+            if !is_file || !self.matches_extension(path) || !self.matches_metadata(path) {
+                continue;
+            }
+            let rel_dir = path
+                .strip_prefix(root)
+                .ok()
+                .and_then(Path::parent)
+                .unwrap_or_else(|| Path::new(""));
+            let output = outputs.output_for(path, rel_dir);
+            in_out_pairs.push((InFile::Path(path.to_owned()), output));
+        }
+    }
+}
+
+/// Drop files that would be written to the same output as an earlier file
+pub fn remove_duplicate_outputs(files: &mut Vec<(InFile, OutFile)>) {
+    let mut outputs = HashSet::new();
+    files.retain(|(input, output)| {
+        let path = match (input, output) {
+            (_, OutFile::Path(Some(path))) | (InFile::Path(path), OutFile::Path(None)) => path,
+            _ => return true,
+        };
+        if outputs.insert(path.clone()) {
+            true
+        } else {
+            warn!(
+                "{}: output {} is already written by another file, skipping",
+                input,
+                path.display()
+            );
+            false
+        }
+    });
+}
+
+/// Make a path absolute, without resolving symlinks as `fs::canonicalize` does
+pub fn absolute(path: &Path) -> PathBuf {
+    if path.is_absolute() {
+        path.to_owned()
+    } else {
+        env::current_dir().unwrap_or_default().join(path)
+    }
+}
+
+/// Read a list of paths separated by newlines, or by NUL bytes if `nul` is set, from a file or
+/// from stdin if `source` is `-`
+pub fn read_file_list(source: &Path, nul: bool) -> Result<Vec<PathBuf>, String> {
+    let mut data = Vec::new();
+    let result = if source.to_str() == Some("-") {
+        io::stdin().read_to_end(&mut data).map(|_| ())
+    } else {
+        fs::read(source).map(|contents| data = contents)
+    };
+    result.map_err(|e| format!("{}: {}", source.display(), e))?;
+    let separator = if nul { b'\0' } else { b'\n' };
+    Ok(data
+        .split(|&b| b == separator)
+        .map(|line| match line {
+            [line @ .., b'\r'] if !nul => line,
+            _ => line,
+        })
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let path = path_from_bytes(line);
+            // A `-` in the list is a file of that name, not stdin
+            if path.as_os_str() == "-" {
+                Path::new(".").join(path)
+            } else {
+                path
+            }
+        })
+        .collect())
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    PathBuf::from(OsStr::from_bytes(bytes))
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// Parse a size in bytes, with an optional `k`, `M` or `G` suffix for powers of 1024
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let (num, shift) = match size.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&size[..i], 10),
+        Some((i, 'm' | 'M')) => (&size[..i], 20),
+        Some((i, 'g' | 'G')) => (&size[..i], 30),
+        _ => (size, 0),
+    };
+    num.trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| format!("Invalid size {}, expected e.g. 500, 64k or 2M", size))
+}
+
+/// Parse an age such as `90s`, `30min`, `12h`, `7d` or `2w` into the time that long ago,
+/// or otherwise take the modification time of the file at that path
+///
+/// Minutes are `min` rather than `m`, which `parse_size` takes as MiB.
+pub fn parse_modified_since(value: &str) -> Result<SystemTime, String> {
+    const UNITS: [(&str, u64); 5] = [
+        ("s", 1),
+        ("min", 60),
+        ("h", 60 * 60),
+        ("d", 24 * 60 * 60),
+        ("w", 7 * 24 * 60 * 60),
+    ];
+    let age = UNITS.iter().find_map(|&(unit, secs)| {
+        value
+            .strip_suffix(unit)?
+            .parse::<u64>()
+            .ok()?
+            .checked_mul(secs)
+    });
+    if let Some(age) = age {
+        return SystemTime::now()
+            .checked_sub(Duration::from_secs(age))
+            .ok_or_else(|| format!("Age {} is too large", value));
+    }
+    fs::metadata(value)
+        .and_then(|meta| meta.modified())
+        .map_err(|e| {
+            format!(
+                "Invalid time {}, expected an age like 30min, 12h or 7d, or a file: {}",
+                value, e
+            )
+        })
+}
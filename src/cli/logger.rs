@@ -0,0 +1,128 @@
+use log::{LevelFilter, Log, Metadata, Record};
+use once_cell::sync::Lazy;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{stderr, Write};
+use std::sync::Mutex;
+
+/// The messages held back for a file, until every job processing it has finished
+#[derive(Default)]
+struct Group {
+    jobs: usize,
+    messages: Vec<String>,
+}
+
+/// The groups of the files being processed, keyed on the file name
+static GROUPS: Lazy<Mutex<HashMap<String, Group>>> = Lazy::new(Default::default);
+
+thread_local! {
+    /// The file that the job running on this thread is processing, if it is grouped
+    ///
+    /// While a job waits for parallel work, rayon may run another job on the same thread. That
+    /// job runs to completion before the first one continues, and puts the file back when it
+    /// finishes, so this always names the file of the job that is running.
+    static CURRENT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Makes a file current, and puts back the previous one when dropped, even on a panic
+struct Current {
+    previous: Option<String>,
+}
+
+impl Current {
+    fn set(file: String) -> Current {
+        let previous = CURRENT.with(|current| current.borrow_mut().replace(file));
+        Current { previous }
+    }
+}
+
+impl Drop for Current {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CURRENT.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+/// Writes log messages from oxipng to stderr, holding them back while a file is processed
+/// so the output of files processed at the same time is not interleaved
+#[derive(Debug)]
+struct Logger {
+    level: LevelFilter,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.level() <= self.level && metadata.target().starts_with("oxipng")
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let message = record.args().to_string();
+        let message = CURRENT.with(|current| {
+            let current = current.borrow();
+            let mut groups = GROUPS.lock().unwrap();
+            match current.as_ref().and_then(|file| groups.get_mut(file)) {
+                Some(group) => {
+                    group.messages.push(message);
+                    None
+                }
+                None => Some(message),
+            }
+        });
+        if let Some(message) = message {
+            let _ = writeln!(stderr(), "{}", message);
+        }
+    }
+
+    fn flush(&self) {
+        let _ = stderr().flush();
+    }
+}
+
+pub fn init(quiet: bool, verbose: bool) -> Result<(), String> {
+    let level = if quiet {
+        LevelFilter::Off
+    } else if verbose {
+        LevelFilter::Debug
+    } else {
+        LevelFilter::Info
+    };
+    log::set_logger(Box::leak(Box::new(Logger { level }))).map_err(|e| e.to_string())?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+/// Run `f` to process `file`, printing the log messages for the file together once no other
+/// job is processing it
+pub fn grouped<T>(file: &str, f: impl FnOnce() -> T) -> T {
+    GROUPS
+        .lock()
+        .unwrap()
+        .entry(file.to_owned())
+        .or_default()
+        .jobs += 1;
+    let result = {
+        let _current = Current::set(file.to_owned());
+        f()
+    };
+    let messages = {
+        let mut groups = GROUPS.lock().unwrap();
+        let group = groups.get_mut(file).unwrap();
+        group.jobs -= 1;
+        if group.jobs == 0 {
+            groups.remove(file).unwrap().messages
+        } else {
+            Vec::new()
+        }
+    };
+    if !messages.is_empty() {
+        let stderr = stderr();
+        let mut stderr = stderr.lock();
+        for message in messages {
+            let _ = writeln!(stderr, "{}", message);
+        }
+    }
+    result
+}
@@ -0,0 +1,9 @@
+//! Parts of the command line interface that are not needed by the library
+
+pub mod cache;
+pub mod config;
+pub mod files;
+pub mod logger;
+pub mod outputs;
+pub mod summary;
+pub mod watch;
@@ -0,0 +1,109 @@
+//! Where to write the output for each input file
+
+use oxipng::OutFile;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+const PLACEHOLDERS: [&str; 4] = ["dir", "name", "stem", "ext"];
+
+#[derive(Debug, Clone)]
+pub struct Outputs {
+    /// Output directory, mirroring the structure of the input directories
+    pub dir: Option<PathBuf>,
+    /// Output for files that are not written to `dir`
+    pub file: OutFile,
+    /// Template for the output file names
+    pub template: Option<Template>,
+}
+
+impl Outputs {
+    /// The output for an input file, found in the directory `rel_dir` below a directory
+    /// given on the command line
+    pub fn output_for(&self, input: &Path, rel_dir: &Path) -> OutFile {
+        let dir = match (&self.dir, &self.template) {
+            (Some(out_dir), _) => out_dir.join(rel_dir),
+            (None, Some(_)) => match input.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent.to_owned(),
+                _ => PathBuf::from("."),
+            },
+            (None, None) => return self.file.clone(),
+        };
+        let path = match self.template {
+            Some(ref template) => template.expand(&dir, input),
+            None => dir.join(input.file_name().unwrap_or_default()),
+        };
+        OutFile::Path(Some(path))
+    }
+}
+
+/// A part of an output name template
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Text(String),
+    Dir,
+    Name,
+    Stem,
+    Ext,
+}
+
+/// A template for output file names, such as `{stem}.min.{ext}`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    /// Parse a template, which may only use known placeholders
+    pub fn parse(template: &str) -> Result<Template, String> {
+        let mut segments = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let len = rest[start..].find('}').ok_or_else(|| {
+                format!("Unclosed placeholder in output name template {}", template)
+            })?;
+            if start > 0 {
+                segments.push(Segment::Text(rest[..start].to_owned()));
+            }
+            segments.push(match &rest[start + 1..start + len] {
+                "dir" => Segment::Dir,
+                "name" => Segment::Name,
+                "stem" => Segment::Stem,
+                "ext" => Segment::Ext,
+                key => {
+                    return Err(format!(
+                    "Unknown placeholder {{{}}} in output name template, expected one of {{{}}}",
+                    key,
+                    PLACEHOLDERS.join("}, {")
+                ))
+                }
+            });
+            rest = &rest[start + len + 1..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Text(rest.to_owned()));
+        }
+        Ok(Template { segments })
+    }
+
+    /// Fill in the template, the result is relative to `dir` unless the template uses `{dir}`
+    ///
+    /// Each placeholder is replaced once, so text in the input name that looks like a
+    /// placeholder is kept as it is, as are names that aren't valid UTF-8.
+    fn expand(&self, dir: &Path, input: &Path) -> PathBuf {
+        let mut name = OsString::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Text(text) => name.push(text),
+                Segment::Dir => name.push(dir),
+                Segment::Name => name.push(input.file_name().unwrap_or_default()),
+                Segment::Stem => name.push(input.file_stem().unwrap_or_default()),
+                Segment::Ext => name.push(input.extension().unwrap_or_default()),
+            }
+        }
+        if self.segments.contains(&Segment::Dir) {
+            PathBuf::from(name)
+        } else {
+            dir.join(name)
+        }
+    }
+}
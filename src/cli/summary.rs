@@ -0,0 +1,107 @@
+//! The outcome of a run, for the exit code and the summary printed at the end
+
+use log::{info, warn};
+use oxipng::{InFile, PngError};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Every file was optimized, or was already optimized
+pub const EXIT_SUCCESS: i32 = 0;
+/// Every file failed, or there were no files to process
+pub const EXIT_ALL_FAILED: i32 = 1;
+/// The flags, options or configuration file are invalid
+pub const EXIT_USAGE: i32 = 2;
+/// Some of the files failed
+pub const EXIT_SOME_FAILED: i32 = 3;
+/// No file failed, but some ran out of time and kept the best result found by then
+pub const EXIT_TIMED_OUT: i32 = 4;
+/// Stopped by Ctrl-C
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+#[derive(Debug, Default)]
+pub struct Summary {
+    succeeded: AtomicUsize,
+    skipped: AtomicUsize,
+    timed_out: Mutex<Vec<String>>,
+    failed: Mutex<Vec<(String, PngError)>>,
+}
+
+impl Summary {
+    pub fn succeeded(&self) {
+        self.succeeded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The file was left untouched, as a policy skips it
+    pub fn skipped(&self) {
+        self.skipped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The file was written, but with the best result found before the timeout
+    pub fn timed_out(&self, input: &InFile) {
+        self.succeeded();
+        self.timed_out.lock().unwrap().push(input.to_string());
+    }
+
+    pub fn failed(&self, input: &InFile, error: PngError) {
+        self.failed.lock().unwrap().push((input.to_string(), error));
+    }
+
+    pub fn has_failures(&self) -> bool {
+        !self.failed.lock().unwrap().is_empty()
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        let failed = self.failed.lock().unwrap().len();
+        if failed > 0 && self.succeeded.load(Ordering::Relaxed) == 0 {
+            EXIT_ALL_FAILED
+        } else if failed > 0 {
+            EXIT_SOME_FAILED
+        } else if !self.timed_out.lock().unwrap().is_empty() {
+            EXIT_TIMED_OUT
+        } else {
+            EXIT_SUCCESS
+        }
+    }
+
+    /// Print the counts and the files that failed, if there was more than one of `total` files
+    pub fn print(&self, total: usize) {
+        if total <= 1 {
+            return;
+        }
+        let succeeded = self.succeeded.load(Ordering::Relaxed);
+        let timed_out = self.timed_out.lock().unwrap();
+        let mut failed = self.failed.lock().unwrap();
+        let skipped = self.skipped.load(Ordering::Relaxed);
+        let not_processed = total - succeeded - skipped - failed.len();
+        let mut counts = format!("{} succeeded", succeeded);
+        if !timed_out.is_empty() {
+            counts += &format!(" ({} timed out)", timed_out.len());
+        }
+        counts += &format!(", {} failed", failed.len());
+        if skipped > 0 {
+            counts += &format!(", {} skipped", skipped);
+        }
+        if not_processed > 0 {
+            counts += &format!(", {} not processed", not_processed);
+        }
+        info!("Processed {} files: {}", total, counts);
+        if failed.is_empty() {
+            return;
+        }
+
+        let mut kinds = BTreeMap::new();
+        for (_, error) in failed.iter() {
+            *kinds.entry(error.kind()).or_insert(0) += 1;
+        }
+        let kinds: Vec<_> = kinds
+            .iter()
+            .map(|(kind, count)| format!("{} {}", count, kind))
+            .collect();
+        warn!("Failed files ({}):", kinds.join(", "));
+        failed.sort_by(|a, b| a.0.cmp(&b.0));
+        for (input, error) in failed.iter() {
+            warn!("    {}: {}: {}", input, error.kind(), error);
+        }
+    }
+}
@@ -0,0 +1,193 @@
+//! Optimizing files as they are added or changed
+//!
+//! Files are picked up once no writes to them have been seen for a short while, so files that
+//! are still being exported or copied are not read halfway. The files written by oxipng itself
+//! are remembered, so that writing them does not trigger another optimization.
+
+use crate::cli::files::{absolute, FileFilter};
+use crate::cli::outputs::Outputs;
+use log::{info, warn};
+use notify::event::{AccessKind, AccessMode, ModifyKind};
+use notify::{EventKind, RecursiveMode, Watcher};
+use oxipng::{Backup, InFile, OutFile};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How long a file must go without writes before it is optimized
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+/// How often to check for an interruption while no events arrive
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The size and modification time of a file, to tell whether it changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileState {
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl FileState {
+    fn of(path: &Path) -> Option<FileState> {
+        let meta = fs::metadata(path).ok().filter(|meta| meta.is_file())?;
+        Some(FileState {
+            len: meta.len(),
+            modified: meta.modified().ok(),
+        })
+    }
+}
+
+/// Watch `paths` until interrupted, passing the files that were added or changed to `process`
+///
+/// Only files that `filter` would pick up from `paths` are passed on.
+pub fn watch<F>(
+    paths: &[PathBuf],
+    filter: &FileFilter,
+    outputs: &Outputs,
+    mut process: F,
+) -> Result<(), String>
+where
+    F: FnMut(&mut Vec<(InFile, OutFile)>),
+{
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx).map_err(|e| e.to_string())?;
+    for path in paths {
+        let (target, mode) = if path.is_dir() {
+            if !filter.recursive {
+                return Err(format!(
+                    "{} is a directory, use -r to watch it",
+                    path.display()
+                ));
+            }
+            (path.as_path(), RecursiveMode::Recursive)
+        } else {
+            // Files are replaced rather than written to, which would end a watch on the file
+            let dir = match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            (dir, RecursiveMode::NonRecursive)
+        };
+        watcher
+            .watch(target, mode)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+    }
+    info!("Watching for changes, press Ctrl-C to stop");
+
+    // When each changed file was last written to
+    let mut pending: HashMap<PathBuf, Instant> = HashMap::new();
+    // The outputs written whose events have not been seen yet, as they were left
+    let mut written: HashMap<PathBuf, FileState> = HashMap::new();
+    while !oxipng::is_interrupted() {
+        match rx.recv_timeout(POLL_INTERVAL) {
+            Ok(Ok(event)) => {
+                if is_write(&event.kind) {
+                    for path in event.paths {
+                        pending.insert(absolute(&path), Instant::now());
+                    }
+                }
+            }
+            Ok(Err(e)) => warn!("{}", e),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        let now = Instant::now();
+        let settled: Vec<PathBuf> = pending
+            .iter()
+            .filter(|(_, &last_write)| now.duration_since(last_write) >= SETTLE_TIME)
+            .map(|(path, _)| path.clone())
+            .collect();
+        if settled.is_empty() {
+            continue;
+        }
+        let changed: HashSet<PathBuf> = settled
+            .into_iter()
+            .filter(|path| {
+                pending.remove(path);
+                let state = FileState::of(path);
+                // Unless it was changed again since, this is the event for our own write
+                let ours = written.remove(path);
+                state.is_some() && state != ours
+            })
+            .collect();
+        if changed.is_empty() {
+            continue;
+        }
+
+        // Apply the usual file search, so the same files are picked up as without --watch
+        let mut files = filter.collect_changed(paths, &changed, outputs);
+        if files.is_empty() {
+            continue;
+        }
+        let outputs_written: Vec<_> = files
+            .iter()
+            .filter_map(|(input, output)| match (input, output) {
+                (_, OutFile::Path(Some(path))) | (InFile::Path(path), OutFile::Path(None)) => {
+                    let path = absolute(path);
+                    let before = FileState::of(&path);
+                    Some((path, before))
+                }
+                _ => None,
+            })
+            .collect();
+        process(&mut files);
+        for (path, before) in outputs_written {
+            // Files left as they were, or outside of the watched paths, cause no events
+            match FileState::of(&path) {
+                Some(after) if Some(after) != before && is_watched(paths, &path) => {
+                    written.insert(path, after);
+                }
+                _ => (),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether changes to `path` are seen while watching `paths`
+fn is_watched(paths: &[PathBuf], path: &Path) -> bool {
+    paths.iter().any(|root| {
+        let root = absolute(root);
+        if root.is_dir() {
+            path.starts_with(&root)
+        } else {
+            // The directory of a file is watched, as files are replaced rather than written to
+            path.parent() == root.parent()
+        }
+    })
+}
+
+/// Check that the backups written while watching `paths` won't be picked up as changed files
+pub fn check_backup(paths: &[PathBuf], filter: &FileFilter, backup: &Backup) -> Result<(), String> {
+    let watched_dir = backup.dir.as_ref().map_or(true, |dir| {
+        let dir = absolute(dir);
+        paths.iter().any(|path| {
+            let path = absolute(path);
+            if path.is_dir() {
+                dir.starts_with(&path)
+            } else {
+                path.parent().map_or(true, |parent| dir.starts_with(parent))
+            }
+        })
+    });
+    let name = format!("image.png{}", backup.suffix);
+    if watched_dir && filter.matches_extension(Path::new(&name)) {
+        return Err(format!(
+            "Backups ending in {} would be optimized again by --watch, use a different --backup-suffix or a --backup-dir outside of the watched paths",
+            backup.suffix
+        ));
+    }
+    Ok(())
+}
+
+/// Whether the event may leave a new or changed file behind
+fn is_write(kind: &EventKind) -> bool {
+    matches!(
+        kind,
+        EventKind::Create(_)
+            | EventKind::Modify(ModifyKind::Any | ModifyKind::Data(_) | ModifyKind::Name(_))
+            | EventKind::Access(AccessKind::Close(AccessMode::Write))
+    )
+}
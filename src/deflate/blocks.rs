@@ -0,0 +1,289 @@
+use crate::error::PngError;
+use crate::PngResult;
+use std::fmt;
+
+/// Base lengths for length symbols 257..285
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+/// Extra bits for length symbols 257..285
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+/// Extra bits for distance symbols 0..29
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// Order in which code length code lengths are stored in a dynamic block header
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Fields of the 2-byte zlib stream header
+pub struct ZlibHeader {
+    /// Compression method, 8 for DEFLATE
+    pub method: u8,
+    /// Base-2 logarithm of the window size, minus 8
+    pub window_bits: u8,
+    /// Whether a preset dictionary is used
+    pub preset_dictionary: bool,
+    /// Compression level hint: 0 (fastest) to 3 (maximum)
+    pub level: u8,
+    /// Whether the header check bits are correct
+    pub check_valid: bool,
+}
+
+impl ZlibHeader {
+    /// Size of the LZ77 window in bytes
+    #[must_use]
+    pub fn window_size(&self) -> u32 {
+        1 << (u32::from(self.window_bits) + 8).min(31)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Type of a DEFLATE block
+pub enum BlockType {
+    Stored,
+    Fixed,
+    Dynamic,
+}
+
+impl fmt::Display for BlockType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match *self {
+            BlockType::Stored => "stored",
+            BlockType::Fixed => "fixed",
+            BlockType::Dynamic => "dynamic",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// A single DEFLATE block
+pub struct DeflateBlock {
+    pub block_type: BlockType,
+    /// Whether this is the last block of the stream
+    pub is_final: bool,
+    /// Size of the block in the compressed stream, including its header
+    pub compressed_bits: usize,
+    /// Number of bytes the block decompresses to
+    pub uncompressed_size: usize,
+}
+
+/// Reads bits from a DEFLATE stream, least significant bit first
+struct BitReader<'a> {
+    data: &'a [u8],
+    bit_pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn bits(&mut self, count: u8) -> PngResult<u32> {
+        let mut value = 0;
+        for i in 0..count {
+            let byte = self
+                .data
+                .get(self.bit_pos / 8)
+                .ok_or(PngError::TruncatedData)?;
+            value |= u32::from((byte >> (self.bit_pos % 8)) & 1) << i;
+            self.bit_pos += 1;
+        }
+        Ok(value)
+    }
+
+    fn align_to_byte(&mut self) {
+        self.bit_pos = (self.bit_pos + 7) / 8 * 8;
+    }
+}
+
+/// A canonical Huffman code, stored as the number of codes of each length
+/// and the symbols ordered by code
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> PngResult<Self> {
+        let mut counts = [0_u16; 16];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        // Reject over-subscribed codes, incomplete ones are allowed
+        let mut left = 1_i32;
+        for &count in &counts[1..] {
+            left = (left << 1) - i32::from(count);
+            if left < 0 {
+                return Err(PngError::InvalidData);
+            }
+        }
+        let mut offsets = [0_u16; 16];
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        Ok(Self { counts, symbols })
+    }
+
+    fn decode(&self, reader: &mut BitReader<'_>) -> PngResult<u16> {
+        let mut code = 0_i32;
+        let mut first = 0_i32;
+        let mut index = 0_i32;
+        for &count in &self.counts[1..] {
+            code |= reader.bits(1)? as i32;
+            let count = i32::from(count);
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(PngError::InvalidData)
+    }
+}
+
+/// Parse the header of a zlib stream
+pub fn zlib_header(data: &[u8]) -> PngResult<ZlibHeader> {
+    let (cmf, flg) = match *data {
+        [cmf, flg, ..] => (cmf, flg),
+        _ => return Err(PngError::TruncatedData),
+    };
+    Ok(ZlibHeader {
+        method: cmf & 0x0F,
+        window_bits: cmf >> 4,
+        preset_dictionary: flg & 0x20 != 0,
+        level: flg >> 6,
+        check_valid: (u16::from(cmf) << 8 | u16::from(flg)) % 31 == 0,
+    })
+}
+
+/// List the blocks of the DEFLATE stream in zlib data, without decompressing it
+///
+/// Blocks are added as they are read, so the blocks before any error are kept.
+pub fn deflate_blocks(data: &[u8], blocks: &mut Vec<DeflateBlock>) -> PngResult<()> {
+    let mut reader = BitReader {
+        data: data.get(2..).ok_or(PngError::TruncatedData)?,
+        bit_pos: 0,
+    };
+    loop {
+        let start = reader.bit_pos;
+        let is_final = reader.bits(1)? == 1;
+        let (block_type, uncompressed_size) = match reader.bits(2)? {
+            0 => (BlockType::Stored, stored_block(&mut reader)?),
+            1 => {
+                let (literals, distances) = fixed_codes()?;
+                (
+                    BlockType::Fixed,
+                    compressed_block(&mut reader, &literals, &distances)?,
+                )
+            }
+            2 => {
+                let (literals, distances) = dynamic_codes(&mut reader)?;
+                (
+                    BlockType::Dynamic,
+                    compressed_block(&mut reader, &literals, &distances)?,
+                )
+            }
+            _ => return Err(PngError::InvalidData),
+        };
+        blocks.push(DeflateBlock {
+            block_type,
+            is_final,
+            compressed_bits: reader.bit_pos - start,
+            uncompressed_size,
+        });
+        if is_final {
+            return Ok(());
+        }
+    }
+}
+
+fn stored_block(reader: &mut BitReader<'_>) -> PngResult<usize> {
+    reader.align_to_byte();
+    let len = reader.bits(16)?;
+    let nlen = reader.bits(16)?;
+    if len != !nlen & 0xFFFF {
+        return Err(PngError::InvalidData);
+    }
+    reader.bit_pos += len as usize * 8;
+    if reader.bit_pos > reader.data.len() * 8 {
+        return Err(PngError::TruncatedData);
+    }
+    Ok(len as usize)
+}
+
+fn fixed_codes() -> PngResult<(Huffman, Huffman)> {
+    let mut lengths = [8_u8; 288];
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    Ok((Huffman::new(&lengths)?, Huffman::new(&[5; 30])?))
+}
+
+fn dynamic_codes(reader: &mut BitReader<'_>) -> PngResult<(Huffman, Huffman)> {
+    let literal_count = reader.bits(5)? as usize + 257;
+    let distance_count = reader.bits(5)? as usize + 1;
+    let code_length_count = reader.bits(4)? as usize + 4;
+    if literal_count > 286 || distance_count > 30 {
+        return Err(PngError::InvalidData);
+    }
+
+    let mut code_lengths = [0_u8; 19];
+    for &index in &CODE_LENGTH_ORDER[..code_length_count] {
+        code_lengths[index] = reader.bits(3)? as u8;
+    }
+    let code_length_code = Huffman::new(&code_lengths)?;
+
+    let mut lengths = Vec::with_capacity(literal_count + distance_count);
+    while lengths.len() < literal_count + distance_count {
+        let (value, repeat) = match code_length_code.decode(reader)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => (
+                *lengths.last().ok_or(PngError::InvalidData)?,
+                3 + reader.bits(2)?,
+            ),
+            17 => (0, 3 + reader.bits(3)?),
+            _ => (0, 11 + reader.bits(7)?),
+        };
+        lengths.extend(std::iter::repeat(value).take(repeat as usize));
+    }
+    if lengths.len() > literal_count + distance_count || lengths[256] == 0 {
+        return Err(PngError::InvalidData);
+    }
+    Ok((
+        Huffman::new(&lengths[..literal_count])?,
+        Huffman::new(&lengths[literal_count..])?,
+    ))
+}
+
+/// Skip over the symbols of a Huffman-coded block, returning its uncompressed size
+fn compressed_block(
+    reader: &mut BitReader<'_>,
+    literals: &Huffman,
+    distances: &Huffman,
+) -> PngResult<usize> {
+    let mut size = 0;
+    loop {
+        match literals.decode(reader)? {
+            0..=255 => size += 1,
+            256 => return Ok(size),
+            symbol => {
+                let index = (symbol - 257) as usize;
+                let base = LENGTH_BASE.get(index).ok_or(PngError::InvalidData)?;
+                size += (u32::from(*base) + reader.bits(LENGTH_EXTRA[index])?) as usize;
+                let distance = distances.decode(reader)? as usize;
+                let extra = DISTANCE_EXTRA.get(distance).ok_or(PngError::InvalidData)?;
+                reader.bits(*extra)?;
+            }
+        }
+    }
+}
@@ -1,4 +1,6 @@
+mod blocks;
 mod deflater;
+pub use blocks::{deflate_blocks, zlib_header, BlockType, DeflateBlock, ZlibHeader};
 pub use deflater::crc32;
 pub use deflater::deflate;
 pub use deflater::inflate;
@@ -1,20 +1,91 @@
+use crate::options::OptionsError;
 use std::error::Error;
 use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 #[derive(Debug, Clone)]
 #[non_exhaustive]
 pub enum PngError {
     DeflatedDataTooLong(usize),
     TimedOut,
+    Interrupted,
     NotPNG,
     APNGNotSupported,
+    C2PAProtected,
+    TrailingData(usize),
+    /// The optimized image differs from the input, first at this pixel
+    VerificationFailed {
+        x: u32,
+        y: u32,
+    },
+    /// The optimized image could not be decoded to verify it
+    OutputNotDecodable(Box<str>),
+    PixelLimitExceeded(u64),
+    DecodedSizeLimitExceeded(u64),
+    ChunkSizeLimitExceeded([u8; 4], usize),
+    MemoryLimitExceeded(u64),
+    /// Compressed metadata inflates to more than this many bytes
+    InflatedSizeLimitExceeded(usize),
     InvalidData,
     TruncatedData,
     ChunkMissing(&'static str),
+    /// The CRC of a chunk did not match its contents
+    CrcMismatch {
+        chunk: [u8; 4],
+        /// Offset of the start of the chunk in the file
+        offset: usize,
+    },
+    /// Reading or writing a file failed
+    ///
+    /// The path is `None` for stdin and stdout. The source is shared so the error can be cloned.
+    Io {
+        path: Option<PathBuf>,
+        source: Arc<io::Error>,
+    },
+    InvalidOptions(OptionsError),
+    /// The configuration for a file could not be applied
+    InvalidConfig(Box<str>),
+    /// The path does not name a file
+    NotAFile(PathBuf),
+    /// The operation needs a file, but was given stdin or stdout
+    FileRequired(&'static str),
+    /// The permissions of a written file could not be set to those of the file it replaces
+    PermissionsNotPreserved(PathBuf),
+    /// A backup at this path failed its integrity checks
+    BackupDamaged {
+        path: PathBuf,
+        source: Box<PngError>,
+    },
+    /// A backup does not match the checksum kept next to it
+    BackupChecksumMismatch(PathBuf),
+    /// The file restored from a backup does not read back the same as the backup
+    BackupMismatch(PathBuf),
+    /// The sidecar file does not start with the sidecar signature
+    InvalidSidecar,
+    /// The sidecar was written by a newer version of oxipng
+    UnsupportedSidecarVersion(u8),
+    /// The headers in the sidecar don't line up with the headers of the image
+    SidecarMismatch,
+    /// A raw profile text chunk with this keyword could not be decoded
+    InvalidRawProfile {
+        keyword: Box<str>,
+        reason: &'static str,
+    },
     Other(Box<str>),
 }
 
-impl Error for PngError {}
+impl Error for PngError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            PngError::Io { ref source, .. } => Some(&**source),
+            PngError::InvalidOptions(ref e) => Some(e),
+            PngError::BackupDamaged { ref source, .. } => Some(&**source),
+            _ => None,
+        }
+    }
+}
 
 impl fmt::Display for PngError {
     #[inline]
@@ -23,21 +94,163 @@ impl fmt::Display for PngError {
         match *self {
             PngError::DeflatedDataTooLong(_) => f.write_str("deflated data too long"),
             PngError::TimedOut => f.write_str("timed out"),
+            PngError::Interrupted => f.write_str("interrupted"),
             PngError::NotPNG => f.write_str("Invalid header detected; Not a PNG file"),
             PngError::InvalidData => f.write_str("Invalid data found; unable to read PNG file"),
             PngError::TruncatedData => {
                 f.write_str("Missing data in the file; the file is truncated")
             }
             PngError::APNGNotSupported => f.write_str("APNG files are not (yet) supported"),
+            PngError::C2PAProtected => {
+                f.write_str("File contains C2PA content credentials which would be invalidated")
+            }
+            PngError::TrailingData(len) => {
+                write!(f, "Found {} bytes of trailing data after IEND", len)
+            }
+            PngError::VerificationFailed { x, y } => write!(
+                f,
+                "Verification failed; the optimized image differs from the input at pixel ({}, {})",
+                x, y
+            ),
+            PngError::OutputNotDecodable(ref e) => {
+                write!(f, "Failed to read output image for verification: {}", e)
+            }
+            PngError::PixelLimitExceeded(pixels) => {
+                write!(f, "Image has {} pixels, more than the limit", pixels)
+            }
+            PngError::DecodedSizeLimitExceeded(size) => {
+                write!(
+                    f,
+                    "Decoded image would be {} bytes, more than the limit",
+                    size
+                )
+            }
+            PngError::ChunkSizeLimitExceeded(name, size) => write!(
+                f,
+                "{} chunk is {} bytes, more than the limit",
+                String::from_utf8_lossy(&name),
+                size
+            ),
+            PngError::MemoryLimitExceeded(size) => write!(
+                f,
+                "Processing the image would use about {} bytes of memory, more than the limit",
+                size
+            ),
+            PngError::InflatedSizeLimitExceeded(size) => write!(
+                f,
+                "Compressed data inflates to more than {} bytes, the limit",
+                size
+            ),
             PngError::ChunkMissing(s) => write!(f, "Chunk {} missing or empty", s),
+            PngError::CrcMismatch { chunk, offset } => write!(
+                f,
+                "CRC Mismatch in {} header at offset {}; May be recoverable by using --fix",
+                String::from_utf8_lossy(&chunk),
+                offset
+            ),
+            PngError::Io {
+                path: Some(ref path),
+                ref source,
+            } => write!(f, "{}: {}", path.display(), source),
+            PngError::Io {
+                path: None,
+                ref source,
+            } => write!(f, "stdio: {}", source),
+            PngError::InvalidOptions(e) => write!(f, "Invalid options: {}", e),
+            PngError::InvalidConfig(ref e) => write!(f, "Invalid configuration: {}", e),
+            PngError::NotAFile(ref path) => write!(f, "{} is not a file", path.display()),
+            PngError::FileRequired(operation) => {
+                write!(f, "{} needs a file, not stdin or stdout", operation)
+            }
+            PngError::PermissionsNotPreserved(ref path) => write!(
+                f,
+                "{}: the permissions of the original file could not be set",
+                path.display()
+            ),
+            PngError::BackupDamaged {
+                ref path,
+                ref source,
+            } => write!(f, "Backup {} is damaged: {}", path.display(), source),
+            PngError::BackupChecksumMismatch(ref path) => {
+                write!(f, "Backup {} does not match its checksum", path.display())
+            }
+            PngError::BackupMismatch(ref path) => write!(
+                f,
+                "{}: the restored file does not match the backup",
+                path.display()
+            ),
+            PngError::InvalidSidecar => f.write_str("Invalid sidecar header detected"),
+            PngError::UnsupportedSidecarVersion(version) => {
+                write!(f, "Unsupported sidecar version {}", version)
+            }
+            PngError::SidecarMismatch => {
+                f.write_str("Sidecar does not match the headers of the image")
+            }
+            PngError::InvalidRawProfile {
+                ref keyword,
+                reason,
+            } => write!(f, "Raw profile \"{}\" is invalid: {}", keyword, reason),
             PngError::Other(ref s) => f.write_str(s),
         }
     }
 }
 
+impl From<OptionsError> for PngError {
+    fn from(e: OptionsError) -> PngError {
+        PngError::InvalidOptions(e)
+    }
+}
+
 impl PngError {
     #[cold]
     pub fn new(description: &str) -> PngError {
         PngError::Other(description.into())
     }
+
+    /// The name of the variant, without its details, for grouping errors
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            PngError::DeflatedDataTooLong(_) => "DeflatedDataTooLong",
+            PngError::TimedOut => "TimedOut",
+            PngError::Interrupted => "Interrupted",
+            PngError::NotPNG => "NotPNG",
+            PngError::APNGNotSupported => "APNGNotSupported",
+            PngError::C2PAProtected => "C2PAProtected",
+            PngError::TrailingData(_) => "TrailingData",
+            PngError::VerificationFailed { .. } => "VerificationFailed",
+            PngError::OutputNotDecodable(_) => "OutputNotDecodable",
+            PngError::PixelLimitExceeded(_) => "PixelLimitExceeded",
+            PngError::DecodedSizeLimitExceeded(_) => "DecodedSizeLimitExceeded",
+            PngError::ChunkSizeLimitExceeded(..) => "ChunkSizeLimitExceeded",
+            PngError::MemoryLimitExceeded(_) => "MemoryLimitExceeded",
+            PngError::InflatedSizeLimitExceeded(_) => "InflatedSizeLimitExceeded",
+            PngError::InvalidData => "InvalidData",
+            PngError::TruncatedData => "TruncatedData",
+            PngError::ChunkMissing(_) => "ChunkMissing",
+            PngError::CrcMismatch { .. } => "CrcMismatch",
+            PngError::Io { .. } => "Io",
+            PngError::InvalidOptions(_) => "InvalidOptions",
+            PngError::InvalidConfig(_) => "InvalidConfig",
+            PngError::NotAFile(_) => "NotAFile",
+            PngError::FileRequired(_) => "FileRequired",
+            PngError::PermissionsNotPreserved(_) => "PermissionsNotPreserved",
+            PngError::BackupDamaged { .. } => "BackupDamaged",
+            PngError::BackupChecksumMismatch(_) => "BackupChecksumMismatch",
+            PngError::BackupMismatch(_) => "BackupMismatch",
+            PngError::InvalidSidecar => "InvalidSidecar",
+            PngError::UnsupportedSidecarVersion(_) => "UnsupportedSidecarVersion",
+            PngError::SidecarMismatch => "SidecarMismatch",
+            PngError::InvalidRawProfile { .. } => "InvalidRawProfile",
+            PngError::Other(_) => "Other",
+        }
+    }
+
+    /// An I/O error on a file, or on stdin/stdout if there is no path
+    #[cold]
+    pub(crate) fn io(path: Option<&Path>, source: io::Error) -> PngError {
+        PngError::Io {
+            path: path.map(Path::to_path_buf),
+            source: Arc::new(source),
+        }
+    }
 }
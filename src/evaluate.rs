@@ -10,9 +10,11 @@ use crate::png::PngImage;
 use crate::rayon;
 use crate::Deadline;
 #[cfg(feature = "parallel")]
-use crossbeam_channel::{unbounded, Receiver, Sender};
+use crossbeam_channel::{unbounded, Receiver, Sender, TryRecvError};
 use indexmap::IndexSet;
 use rayon::prelude::*;
+#[cfg(feature = "parallel")]
+use rayon::Yield;
 #[cfg(not(feature = "parallel"))]
 use std::cell::RefCell;
 use std::sync::atomic::AtomicUsize;
@@ -84,7 +86,28 @@ impl Evaluator {
     pub fn get_best_candidate(self) -> Option<Candidate> {
         let (eval_send, eval_recv) = self.eval_channel;
         drop(eval_send); // disconnect the sender, breaking the loop in the thread
-        eval_recv.into_iter().min_by_key(Candidate::cmp_key)
+        if rayon::current_thread_index().is_none() {
+            return eval_recv.into_iter().min_by_key(Candidate::cmp_key);
+        }
+        // On a pool thread, blocking while evaluations are still queued could leave no threads
+        // to run them, so run pending jobs first and only block once none are left. Any
+        // evaluations still running then send their result or drop the sender when done.
+        let mut best: Option<Candidate> = None;
+        loop {
+            let new = match eval_recv.try_recv() {
+                Ok(new) => new,
+                Err(TryRecvError::Empty) if rayon::yield_now() == Some(Yield::Executed) => continue,
+                Err(TryRecvError::Empty) => match eval_recv.recv() {
+                    Ok(new) => new,
+                    Err(_) => return best,
+                },
+                Err(TryRecvError::Disconnected) => return best,
+            };
+            match best {
+                Some(ref prev) if prev.cmp_key() <= new.cmp_key() => {}
+                _ => best = Some(new),
+            }
+        }
     }
 
     #[cfg(not(feature = "parallel"))]
@@ -140,6 +163,8 @@ impl Evaluator {
                             idat_data,
                             filtered,
                             raw: Arc::clone(&image),
+                            trailing_data: Vec::new(),
+                            repairs: Vec::new(),
                         },
                         filter,
                         is_reduction,
@@ -35,36 +35,146 @@ impl IhdrData {
     }
 
     /// Byte length of IDAT that is correct for this IHDR
+    ///
+    /// The size must fit in memory, as checked by `checked_raw_data_size`
     #[must_use]
     pub fn raw_data_size(&self) -> usize {
-        let w = self.width as usize;
-        let h = self.height as usize;
-        let bpp = self.bpp();
+        self.checked_raw_data_size()
+            .and_then(|size| usize::try_from(size).ok())
+            .unwrap_or(usize::MAX)
+    }
+
+    /// Byte length of IDAT that is correct for this IHDR, or `None` if it overflows
+    #[must_use]
+    pub fn checked_raw_data_size(&self) -> Option<u64> {
+        let w = u64::from(self.width);
+        let h = u64::from(self.height);
+        let bpp = u64::from(self.bpp());
 
-        fn bitmap_size(bpp: u8, w: usize, h: usize) -> usize {
-            (((w / 8) * bpp as usize) + ((w & 7) * bpp as usize + 7) / 8) * h
+        fn bitmap_size(bpp: u64, w: u64, h: u64) -> Option<u64> {
+            // Passes of an interlaced image with no pixels in a line are skipped entirely
+            if w == 0 {
+                return Some(0);
+            }
+            // Each line starts with the filter type byte
+            ((w / 8) * bpp + ((w & 7) * bpp + 7) / 8 + 1).checked_mul(h)
         }
 
         if self.interlaced == Interlacing::None {
-            bitmap_size(bpp, w, h) + h
+            bitmap_size(bpp, w, h)
         } else {
-            let mut size = bitmap_size(bpp, (w + 7) >> 3, (h + 7) >> 3) + ((h + 7) >> 3);
-            if w > 4 {
-                size += bitmap_size(bpp, (w + 3) >> 3, (h + 7) >> 3) + ((h + 7) >> 3);
-            }
-            size += bitmap_size(bpp, (w + 3) >> 2, (h + 3) >> 3) + ((h + 3) >> 3);
-            if w > 2 {
-                size += bitmap_size(bpp, (w + 1) >> 2, (h + 3) >> 2) + ((h + 3) >> 2);
-            }
-            size += bitmap_size(bpp, (w + 1) >> 1, (h + 1) >> 2) + ((h + 1) >> 2);
-            if w > 1 {
-                size += bitmap_size(bpp, w >> 1, (h + 1) >> 1) + ((h + 1) >> 1);
-            }
-            size + bitmap_size(bpp, w, h >> 1) + (h >> 1)
+            let passes = [
+                bitmap_size(bpp, (w + 7) >> 3, (h + 7) >> 3)?,
+                bitmap_size(bpp, (w + 3) >> 3, (h + 7) >> 3)?,
+                bitmap_size(bpp, (w + 3) >> 2, (h + 3) >> 3)?,
+                bitmap_size(bpp, (w + 1) >> 2, (h + 3) >> 2)?,
+                bitmap_size(bpp, (w + 1) >> 1, (h + 1) >> 2)?,
+                bitmap_size(bpp, w >> 1, (h + 1) >> 1)?,
+                bitmap_size(bpp, w, h >> 1)?,
+            ];
+            passes
+                .iter()
+                .try_fold(0_u64, |total, &size| total.checked_add(size))
         }
     }
 }
 
+#[derive(Debug, PartialEq, Eq, Clone)]
+/// A single chunk, owning its data
+pub struct Chunk {
+    pub name: [u8; 4],
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+/// Ancillary headers of an image, in the order they appeared in the file
+///
+/// The text headers and sPLT may be repeated, and every copy is kept, so lookups by name return
+/// the first one. Any other header can only appear once: if a file repeats it anyway, the last
+/// copy wins and takes the place of the first.
+pub struct AuxHeaders(Vec<Chunk>);
+
+impl AuxHeaders {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Get the data of the first header with the given name
+    #[must_use]
+    pub fn get(&self, name: &[u8]) -> Option<&Vec<u8>> {
+        self.0.iter().find(|c| c.name == name).map(|c| &c.data)
+    }
+
+    #[must_use]
+    pub fn contains_key(&self, name: &[u8]) -> bool {
+        self.0.iter().any(|c| c.name == name)
+    }
+
+    /// Replace the data of the first header with the given name, or append a new header
+    pub fn insert(&mut self, name: [u8; 4], data: Vec<u8>) {
+        match self.0.iter_mut().find(|c| c.name == name) {
+            Some(chunk) => chunk.data = data,
+            None => self.0.push(Chunk { name, data }),
+        }
+    }
+
+    /// Append a header, or replace an earlier one with the same name unless it may be repeated
+    pub fn push(&mut self, name: [u8; 4], data: Vec<u8>) {
+        if REPEATABLE_HEADERS.contains(&name) {
+            self.0.push(Chunk { name, data });
+        } else {
+            self.insert(name, data);
+        }
+    }
+
+    /// Remove all headers with the given name, returning the data of the first one
+    pub fn remove(&mut self, name: &[u8]) -> Option<Vec<u8>> {
+        let index = self.0.iter().position(|c| c.name == name)?;
+        let first = self.0.remove(index);
+        self.0.retain(|c| c.name != name);
+        Some(first.data)
+    }
+
+    pub fn retain<F: FnMut(&[u8; 4], &[u8]) -> bool>(&mut self, mut f: F) {
+        self.0.retain(|c| f(&c.name, &c.data));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8; 4], &Vec<u8>)> {
+        self.0.iter().map(|c| (&c.name, &c.data))
+    }
+
+    #[must_use]
+    pub fn chunks(&self) -> &[Chunk] {
+        &self.0
+    }
+
+    #[must_use]
+    pub fn into_chunks(self) -> Vec<Chunk> {
+        self.0
+    }
+}
+
+impl From<Vec<Chunk>> for AuxHeaders {
+    fn from(chunks: Vec<Chunk>) -> Self {
+        let mut headers = Self::new();
+        for chunk in chunks {
+            headers.push(chunk.name, chunk.data);
+        }
+        headers
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 /// Options to use for performing operations on headers (such as stripping)
 pub enum Headers {
@@ -72,7 +182,7 @@ pub enum Headers {
     None,
     /// Remove specific chunks
     Strip(Vec<String>),
-    /// Headers that won't affect rendering (all but cICP, iCCP, sBIT, sRGB, pHYs)
+    /// Headers that won't affect rendering (all but cICP, cLLi, iCCP, mDCv, sBIT, sRGB, pHYs)
     Safe,
     /// Remove all non-critical chunks except these
     Keep(IndexSet<String>),
@@ -80,6 +190,64 @@ pub enum Headers {
     All,
 }
 
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+/// What to do with files that carry C2PA content credentials
+///
+/// The credentials include a hash of the exact bytes of the file, so they are
+/// invalidated by any change to the file.
+pub enum C2PAPolicy {
+    /// Leave the file untouched
+    Skip,
+    /// Remove the credentials and optimize the file, with a warning
+    Strip,
+    /// Keep the (now invalid) credentials and optimize the file, with a warning
+    Keep,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+/// What to do with data found after the IEND chunk
+pub enum TrailingData {
+    /// Copy the data verbatim after the IEND chunk of the output
+    Keep,
+    /// Remove the data
+    Strip,
+    /// Refuse to process the file
+    Error,
+}
+
+/// Headers that may appear more than once in a file
+const REPEATABLE_HEADERS: [[u8; 4]; 4] = [*b"tEXt", *b"zTXt", *b"iTXt", *b"sPLT"];
+
+/// Headers kept by `Headers::Safe`
+pub(crate) const SAFE_HEADERS: [[u8; 4]; 7] = [
+    *b"cICP", *b"cLLi", *b"iCCP", *b"mDCv", *b"sBIT", *b"sRGB", *b"pHYs",
+];
+
+/// Check if a chunk contains a C2PA manifest store
+///
+/// This is normally the `caBX` chunk, but any chunk containing a JUMBF superbox
+/// labelled with the C2PA UUID is treated the same way.
+pub fn is_c2pa_manifest(name: &[u8; 4], data: &[u8]) -> bool {
+    if name == b"caBX" {
+        return true;
+    }
+    // JUMBF superbox: box header, then a description box header, then the content type UUID
+    data.len() >= 24
+        && &data[4..8] == b"jumb"
+        && &data[12..16] == b"jumd"
+        && &data[16..20] == b"c2pa"
+}
+
+/// Check if a cICP chunk describes PQ or HLG content using narrow (video) range samples
+///
+/// Such samples are scaled between bit depths by shifting rather than by bit replication,
+/// so they don't follow the usual PNG rules for converting between bit depths.
+#[inline]
+pub fn cicp_is_narrow_range_hdr(cicp: &[u8]) -> bool {
+    // Transfer function 16 is SMPTE ST 2084 (PQ) and 18 is ARIB STD-B67 (HLG)
+    matches!(cicp, [_, 16 | 18, _, 0])
+}
+
 #[inline]
 pub fn file_header_is_valid(bytes: &[u8]) -> bool {
     let expected_header: [u8; 8] = [0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A];
@@ -91,6 +259,8 @@ pub fn file_header_is_valid(bytes: &[u8]) -> bool {
 pub struct RawHeader<'a> {
     pub name: [u8; 4],
     pub data: &'a [u8],
+    /// Whether the CRC of the chunk was correct, only ever `false` when fixing errors
+    pub crc_valid: bool,
 }
 
 pub fn parse_next_header<'a>(
@@ -111,7 +281,10 @@ pub fn parse_next_header<'a>(
         .get(header_start..header_start + 4)
         .ok_or(PngError::TruncatedData)?;
     if chunk_name == b"IEND" {
-        // End of data
+        // End of data, skip past the chunk so the offset points at any trailing data
+        *byte_offset = (header_start + 8)
+            .saturating_add(length as usize)
+            .min(byte_data.len());
         return Ok(None);
     }
     *byte_offset += 4;
@@ -131,16 +304,21 @@ pub fn parse_next_header<'a>(
     let header_bytes = byte_data
         .get(header_start..header_start + 4 + length as usize)
         .ok_or(PngError::TruncatedData)?;
-    if !fix_errors && crc32(header_bytes) != crc {
-        return Err(PngError::new(&format!(
-            "CRC Mismatch in {} header; May be recoverable by using --fix",
-            String::from_utf8_lossy(chunk_name)
-        )));
-    }
-
     let mut name = [0_u8; 4];
     name.copy_from_slice(chunk_name);
-    Ok(Some(RawHeader { name, data }))
+    let crc_valid = crc32(header_bytes) == crc;
+    if !fix_errors && !crc_valid {
+        return Err(PngError::CrcMismatch {
+            chunk: name,
+            offset: header_start - 4,
+        });
+    }
+
+    Ok(Some(RawHeader {
+        name,
+        data,
+        crc_valid,
+    }))
 }
 
 pub fn parse_ihdr_header(byte_data: &[u8]) -> PngResult<IhdrData> {
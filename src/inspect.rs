@@ -0,0 +1,292 @@
+use crate::deflate::{crc32, deflate_blocks, inflate, zlib_header, DeflateBlock, ZlibHeader};
+use crate::error::PngError;
+use crate::filters::RowFilter;
+use crate::headers::{
+    file_header_is_valid, parse_ihdr_header, parse_next_header, AuxHeaders, IhdrData,
+};
+use crate::limits::Limits;
+use crate::png::{PngData, PngImage};
+use crate::{InFile, PngResult};
+use rgb::RGBA8;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Location and integrity of a chunk in the file
+pub struct ChunkInfo {
+    pub name: [u8; 4],
+    /// Offset of the chunk from the start of the file
+    pub offset: usize,
+    /// Length of the chunk data
+    pub length: usize,
+    pub crc_valid: bool,
+}
+
+#[derive(Debug, Clone)]
+/// The internal structure of a PNG file
+pub struct Inspection {
+    pub chunks: Vec<ChunkInfo>,
+    /// Number of bytes following the IEND chunk
+    pub trailing_data: usize,
+    pub ihdr: Option<IhdrData>,
+    pub palette: Option<Vec<RGBA8>>,
+    pub transparency_pixel: Option<Vec<u8>>,
+    /// Filter type of each row, in the order they are stored
+    pub row_filters: Vec<u8>,
+    pub zlib_header: Option<ZlibHeader>,
+    pub deflate_blocks: Vec<DeflateBlock>,
+    /// Problems that stopped parts of the file from being inspected
+    pub errors: Vec<String>,
+}
+
+/// Inspect the internal structure of the input file
+pub fn inspect_file(input: &InFile, limits: &Limits) -> PngResult<Inspection> {
+    Ok(inspect(&input.read()?, limits))
+}
+
+/// Inspect the internal structure of a PNG file that is already loaded in-memory
+///
+/// As much of the file is inspected as possible, problems are listed in `errors`. The image
+/// data is only decoded to read the row filters if it is within the `limits`.
+#[must_use]
+pub fn inspect(data: &[u8], limits: &Limits) -> Inspection {
+    let mut inspection = Inspection {
+        chunks: Vec::new(),
+        trailing_data: 0,
+        ihdr: None,
+        palette: None,
+        transparency_pixel: None,
+        row_filters: Vec::new(),
+        zlib_header: None,
+        deflate_blocks: Vec::new(),
+        errors: Vec::new(),
+    };
+    if !data.get(0..8).map_or(false, file_header_is_valid) {
+        inspection.errors.push("Not a PNG file".to_owned());
+        return inspection;
+    }
+
+    let mut idat = Vec::new();
+    let (mut ihdr, mut palette, mut transparency) = (None, None, None);
+    let mut offset = 8;
+    loop {
+        let start = offset;
+        match parse_next_header(data, &mut offset, true) {
+            Ok(Some(chunk)) => {
+                match &chunk.name {
+                    b"IDAT" => idat.extend_from_slice(chunk.data),
+                    b"IHDR" => ihdr = Some(chunk.data),
+                    b"PLTE" => palette = Some(chunk.data.to_owned()),
+                    b"tRNS" => transparency = Some(chunk.data.to_owned()),
+                    _ => (),
+                }
+                inspection.chunks.push(ChunkInfo {
+                    name: chunk.name,
+                    offset: start,
+                    length: chunk.data.len(),
+                    crc_valid: chunk.crc_valid,
+                });
+            }
+            Ok(None) => {
+                // The parser doesn't check the CRC of IEND
+                let crc = data.get(start + 4..start + 12);
+                inspection.chunks.push(ChunkInfo {
+                    name: *b"IEND",
+                    offset: start,
+                    length: offset.saturating_sub(start + 12),
+                    crc_valid: crc.map_or(false, |c| c[4..] == crc32(b"IEND").to_be_bytes()),
+                });
+                inspection.trailing_data = data.len() - offset;
+                break;
+            }
+            Err(e) => {
+                if start < data.len() {
+                    inspection
+                        .errors
+                        .push(format!("Unreadable chunk at offset {}: {}", start, e));
+                }
+                break;
+            }
+        }
+    }
+
+    if !idat.is_empty() {
+        match zlib_header(&idat) {
+            Ok(header) => inspection.zlib_header = Some(header),
+            Err(e) => inspection.errors.push(format!("zlib header: {}", e)),
+        }
+        if let Err(e) = deflate_blocks(&idat, &mut inspection.deflate_blocks) {
+            inspection.errors.push(format!("DEFLATE stream: {}", e));
+        }
+    }
+
+    // Read the file as it is, without the repairs that loading it for optimization may make
+    let ihdr = match ihdr.map(parse_ihdr_header) {
+        Some(Ok(ihdr)) => ihdr,
+        Some(Err(e)) => {
+            inspection.errors.push(format!("IHDR: {}", e));
+            return inspection;
+        }
+        None => {
+            inspection.errors.push("IHDR chunk missing".to_owned());
+            return inspection;
+        }
+    };
+    inspection.ihdr = Some(ihdr);
+    match PngData::palette_to_rgba(ihdr.color_type, palette, transparency) {
+        Ok((palette, transparency_pixel)) => {
+            inspection.palette = palette;
+            inspection.transparency_pixel = transparency_pixel;
+        }
+        Err(e) => inspection.errors.push(format!("Palette: {}", e)),
+    }
+    if let Err(e) = row_filters(
+        &ihdr,
+        &idat,
+        data.len(),
+        limits,
+        &mut inspection.row_filters,
+    ) {
+        inspection.errors.push(format!("Image data: {}", e));
+    }
+    inspection
+}
+
+/// The filter type of each row, read from the inflated image data
+fn row_filters(
+    ihdr: &IhdrData,
+    idat: &[u8],
+    file_size: usize,
+    limits: &Limits,
+    row_filters: &mut Vec<u8>,
+) -> PngResult<()> {
+    if idat.is_empty() {
+        return Err(PngError::ChunkMissing("IDAT"));
+    }
+    let size = limits.check_image(ihdr, file_size, 0)?;
+    let data = inflate(idat, size)?;
+    if data.len() != size {
+        return Err(PngError::TruncatedData);
+    }
+    let image = PngImage {
+        ihdr: *ihdr,
+        data,
+        palette: None,
+        transparency_pixel: None,
+        aux_headers: AuxHeaders::new(),
+    };
+    row_filters.extend(image.scan_lines(true).map(|l| l.filter));
+    Ok(())
+}
+
+impl fmt::Display for Inspection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Chunks:")?;
+        writeln!(f, "    {:>10}  name  {:>10}  crc", "offset", "length")?;
+        for chunk in &self.chunks {
+            writeln!(
+                f,
+                "    {:>10}  {}  {:>10}  {}",
+                chunk.offset,
+                String::from_utf8_lossy(&chunk.name),
+                chunk.length,
+                if chunk.crc_valid { "ok" } else { "MISMATCH" }
+            )?;
+        }
+        if self.trailing_data > 0 {
+            writeln!(f, "    {} bytes of trailing data", self.trailing_data)?;
+        }
+
+        if let Some(ref ihdr) = self.ihdr {
+            writeln!(f, "IHDR:")?;
+            writeln!(f, "    size: {}x{} pixels", ihdr.width, ihdr.height)?;
+            writeln!(
+                f,
+                "    color type: {} ({}), bit depth: {}",
+                ihdr.color_type,
+                ihdr.color_type.png_header_code(),
+                ihdr.bit_depth
+            )?;
+            writeln!(
+                f,
+                "    compression method: {}, filter method: {}, {}",
+                ihdr.compression, ihdr.filter, ihdr.interlaced
+            )?;
+        }
+
+        if let Some(ref palette) = self.palette {
+            writeln!(f, "Palette: {} entries", palette.len())?;
+            for (i, color) in palette.iter().enumerate() {
+                writeln!(
+                    f,
+                    "    {:>3}: #{:02x}{:02x}{:02x} alpha {}",
+                    i, color.r, color.g, color.b, color.a
+                )?;
+            }
+        }
+        if let Some(ref trns) = self.transparency_pixel {
+            writeln!(f, "tRNS: transparent pixel value {:02x?}", trns)?;
+        }
+
+        if !self.row_filters.is_empty() {
+            writeln!(f, "Row filters: {} rows", self.row_filters.len())?;
+            let mut counts = [0_usize; 5];
+            for &filter in &self.row_filters {
+                if let Some(count) = counts.get_mut(filter as usize) {
+                    *count += 1;
+                }
+            }
+            for (filter, &count) in counts.iter().enumerate() {
+                if count > 0 {
+                    writeln!(
+                        f,
+                        "    {}{:>8}  {:5.1}%",
+                        RowFilter::try_from(filter as u8).unwrap(),
+                        count,
+                        count as f64 * 100.0 / self.row_filters.len() as f64
+                    )?;
+                }
+            }
+        }
+
+        if let Some(ref header) = self.zlib_header {
+            writeln!(f, "zlib header:")?;
+            writeln!(
+                f,
+                "    method: {}, window size: {}, level hint: {}, preset dictionary: {}, check: {}",
+                header.method,
+                header.window_size(),
+                header.level,
+                if header.preset_dictionary {
+                    "yes"
+                } else {
+                    "no"
+                },
+                if header.check_valid { "ok" } else { "MISMATCH" }
+            )?;
+        }
+        if !self.deflate_blocks.is_empty() {
+            writeln!(f, "DEFLATE blocks: {}", self.deflate_blocks.len())?;
+            writeln!(
+                f,
+                "    {:>5}  type     {:>12}  {:>12}",
+                "block", "compressed", "uncompressed"
+            )?;
+            for (i, block) in self.deflate_blocks.iter().enumerate() {
+                writeln!(
+                    f,
+                    "    {:>5}  {:<7}  {:>12.1}  {:>12}{}",
+                    i,
+                    block.block_type,
+                    block.compressed_bits as f64 / 8.0,
+                    block.uncompressed_size,
+                    if block.is_final { "  final" } else { "" }
+                )?;
+            }
+        }
+
+        for error in &self.errors {
+            writeln!(f, "Error: {}", error)?;
+        }
+        Ok(())
+    }
+}
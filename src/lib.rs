@@ -25,40 +25,65 @@ extern crate rayon;
 mod rayon;
 
 use crate::atomicmin::AtomicMin;
+use crate::atomicwrite::{check_interrupted, write_in_place, TempFile};
+use crate::attributes::copy_attributes;
+use crate::backup::perform_backup;
 use crate::colors::BitDepth;
 use crate::deflate::{crc32, inflate};
 use crate::evaluate::Evaluator;
-use crate::png::PngData;
+use crate::headers::{is_c2pa_manifest, AuxHeaders, SAFE_HEADERS};
+use crate::links::{output_destination, Destination};
 use crate::png::PngImage;
+use crate::png::{PngData, Repair};
+use crate::raw_profile::convert_raw_profiles;
 use crate::reduction::*;
-use image::{DynamicImage, GenericImageView, ImageFormat, Pixel};
-use log::{debug, error, info, warn};
+use crate::sidecar::{write_sidecar, Sidecar};
+use image::{DynamicImage, GenericImageView, ImageFormat};
+use log::{debug, info, warn};
 use rayon::prelude::*;
 use std::fmt;
-use std::fs::{copy, File, Metadata};
+use std::fs::{self, Metadata};
 use std::io::{stdin, stdout, BufWriter, Cursor, Read, Write};
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-pub use crate::deflate::Deflaters;
+pub use crate::atomicwrite::{interrupt, is_interrupted, remove_temp_files};
+pub use crate::backup::{backup_path, restore_backup, Backup};
+pub use crate::deflate::{BlockType, DeflateBlock, Deflaters, ZlibHeader};
 pub use crate::error::PngError;
 pub use crate::filters::RowFilter;
-pub use crate::headers::Headers;
+pub use crate::headers::{C2PAPolicy, Headers, TrailingData};
+pub use crate::inspect::{inspect, inspect_file, ChunkInfo, Inspection};
 pub use crate::interlace::Interlacing;
+pub use crate::limits::Limits;
+pub use crate::links::LinkPolicy;
+pub use crate::lint::{diagnostics_to_json, lint, lint_file, Diagnostic, Severity};
+pub use crate::options::{OptionsBuilder, OptionsError};
+pub use crate::sidecar::{restore_metadata, restore_metadata_from_memory, sidecar_path};
 pub use indexmap::{indexset, IndexMap, IndexSet};
 
 mod atomicmin;
+mod atomicwrite;
+mod attributes;
+mod backup;
 mod colors;
 mod deflate;
 mod error;
 mod evaluate;
 mod filters;
 mod headers;
+mod inspect;
 mod interlace;
+mod limits;
+mod links;
+mod lint;
+mod options;
 mod png;
+mod raw_profile;
 mod reduction;
+mod sidecar;
 
 /// Private to oxipng; don't use outside tests and benches
 #[doc(hidden)]
@@ -112,6 +137,22 @@ impl fmt::Display for InFile {
     }
 }
 
+impl InFile {
+    /// Read the whole file, without checking that it is a PNG file
+    pub(crate) fn read(&self) -> PngResult<Vec<u8>> {
+        match *self {
+            InFile::Path(ref path) => std::fs::read(path).map_err(|e| PngError::io(Some(path), e)),
+            InFile::StdIn => {
+                let mut data = Vec::new();
+                stdin()
+                    .read_to_end(&mut data)
+                    .map_err(|e| PngError::io(None, e))?;
+                Ok(data)
+            }
+        }
+    }
+}
+
 impl<T: Into<PathBuf>> From<T> for InFile {
     fn from(s: T) -> Self {
         InFile::Path(s.into())
@@ -120,15 +161,72 @@ impl<T: Into<PathBuf>> From<T> for InFile {
 
 pub type PngResult<T> = Result<T, PngError>;
 
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+/// How to check that the optimized image has the same pixels as the input
+pub enum VerifyMode {
+    /// Don't check the output (it is always checked in debug builds)
+    Off,
+    /// Check that all visible pixels are identical
+    Visible,
+    /// Check that all pixels are identical, including the colour of fully transparent pixels
+    ///
+    /// This is the same as `Visible` when `optimize_alpha` is enabled,
+    /// as that allows transparent pixels to be altered.
+    Strict,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+/// How much smaller the optimized image must be for it to be written
+pub enum MinSavings {
+    /// A number of bytes
+    Bytes(u64),
+    /// A percentage of the input size, from 0 to 100
+    Percent(f64),
+}
+
+impl MinSavings {
+    /// The savings in bytes for an input of `original_size` bytes
+    fn bytes(self, original_size: usize) -> u64 {
+        match self {
+            MinSavings::Bytes(bytes) => bytes,
+            MinSavings::Percent(percent) => (original_size as f64 * percent / 100.0).ceil() as u64,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[non_exhaustive]
+/// What `optimize_with_outcome` did with a file
+pub enum Outcome {
+    /// The file was optimized, or was already optimized
+    Optimized,
+    /// The file was left untouched, as the link or C2PA policy skips it
+    Skipped,
+    /// The timeout was reached, and the file was optimized with the best result found by then
+    TimedOut,
+}
+
 #[derive(Clone, Debug)]
 /// Options controlling the output of the `optimize` function
+///
+/// The fields are not checked until the options are used, `OptionsBuilder` validates them
+/// up front and documents how presets combine with explicit settings.
 pub struct Options {
     /// Whether the input file should be backed up before writing the output.
     ///
     /// Default: `false`
     pub backup: bool,
+    /// Where to keep backups and how many, when `backup` is enabled
+    ///
+    /// Default: `Backup::default()`
+    pub backup_config: Backup,
     /// Attempt to fix errors when decoding the input file rather than returning an `Err`.
     ///
+    /// This ignores bad checksums, skips unreadable bytes between chunks, salvages as much
+    /// image data as possible from truncated files and clamps invalid palette indices.
+    /// Each repair is logged as a warning, and an image with salvaged pixel data is always
+    /// written.
+    ///
     /// Default: `false`
     pub fix_errors: bool,
     /// Don't actually run any optimizations, just parse the PNG file.
@@ -143,10 +241,32 @@ pub struct Options {
     ///
     /// Default: `false`
     pub force: bool,
-    /// Ensure the output file has the same permissions as the input file.
+    /// The least the optimized image must save for it to be written, otherwise the input is
+    /// left untouched (or copied to the output as it is).
+    ///
+    /// Images that were changed as requested by `strip` or `interlace` are always written.
+    ///
+    /// Default: `None`
+    pub min_savings: Option<MinSavings>,
+    /// Ensure the output file has the same permissions and file times as the input file.
+    ///
+    /// On Unix the owner and group are kept too, and on Linux the SELinux label, the POSIX ACL
+    /// and user extended attributes. Those that can't be copied, such as the owner without root,
+    /// are left as they are with a warning.
+    ///
+    /// Without it, a file that is replaced still keeps its permissions, owner and extended
+    /// attributes, but not its file times.
     ///
     /// Default: `false`
     pub preserve_attrs: bool,
+    /// What to do when the file replaced by the output is a symlink.
+    ///
+    /// Default: `WriteThrough`
+    pub symlinks: LinkPolicy,
+    /// What to do when the file replaced by the output has several hardlinks.
+    ///
+    /// Default: `WriteThrough`
+    pub hardlinks: LinkPolicy,
     /// Which RowFilters to try on the file
     ///
     /// Default: `None,Sub,Entropy,Bigrams`
@@ -188,6 +308,25 @@ pub struct Options {
     ///
     /// Default: `None`
     pub strip: Headers,
+    /// Whether to convert ImageMagick raw profile text chunks to native eXIf, iTXt (XMP) and
+    /// iCCP chunks, dropping them if the native chunk already exists.
+    ///
+    /// Default: `false`
+    pub convert_raw_profiles: bool,
+    /// Whether to write the headers removed by `strip` to a sidecar file next to the output file.
+    ///
+    /// The sidecar can be used to put the headers back with `restore_metadata`.
+    ///
+    /// Default: `false`
+    pub sidecar: bool,
+    /// What to do with files carrying C2PA content credentials
+    ///
+    /// Default: `Skip`
+    pub c2pa: C2PAPolicy,
+    /// What to do with data found after the end of the PNG stream
+    ///
+    /// Default: `Strip`
+    pub trailing_data: TrailingData,
     /// Which DEFLATE algorithm to use
     ///
     /// Default: `Libdeflater`
@@ -197,12 +336,30 @@ pub struct Options {
     /// Default: `true`
     pub fast_evaluation: bool,
 
+    /// Whether to decode the optimized image and compare it with the input.
+    ///
+    /// Default: `Off`
+    pub verify: VerifyMode,
+    /// Whether to output the original image instead of failing when verification finds a difference.
+    ///
+    /// Default: `false`
+    pub verify_fallback: bool,
+
+    /// Limits on the size of images and the memory used to process them.
+    ///
+    /// Default: no limits
+    pub limits: Limits,
+
     /// Maximum amount of time to spend on optimizations.
     /// Further potential optimizations are skipped if the timeout is exceeded.
     pub timeout: Option<Duration>,
 }
 
 impl Options {
+    /// Options for an optimization preset from 0 to 6, as with `-o` on the command line
+    ///
+    /// Presets set the filters, the libdeflater compression level and fast evaluation,
+    /// see `OptionsBuilder` for the values used by each preset.
     pub fn from_preset(level: u8) -> Options {
         let opts = Options::default();
         match level {
@@ -288,11 +445,15 @@ impl Default for Options {
         // Default settings based on -o 2 from the CLI interface
         Options {
             backup: false,
+            backup_config: Backup::default(),
             check: false,
             pretend: false,
             fix_errors: false,
             force: false,
+            min_savings: None,
             preserve_attrs: false,
+            symlinks: LinkPolicy::WriteThrough,
+            hardlinks: LinkPolicy::WriteThrough,
             filter: indexset! {RowFilter::None, RowFilter::Sub, RowFilter::Entropy, RowFilter::Bigrams},
             interlace: None,
             optimize_alpha: false,
@@ -302,8 +463,15 @@ impl Default for Options {
             grayscale_reduction: true,
             idat_recoding: true,
             strip: Headers::None,
+            convert_raw_profiles: false,
+            sidecar: false,
+            c2pa: C2PAPolicy::Skip,
+            trailing_data: TrailingData::Strip,
             deflate: Deflaters::Libdeflater { compression: 11 },
             fast_evaluation: true,
+            verify: VerifyMode::Off,
+            verify_fallback: false,
+            limits: Limits::default(),
             timeout: None,
         }
     }
@@ -311,8 +479,35 @@ impl Default for Options {
 
 /// Perform optimization on the input file using the options provided
 pub fn optimize(input: &InFile, output: &OutFile, opts: &Options) -> PngResult<()> {
+    optimize_with_outcome(input, output, opts).map(|_| ())
+}
+
+/// Perform optimization on the input file using the options provided, reporting whether the
+/// file was skipped
+pub fn optimize_with_outcome(
+    input: &InFile,
+    output: &OutFile,
+    opts: &Options,
+) -> PngResult<Outcome> {
     // Read in the file and try to decode as PNG.
     info!("Processing: {}", input);
+    opts.validate()?;
+    check_interrupted()?;
+
+    // Apply the link policies up front, so files that are skipped aren't optimized
+    let destination = match (output, input) {
+        (&OutFile::StdOut, _) | (&OutFile::Path(None), &InFile::StdIn) => None,
+        (&OutFile::Path(ref output_path), _) => {
+            let output_path = output_path
+                .as_ref()
+                .map(|p| p.as_path())
+                .unwrap_or_else(|| input.path().unwrap());
+            match output_destination(output_path, opts)? {
+                Some(destination) => Some((output_path, destination)),
+                None => return Ok(Outcome::Skipped),
+            }
+        }
+    };
 
     let deadline = Arc::new(Deadline::new(opts.timeout));
 
@@ -323,13 +518,8 @@ pub fn optimize(input: &InFile, output: &OutFile, opts: &Options) -> PngResult<(
             if opts.preserve_attrs {
                 opt_metadata_preserved = input_path
                     .metadata()
-                    .map_err(|err| {
-                        // Fail if metadata cannot be preserved
-                        PngError::new(&format!(
-                            "Unable to read metadata from input file {:?}: {}",
-                            input_path, err
-                        ))
-                    })
+                    // Fail if metadata cannot be preserved
+                    .map_err(|e| PngError::io(Some(input_path), e))
                     .map(Some)?;
                 debug!("preserving metadata: {:?}", opt_metadata_preserved);
             } else {
@@ -342,83 +532,137 @@ pub fn optimize(input: &InFile, output: &OutFile, opts: &Options) -> PngResult<(
             let mut data = Vec::new();
             stdin()
                 .read_to_end(&mut data)
-                .map_err(|e| PngError::new(&format!("Error reading stdin: {}", e)))?;
+                .map_err(|e| PngError::io(None, e))?;
             data
         }
     };
 
-    let mut png = PngData::from_slice(&in_data, opts.fix_errors)?;
+    let mut png = match PngData::from_slice_with_options(&in_data, opts) {
+        Err(PngError::C2PAProtected) => {
+            warn!("{}: {}; skipping", input, PngError::C2PAProtected);
+            // A separate output still gets the image, unchanged
+            if !writes_to_input(input, output) && !opts.pretend && !opts.check {
+                write_output(
+                    input,
+                    destination,
+                    &in_data,
+                    None,
+                    opt_metadata_preserved.as_ref(),
+                    opts,
+                )?;
+            }
+            return Ok(Outcome::Skipped);
+        }
+        png => png?,
+    };
 
     if opts.check {
         info!("Running in check mode, not optimizing");
-        return Ok(());
+        return Ok(Outcome::Optimized);
     }
 
     // Run the optimizer on the decoded PNG.
-    let mut optimized_output = optimize_png(&mut png, &in_data, opts, deadline)?;
+    let (mut optimized_output, mut sidecar, changed) =
+        optimize_png(&mut png, &in_data, opts, deadline.clone())?;
+    check_interrupted()?;
+    let outcome = if deadline.timed_out() {
+        Outcome::TimedOut
+    } else {
+        Outcome::Optimized
+    };
 
-    if is_fully_optimized(in_data.len(), optimized_output.len(), opts) {
-        info!("File already optimized");
-        match (output, input) {
-            // if p is None, it also means same as the input path
-            (&OutFile::Path(ref p), &InFile::Path(ref input_path))
-                if p.as_ref().map_or(true, |p| p == input_path) =>
-            {
-                return Ok(());
-            }
-            _ => {
-                optimized_output = in_data;
-            }
+    // An image with salvaged pixel data must be written even if it is larger, the original is
+    // damaged. Other repairs, such as a fixed CRC, don't make the original unusable.
+    if !png.repairs.iter().any(Repair::salvages_image_data)
+        && keep_original(in_data.len(), optimized_output.len(), changed, opts)
+    {
+        if writes_to_input(input, output) {
+            return Ok(outcome);
         }
+        optimized_output = in_data;
+        sidecar = None;
     }
 
     if opts.pretend {
         info!("Running in pretend mode, no output");
-        return Ok(());
+        return Ok(outcome);
     }
 
+    write_output(
+        input,
+        destination,
+        &optimized_output,
+        sidecar.as_ref(),
+        opt_metadata_preserved.as_ref(),
+        opts,
+    )?;
+    Ok(outcome)
+}
+
+/// Whether the output replaces the input file itself
+fn writes_to_input(input: &InFile, output: &OutFile) -> bool {
     match (output, input) {
-        (&OutFile::StdOut, _) | (&OutFile::Path(None), &InFile::StdIn) => {
+        // if p is None, it also means same as the input path
+        (&OutFile::Path(ref p), &InFile::Path(ref input_path)) => {
+            p.as_ref().map_or(true, |p| p == input_path)
+        }
+        _ => false,
+    }
+}
+
+/// Write the output to stdout, or to a file at the destination chosen by the link policies
+///
+/// With `metadata_input`, the attributes of the input file are carried over.
+fn write_output(
+    input: &InFile,
+    destination: Option<(&Path, Destination)>,
+    output: &[u8],
+    sidecar: Option<&Sidecar>,
+    metadata_input: Option<&Metadata>,
+    opts: &Options,
+) -> PngResult<()> {
+    match destination {
+        None => {
+            if sidecar.is_some() {
+                warn!("Can't write a sidecar file when writing to stdout");
+            }
             let mut buffer = BufWriter::new(stdout());
             buffer
-                .write_all(&optimized_output)
-                .map_err(|e| PngError::new(&format!("Unable to write to stdout: {}", e)))?;
+                .write_all(output)
+                .map_err(|e| PngError::io(None, e))?;
         }
-        (&OutFile::Path(ref output_path), _) => {
-            let output_path = output_path
-                .as_ref()
-                .map(|p| p.as_path())
-                .unwrap_or_else(|| input.path().unwrap());
+        Some((output_path, destination)) => {
             if opts.backup {
-                perform_backup(output_path)?;
+                perform_backup(output_path, &opts.backup_config)?;
             }
-            let out_file = File::create(output_path).map_err(|err| {
-                PngError::new(&format!(
-                    "Unable to write to file {}: {}",
-                    output_path.display(),
-                    err
-                ))
-            })?;
-            if let Some(metadata_input) = &opt_metadata_preserved {
-                copy_permissions(metadata_input, &out_file)?;
+            // Write the sidecar first so the removed headers are never lost
+            if let Some(sidecar) = sidecar {
+                write_sidecar(output_path, sidecar)?;
             }
-
-            let mut buffer = BufWriter::new(out_file);
-            buffer
-                .write_all(&optimized_output)
-                // flush BufWriter so IO errors don't get swallowed silently on close() by drop!
-                .and_then(|()| buffer.flush())
-                .map_err(|e| {
-                    PngError::new(&format!(
-                        "Unable to write to {}: {}",
-                        output_path.display(),
-                        e
-                    ))
-                })?;
-            // force drop and thereby closing of file handle before modifying any timestamp
-            std::mem::drop(buffer);
-            if let Some(metadata_input) = &opt_metadata_preserved {
-                copy_times(metadata_input, output_path)?;
+            match destination {
+                Destination::Replace(dest) => {
+                    // Write to a temporary file and rename it over the output, so the output is
+                    // never left partially written
+                    let mut temp = TempFile::replacing(dest)?;
+                    temp.write_all(output)?;
+                    if let Some(metadata_input) = metadata_input {
+                        copy_attributes(input.path().unwrap(), metadata_input, &temp);
+                        temp.set_permissions(metadata_input.permissions());
+                        copy_times(metadata_input, temp.path())?;
+                    } else if let Ok(metadata) = fs::metadata(temp.dest()) {
+                        // The replacement is a new file, so it only has the permissions of the
+                        // file it replaces unless its owner and attributes are copied too
+                        copy_attributes(temp.dest(), &metadata, &temp);
+                    }
+                    temp.persist()?;
+                }
+                Destination::InPlace(dest) => {
+                    // The file keeps its owner, permissions and attributes
+                    write_in_place(&dest, output)?;
+                    if let Some(metadata_input) = metadata_input {
+                        copy_times(metadata_input, &dest)?;
+                    }
+                }
             }
             info!("Output: {}", output_path.display());
         }
@@ -431,17 +675,29 @@ pub fn optimize(input: &InFile, output: &OutFile, opts: &Options) -> PngResult<(
 pub fn optimize_from_memory(data: &[u8], opts: &Options) -> PngResult<Vec<u8>> {
     // Read in the file and try to decode as PNG.
     info!("Processing from memory");
+    opts.validate()?;
+    check_interrupted()?;
 
     let deadline = Arc::new(Deadline::new(opts.timeout));
 
     let original_size = data.len();
-    let mut png = PngData::from_slice(data, opts.fix_errors)?;
+    let mut png = match PngData::from_slice_with_options(data, opts) {
+        Err(PngError::C2PAProtected) => {
+            warn!("{}; skipping", PngError::C2PAProtected);
+            return Ok(data.to_vec());
+        }
+        png => png?,
+    };
 
     // Run the optimizer on the decoded PNG.
-    let optimized_output = optimize_png(&mut png, data, opts, deadline)?;
-
-    if is_fully_optimized(original_size, optimized_output.len(), opts) {
-        info!("Image already optimized");
+    let (optimized_output, _, changed) = optimize_png(&mut png, data, opts, deadline)?;
+    check_interrupted()?;
+
+    // An image with salvaged pixel data must be written even if it is larger, the original is
+    // damaged. Other repairs, such as a fixed CRC, don't make the original unusable.
+    if !png.repairs.iter().any(Repair::salvages_image_data)
+        && keep_original(original_size, optimized_output.len(), changed, opts)
+    {
         Ok(data.to_vec())
     } else {
         Ok(optimized_output)
@@ -457,12 +713,15 @@ struct TrialOptions {
 type TrialWithData = (TrialOptions, Vec<u8>);
 
 /// Perform optimization on the input PNG object using the options provided
+///
+/// Returns the output, the sidecar if requested, and whether headers were stripped or the
+/// interlacing was changed.
 fn optimize_png(
     png: &mut PngData,
     original_data: &[u8],
     opts: &Options,
     deadline: Arc<Deadline>,
-) -> PngResult<Vec<u8>> {
+) -> PngResult<(Vec<u8>, Option<Sidecar>, bool)> {
     // Print png info
     let file_original_size = original_data.len();
     let idat_original_size = png.idat_data.len();
@@ -474,18 +733,43 @@ fn optimize_png(
     info!("    IDAT size = {} bytes", idat_original_size);
     info!("    File size = {} bytes", file_original_size);
 
+    // Trailing data is not part of the image, set it aside until the output is assembled
+    let trailing_data = std::mem::take(&mut png.trailing_data);
+    // A damaged original can't be decoded reliably, so verify against the repaired image
+    let repaired_data = (!png.repairs.is_empty()).then(|| png.output());
+    let reference = repaired_data.as_deref().unwrap_or(original_data);
+
+    // The sidecar restores the headers as they were read, including those removed or converted
+    let sidecar_headers = opts.sidecar.then(|| png.raw.aux_headers.clone());
+    if opts.c2pa == C2PAPolicy::Strip {
+        Arc::make_mut(&mut png.raw)
+            .aux_headers
+            .retain(|name, data| !is_c2pa_manifest(name, data));
+    }
+    if opts.convert_raw_profiles {
+        convert_raw_profiles(Arc::make_mut(&mut png.raw), &opts.limits);
+    }
+
     // Do this first so that reductions can ignore certain chunks such as bKGD
+    let original_headers = (opts.strip != Headers::None).then(|| png.raw.aux_headers.clone());
     perform_strip(png, opts);
+    let sidecar = sidecar_headers.and_then(|original| Sidecar::new(&png.raw, &original));
+    let stripped = original_headers.map_or(false, |headers| headers != png.raw.aux_headers);
     let stripped_png = png.clone();
+    let original_interlacing = png.raw.ihdr.interlaced;
 
     // Interlacing is not part of the evaluator trials but must be done first to evaluate the rest correctly
     let mut reduction_occurred = false;
+    let mut interlacing_changed = false;
     if let Some(interlacing) = opts.interlace {
         if let Some(reduced) = png.raw.change_interlacing(interlacing) {
             png.raw = Arc::new(reduced);
             reduction_occurred = true;
+            interlacing_changed = true;
         }
     }
+    // The requested interlacing must be kept even if the image is larger
+    let any_size = opts.force || interlacing_changed;
 
     // If alpha optimization is enabled, perform a black alpha reduction before evaluating reductions
     // This can allow reductions from alpha to indexed which may not have been possible otherwise
@@ -545,25 +829,33 @@ fn optimize_png(
                 }
             }
 
-            let trial = TrialOptions {
-                filter: eval_filter.unwrap(),
+            let trial = eval_filter.map(|filter| TrialOptions {
+                filter,
                 compression: match opts.deflate {
                     Deflaters::Libdeflater { compression } => compression,
                     _ => 0,
                 },
-            };
-            if trial.compression > 0 && trial.compression <= eval_compression {
-                // No further compression required
-                if png.idat_data.len() < idat_original_size || opts.force {
-                    Some((trial, png.idat_data.clone()))
-                } else {
-                    None
+            });
+            match trial {
+                // The deadline passed before any filter was evaluated
+                None => None,
+                Some(trial) if trial.compression > 0 && trial.compression <= eval_compression => {
+                    // No further compression required
+                    if png.idat_data.len() < idat_original_size || any_size {
+                        Some((trial, png.idat_data.clone()))
+                    } else {
+                        None
+                    }
+                }
+                Some(trial) => {
+                    info!("Trying: {}", trial.filter);
+                    let original_len = idat_original_size;
+                    let best_size =
+                        AtomicMin::new(if any_size { None } else { Some(original_len) });
+                    let result = perform_trial(&png.filtered, opts, trial, &best_size);
+                    log_trial(trial, &result);
+                    result.ok().map(|n| (trial, n))
                 }
-            } else {
-                info!("Trying: {}", trial.filter);
-                let original_len = idat_original_size;
-                let best_size = AtomicMin::new(if opts.force { None } else { Some(original_len) });
-                perform_trial(&png.filtered, opts, trial, &best_size)
             }
         } else {
             // Perform full compression trials of selected filters and determine the best
@@ -594,22 +886,32 @@ fn optimize_png(
             info!("Trying: {} filters", results.len());
 
             let original_len = idat_original_size;
-            let best_size = AtomicMin::new(if opts.force { None } else { Some(original_len) });
+            let best_size = AtomicMin::new(if any_size { None } else { Some(original_len) });
             let results_iter = results.into_par_iter().with_max_len(1);
-            let best = results_iter.filter_map(|trial| {
-                if deadline.passed() {
-                    return None;
-                }
-                let filtered = &png.raw.filter_image(trial.filter, opts.optimize_alpha);
-                perform_trial(filtered, opts, trial, &best_size)
-            });
-            best.reduce_with(|i, j| {
-                if i.1.len() < j.1.len() || (i.1.len() == j.1.len() && i.0 < j.0) {
-                    i
-                } else {
-                    j
-                }
-            })
+            let trials: Vec<_> = results_iter
+                .filter_map(|trial| {
+                    if deadline.passed() {
+                        return None;
+                    }
+                    let filtered = &png.raw.filter_image(trial.filter, opts.optimize_alpha);
+                    Some((trial, perform_trial(filtered, opts, trial, &best_size)))
+                })
+                .collect();
+            // Log the trials from this thread, so they are in order and with the rest of the
+            // output for this image
+            trials
+                .into_iter()
+                .filter_map(|(trial, result)| {
+                    log_trial(trial, &result);
+                    result.ok().map(|n| (trial, n))
+                })
+                .reduce(|i, j| {
+                    if i.1.len() < j.1.len() || (i.1.len() == j.1.len() && i.0 < j.0) {
+                        i
+                    } else {
+                        j
+                    }
+                })
         };
 
         if let Some((opts, idat_data)) = best {
@@ -628,7 +930,10 @@ fn optimize_png(
         *png = stripped_png;
     }
 
+    png.trailing_data = trailing_data;
     let output = png.output();
+    // Whether the image was changed as requested, rather than just made smaller
+    let changed = stripped || png.raw.ihdr.interlaced != original_interlacing;
 
     if idat_original_size >= png.idat_data.len() {
         info!(
@@ -659,9 +964,29 @@ fn optimize_png(
         );
     }
 
-    debug_assert!(validate_output(&output, original_data));
+    match opts.verify {
+        VerifyMode::Off => {
+            if cfg!(debug_assertions) {
+                if let Err(e) = verify_output(&output, reference, false, &opts.limits) {
+                    panic!("{}", e);
+                }
+            }
+        }
+        mode => {
+            let strict = mode == VerifyMode::Strict && !opts.optimize_alpha;
+            if let Err(e) = verify_output(&output, reference, strict, &opts.limits) {
+                if !opts.verify_fallback {
+                    return Err(e);
+                }
+                warn!("{}; keeping the original image", e);
+                // The input as it was read, with any trailing data and without repairs
+                return Ok((original_data.to_vec(), None, false));
+            }
+            debug!("Verified the optimized image");
+        }
+    }
 
-    Ok(output)
+    Ok((output, sidecar, changed))
 }
 
 fn perform_reductions(
@@ -725,12 +1050,14 @@ fn perform_reductions(
 }
 
 /// Execute a compression trial
+/// Compress the filtered data, failing with `DeflatedDataTooLong` if it is not smaller than
+/// the best so far
 fn perform_trial(
     filtered: &[u8],
     opts: &Options,
     trial: TrialOptions,
     best_size: &AtomicMin,
-) -> Option<TrialWithData> {
+) -> PngResult<Vec<u8>> {
     let new_idat = match opts.deflate {
         Deflaters::Libdeflater { .. } => deflate::deflate(filtered, trial.compression, best_size),
         #[cfg(feature = "zopfli")]
@@ -738,28 +1065,29 @@ fn perform_trial(
     };
 
     // update best size or convert to error if not smaller
-    let new_idat = match new_idat {
+    match new_idat {
         Ok(n) if !best_size.set_min(n.len()) => Err(PngError::DeflatedDataTooLong(n.len())),
         _ => new_idat,
-    };
+    }
+}
 
-    match new_idat {
-        Ok(n) => {
-            let bytes = n.len();
+fn log_trial(trial: TrialOptions, result: &PngResult<Vec<u8>>) {
+    match *result {
+        Ok(ref n) => {
             debug!(
                 "    zc = {}  f = {}  {} bytes",
-                trial.compression, trial.filter, bytes
+                trial.compression,
+                trial.filter,
+                n.len()
             );
-            Some((trial, n))
         }
         Err(PngError::DeflatedDataTooLong(bytes)) => {
             debug!(
                 "    zc = {}  f = {} >{} bytes",
                 trial.compression, trial.filter, bytes,
             );
-            None
         }
-        Err(_) => None,
+        Err(_) => (),
     }
 }
 
@@ -767,7 +1095,7 @@ fn perform_trial(
 struct DeadlineImp {
     start: Instant,
     timeout: Duration,
-    print_message: AtomicBool,
+    timed_out: AtomicBool,
 }
 
 /// Keep track of processing timeout
@@ -783,26 +1111,22 @@ impl Deadline {
             imp: timeout.map(|timeout| DeadlineImp {
                 start: Instant::now(),
                 timeout,
-                print_message: AtomicBool::new(true),
+                timed_out: AtomicBool::new(false),
             }),
         }
     }
 
-    /// True if the timeout has passed, and no new work should be done.
+    /// True if the timeout has passed or processing was interrupted, and no new work should be done.
     ///
     /// If the verbose option is on, it also prints a timeout message once.
     pub fn passed(&self) -> bool {
+        if is_interrupted() {
+            return true;
+        }
         if let Some(imp) = &self.imp {
             let elapsed = imp.start.elapsed();
             if elapsed > imp.timeout {
-                if match imp.print_message.compare_exchange(
-                    true,
-                    false,
-                    Ordering::SeqCst,
-                    Ordering::SeqCst,
-                ) {
-                    Ok(x) | Err(x) => x,
-                } {
+                if !imp.timed_out.swap(true, Ordering::SeqCst) {
                     warn!("Timed out after {} second(s)", elapsed.as_secs());
                 }
                 return true;
@@ -810,6 +1134,13 @@ impl Deadline {
         }
         false
     }
+
+    /// True if the timeout was reached while processing, so the result may not be the best one
+    pub fn timed_out(&self) -> bool {
+        self.imp
+            .as_ref()
+            .map_or(false, |imp| imp.timed_out.load(Ordering::SeqCst))
+    }
 }
 
 /// Display the format of the image data
@@ -849,17 +1180,10 @@ fn perform_strip(png: &mut PngData, opts: &Options) {
             }
         }
         Headers::Safe => {
-            const PRESERVED_HEADERS: [[u8; 4]; 5] =
-                [*b"cICP", *b"iCCP", *b"sBIT", *b"sRGB", *b"pHYs"];
-            let keys: Vec<[u8; 4]> = raw.aux_headers.keys().cloned().collect();
-            for hdr in &keys {
-                if !PRESERVED_HEADERS.contains(hdr) {
-                    raw.aux_headers.remove(hdr);
-                }
-            }
+            raw.aux_headers.retain(|hdr, _| SAFE_HEADERS.contains(hdr));
         }
         Headers::All => {
-            raw.aux_headers = IndexMap::new();
+            raw.aux_headers = AuxHeaders::new();
         }
     }
 
@@ -931,96 +1255,33 @@ fn srgb_rendering_intent(mut iccp: &[u8]) -> Option<u8> {
     }
 }
 
-/// Check if an image was already optimized prior to oxipng's operations
-fn is_fully_optimized(original_size: usize, optimized_size: usize, opts: &Options) -> bool {
-    original_size <= optimized_size && !opts.force && opts.interlace.is_none()
-}
-
-fn perform_backup(input_path: &Path) -> PngResult<()> {
-    let backup_file = input_path.with_extension(format!(
-        "bak.{}",
-        input_path.extension().unwrap().to_str().unwrap()
-    ));
-    copy(input_path, &backup_file).map(|_| ()).map_err(|_| {
-        PngError::new(&format!(
-            "Unable to write to backup file at {}",
-            backup_file.display()
-        ))
-    })
-}
-
-#[cfg(not(unix))]
-fn copy_permissions(metadata_input: &Metadata, out_file: &File) -> PngResult<()> {
-    let readonly_input = metadata_input.permissions().readonly();
-
-    out_file
-        .metadata()
-        .map_err(|err_io| {
-            PngError::new(&format!(
-                "unable to read filesystem metadata of output file: {}",
-                err_io
-            ))
-        })
-        .and_then(|out_meta| {
-            out_meta.permissions().set_readonly(readonly_input);
-            out_file
-                .metadata()
-                .map_err(|err_io| {
-                    PngError::new(&format!(
-                        "unable to re-read filesystem metadata of output file: {}",
-                        err_io
-                    ))
-                })
-                .and_then(|out_meta_reread| {
-                    if out_meta_reread.permissions().readonly() != readonly_input {
-                        Err(PngError::new(&format!(
-                            "failed to set readonly, expected: {}, found: {}",
-                            readonly_input,
-                            out_meta_reread.permissions().readonly()
-                        )))
-                    } else {
-                        Ok(())
-                    }
-                })
-        })
-}
-
-#[cfg(unix)]
-fn copy_permissions(metadata_input: &Metadata, out_file: &File) -> PngResult<()> {
-    use std::os::unix::fs::PermissionsExt;
-
-    let permissions = metadata_input.permissions().mode();
-
-    out_file
-        .metadata()
-        .map_err(|err_io| {
-            PngError::new(&format!(
-                "unable to read filesystem metadata of output file: {}",
-                err_io
-            ))
-        })
-        .and_then(|out_meta| {
-            out_meta.permissions().set_mode(permissions);
-            out_file
-                .metadata()
-                .map_err(|err_io| {
-                    PngError::new(&format!(
-                        "unable to re-read filesystem metadata of output file: {}",
-                        err_io
-                    ))
-                })
-                .and_then(|out_meta_reread| {
-                    if out_meta_reread.permissions().mode() != permissions {
-                        Err(PngError::new(&format!(
-                            "failed to set permissions, expected: {:04o}, found: {:04o}",
-                            permissions,
-                            out_meta_reread.permissions().mode()
-                        )))
-                    } else {
-                        Ok(())
-                    }
-                })
-        })
+/// Check if the original image should be kept, as it was already optimized prior to oxipng's
+/// operations or the optimized image does not save enough
+///
+/// Images that were changed as requested are always written, whatever their size.
+fn keep_original(
+    original_size: usize,
+    optimized_size: usize,
+    changed: bool,
+    opts: &Options,
+) -> bool {
+    if opts.force || changed {
+        return false;
+    }
+    let savings = original_size.saturating_sub(optimized_size) as u64;
+    if savings == 0 {
+        info!("File already optimized");
+        return true;
+    }
+    let min_savings = opts.min_savings.map_or(0, |min| min.bytes(original_size));
+    if savings < min_savings {
+        info!(
+            "Saved {} bytes, less than the minimum of {} bytes; keeping the original",
+            savings, min_savings
+        );
+        return true;
+    }
+    false
 }
 
 #[cfg(not(feature = "filetime"))]
@@ -1036,54 +1297,84 @@ fn copy_times(input_path_meta: &Metadata, out_path: &Path) -> PngResult<()> {
         "attempting to set file times: atime: {:?}, mtime: {:?}",
         atime, mtime
     );
-    filetime::set_file_times(out_path, atime, mtime).map_err(|err_io| {
-        PngError::new(&format!(
-            "unable to set file times on {:?}: {}",
-            out_path, err_io
-        ))
-    })
+    filetime::set_file_times(out_path, atime, mtime).map_err(|e| PngError::io(Some(out_path), e))
 }
 
 /// Validate that the output png data still matches the original image
-fn validate_output(output: &[u8], original_data: &[u8]) -> bool {
+/// Decode the input and output images and check that their pixels are identical
+///
+/// If `strict` is false, fully transparent pixels may have different colors.
+fn verify_output(
+    output: &[u8],
+    original_data: &[u8],
+    strict: bool,
+    limits: &Limits,
+) -> PngResult<()> {
     let (old_png, new_png) = rayon::join(
-        || load_png_image_from_memory(original_data),
-        || load_png_image_from_memory(output),
+        || load_png_image_from_memory(original_data, limits),
+        || load_png_image_from_memory(output, limits),
     );
 
-    match (new_png, old_png) {
-        (Err(new_err), _) => {
-            error!("Failed to read output image for validation: {}", new_err);
-            false
-        }
-        (_, Err(old_err)) => {
+    let new_png = new_png.map_err(|e| PngError::OutputNotDecodable(e.to_string().into()))?;
+    let old_png = match old_png {
+        Ok(png) => png,
+        Err(e) => {
             // The original image might be invalid if, for example, there is a CRC error,
             // and we set fix_errors to true. In that case, all we can do is check that the
             // new image is decodable.
-            warn!("Failed to read input image for validation: {}", old_err);
-            true
+            warn!("Failed to read input image for verification: {}", e);
+            return Ok(());
         }
-        (Ok(new_png), Ok(old_png)) => images_equal(&old_png, &new_png),
+    };
+
+    match first_difference(&old_png, &new_png, strict) {
+        Some((x, y)) => Err(PngError::VerificationFailed { x, y }),
+        None => Ok(()),
     }
 }
 
 /// Loads a PNG image from memory to a [DynamicImage]
-fn load_png_image_from_memory(png_data: &[u8]) -> Result<DynamicImage, image::ImageError> {
+fn load_png_image_from_memory(
+    png_data: &[u8],
+    limits: &Limits,
+) -> Result<DynamicImage, image::ImageError> {
     let mut reader = image::io::Reader::new(Cursor::new(png_data));
     reader.set_format(ImageFormat::Png);
-    reader.no_limits();
+    let mut image_limits = image::io::Limits::no_limits();
+    image_limits.max_alloc = limits.max_memory;
+    // The decoder has no limit on the pixel count, but neither side can be longer than it
+    let max_side = limits
+        .max_pixels
+        .map(|max| u32::try_from(max).unwrap_or(u32::MAX));
+    image_limits.max_image_width = max_side;
+    image_limits.max_image_height = max_side;
+    reader.limits(image_limits);
     reader.decode()
 }
 
-/// Compares images pixel by pixel for equivalent content
-fn images_equal(old_png: &DynamicImage, new_png: &DynamicImage) -> bool {
-    let a = old_png.pixels().filter(|x| {
-        let p = x.2.channels();
-        !(p.len() == 4 && p[3] == 0)
-    });
-    let b = new_png.pixels().filter(|x| {
-        let p = x.2.channels();
-        !(p.len() == 4 && p[3] == 0)
-    });
-    a.eq(b)
+/// Compares images pixel by pixel, returning the position of the first difference
+fn first_difference(
+    old_png: &DynamicImage,
+    new_png: &DynamicImage,
+    strict: bool,
+) -> Option<(u32, u32)> {
+    if old_png.dimensions() != new_png.dimensions() {
+        return Some((0, 0));
+    }
+
+    let new_pixels = new_png.to_rgba16();
+    for (x, y, old) in old_png.to_rgba16().enumerate_pixels() {
+        let new = new_pixels.get_pixel(x, y);
+        if !strict && old[3] == 0 && new[3] == 0 {
+            continue;
+        }
+        if old != new {
+            warn!(
+                "Pixel ({}, {}) differs: input is {:?}, output is {:?}",
+                x, y, old.0, new.0
+            );
+            return Some((x, y));
+        }
+    }
+    None
 }
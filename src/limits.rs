@@ -0,0 +1,80 @@
+use crate::error::PngError;
+use crate::headers::IhdrData;
+use crate::PngResult;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+/// Limits on the resources used to process an image, to guard against decompression bombs
+///
+/// Images exceeding a limit are rejected with an error naming that limit,
+/// before any memory is allocated for the decoded image data.
+pub struct Limits {
+    /// Maximum number of pixels (width × height)
+    ///
+    /// Default: `None`
+    pub max_pixels: Option<u64>,
+    /// Maximum size of the decoded (uncompressed) image data, in bytes
+    ///
+    /// Default: `None`
+    pub max_decoded_bytes: Option<u64>,
+    /// Maximum size of the data of a single chunk, in bytes
+    ///
+    /// Default: `None`
+    pub max_chunk_size: Option<u32>,
+    /// Maximum memory to use for an image, in bytes
+    ///
+    /// This is an estimate covering the input file and the copies of the decoded image data
+    /// held during optimization, which depends on the number of filters being tried.
+    ///
+    /// Default: `None`
+    pub max_memory: Option<u64>,
+}
+
+impl Limits {
+    /// Check the size of a chunk against the limits
+    pub(crate) fn check_chunk(&self, name: &[u8; 4], size: usize) -> PngResult<()> {
+        match self.max_chunk_size {
+            Some(max) if size > max as usize => Err(PngError::ChunkSizeLimitExceeded(*name, size)),
+            _ => Ok(()),
+        }
+    }
+
+    /// The most data to inflate for compressed metadata of unknown size, at most `cap` bytes
+    pub(crate) fn max_inflated_size(&self, cap: usize) -> usize {
+        [self.max_decoded_bytes, self.max_memory]
+            .iter()
+            .flatten()
+            .fold(cap, |max, &limit| {
+                max.min(usize::try_from(limit).unwrap_or(usize::MAX))
+            })
+    }
+
+    /// Check an image against the limits, returning the size of its decoded data
+    pub(crate) fn check_image(
+        &self,
+        ihdr: &IhdrData,
+        file_size: usize,
+        filters: usize,
+    ) -> PngResult<usize> {
+        let pixels = u64::from(ihdr.width) * u64::from(ihdr.height);
+        if matches!(self.max_pixels, Some(max) if pixels > max) {
+            return Err(PngError::PixelLimitExceeded(pixels));
+        }
+
+        let decoded = ihdr.checked_raw_data_size().unwrap_or(u64::MAX);
+        if matches!(self.max_decoded_bytes, Some(max) if decoded > max) {
+            return Err(PngError::DecodedSizeLimitExceeded(decoded));
+        }
+
+        // The filtered and unfiltered data, a reduced image and one buffer per filter trial
+        let memory = (filters as u64 + 3)
+            .checked_mul(decoded)
+            .and_then(|m| m.checked_add(file_size as u64))
+            .unwrap_or(u64::MAX);
+        if matches!(self.max_memory, Some(max) if memory > max) {
+            return Err(PngError::MemoryLimitExceeded(memory));
+        }
+
+        // Even without limits, the data must be addressable on this platform
+        usize::try_from(decoded).map_err(|_| PngError::DecodedSizeLimitExceeded(decoded))
+    }
+}
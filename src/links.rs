@@ -0,0 +1,105 @@
+use crate::error::PngError;
+use crate::{Options, PngResult};
+use log::info;
+use std::fs::{self, Metadata};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+/// What to do when the file replaced by the output is a symlink or has several hardlinks
+pub enum LinkPolicy {
+    /// Write the output to the file behind the link, so every link to it sees the output
+    ///
+    /// For hardlinks the file is overwritten in place rather than replaced, which keeps it
+    /// shared but means a crash while writing can leave it partially written.
+    WriteThrough,
+    /// Replace the link itself with the output, leaving the file it shared untouched
+    Replace,
+    /// Leave the file untouched, without optimizing it
+    Skip,
+}
+
+/// How the output is written to a path that already holds a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Destination {
+    /// Write to a temporary file and rename it over this path
+    Replace(PathBuf),
+    /// Overwrite the contents of the existing file at this path
+    InPlace(PathBuf),
+}
+
+/// Apply the link policies to the file the output will replace, reporting the policy used
+///
+/// Returns `None` if the file should be skipped.
+pub(crate) fn output_destination(path: &Path, opts: &Options) -> PngResult<Option<Destination>> {
+    let meta = match fs::symlink_metadata(path) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Ok(Some(Destination::Replace(path.to_owned())))
+        }
+        Err(e) => return Err(PngError::io(Some(path), e)),
+    };
+
+    let (dest, meta) = if meta.file_type().is_symlink() {
+        match opts.symlinks {
+            LinkPolicy::Skip => {
+                info!("{} is a symlink, skipping", path.display());
+                return Ok(None);
+            }
+            LinkPolicy::Replace => {
+                info!("{} is a symlink, replacing the link", path.display());
+                return Ok(Some(Destination::Replace(path.to_owned())));
+            }
+            LinkPolicy::WriteThrough => {
+                let target = fs::canonicalize(path).map_err(|e| PngError::io(Some(path), e))?;
+                info!(
+                    "{} is a symlink, writing through to {}",
+                    path.display(),
+                    target.display()
+                );
+                let meta = fs::metadata(&target).map_err(|e| PngError::io(Some(&target), e))?;
+                (target, meta)
+            }
+        }
+    } else {
+        (path.to_owned(), meta)
+    };
+
+    let links = hardlinks(&meta);
+    if links > 1 {
+        match opts.hardlinks {
+            LinkPolicy::Skip => {
+                info!("{} has {} hardlinks, skipping", dest.display(), links);
+                return Ok(None);
+            }
+            LinkPolicy::Replace => {
+                info!(
+                    "{} has {} hardlinks, replacing only this one",
+                    dest.display(),
+                    links
+                );
+            }
+            LinkPolicy::WriteThrough => {
+                info!(
+                    "{} has {} hardlinks, writing into the shared file",
+                    dest.display(),
+                    links
+                );
+                return Ok(Some(Destination::InPlace(dest)));
+            }
+        }
+    }
+    Ok(Some(Destination::Replace(dest)))
+}
+
+#[cfg(unix)]
+fn hardlinks(meta: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    meta.nlink()
+}
+
+/// The number of links is only available on Unix
+#[cfg(not(unix))]
+fn hardlinks(_: &Metadata) -> u64 {
+    1
+}
@@ -0,0 +1,585 @@
+use crate::colors::ColorType;
+use crate::headers::{
+    file_header_is_valid, is_c2pa_manifest, parse_ihdr_header, parse_next_header, C2PAPolicy,
+    IhdrData, RawHeader, SAFE_HEADERS,
+};
+use crate::png::{PngData, PngImage};
+use crate::reduction::{reduce_bit_depth, reduce_color_type};
+use crate::{InFile, Limits, Options, PngResult};
+use std::fmt;
+
+/// Chunks that may appear at most once
+const SINGLE_CHUNKS: [[u8; 4]; 16] = [
+    *b"IHDR", *b"PLTE", *b"cHRM", *b"gAMA", *b"iCCP", *b"sBIT", *b"sRGB", *b"cICP", *b"mDCv",
+    *b"cLLi", *b"bKGD", *b"hIST", *b"tRNS", *b"pHYs", *b"tIME", *b"eXIf",
+];
+/// Chunks that must come before PLTE and IDAT
+const BEFORE_PLTE_CHUNKS: [[u8; 4]; 8] = [
+    *b"cHRM", *b"gAMA", *b"iCCP", *b"sBIT", *b"sRGB", *b"cICP", *b"mDCv", *b"cLLi",
+];
+/// Chunks that must come before IDAT
+const BEFORE_IDAT_CHUNKS: [[u8; 4]; 8] = [
+    *b"tRNS", *b"bKGD", *b"hIST", *b"pHYs", *b"sPLT", *b"oFFs", *b"pCAL", *b"sCAL",
+];
+/// Chunks that must come after PLTE, if there is one
+const AFTER_PLTE_CHUNKS: [[u8; 4]; 3] = [*b"tRNS", *b"bKGD", *b"hIST"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+/// How serious a lint finding is
+pub enum Severity {
+    /// The file can be optimized further
+    Info,
+    /// The file violates the spec in a way most decoders tolerate
+    Warning,
+    /// The file violates the spec in a way decoders may reject or misrender
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match *self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A single finding of the linter
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// A short, stable identifier for the kind of finding
+    pub code: &'static str,
+    /// The chunk the finding applies to, if any
+    pub chunk: Option<[u8; 4]>,
+    pub message: String,
+}
+
+impl Diagnostic {
+    fn new(
+        severity: Severity,
+        code: &'static str,
+        chunk: Option<[u8; 4]>,
+        message: String,
+    ) -> Self {
+        Self {
+            severity,
+            code,
+            chunk,
+            message,
+        }
+    }
+
+    /// Format the diagnostic as a JSON object
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"severity\":\"{}\",\"code\":\"{}\",\"chunk\":{},\"message\":{}}}",
+            self.severity,
+            self.code,
+            self.chunk.map_or_else(
+                || "null".to_owned(),
+                |name| json_string(&String::from_utf8_lossy(&name))
+            ),
+            json_string(&self.message)
+        )
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {} [{}]", self.severity, self.message, self.code)
+    }
+}
+
+/// Format the diagnostics for a file as a JSON object
+#[must_use]
+pub fn diagnostics_to_json(file: &str, diagnostics: &[Diagnostic]) -> String {
+    let diagnostics: Vec<_> = diagnostics.iter().map(Diagnostic::to_json).collect();
+    format!(
+        "{{\"file\":{},\"diagnostics\":[{}]}}",
+        json_string(file),
+        diagnostics.join(",")
+    )
+}
+
+/// Quote and escape a string for JSON output
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Check the input file for spec violations and optimization opportunities
+pub fn lint_file(input: &InFile, limits: &Limits) -> PngResult<Vec<Diagnostic>> {
+    Ok(lint(&input.read()?, limits))
+}
+
+/// Check a PNG file that is already loaded in-memory for spec violations and optimization opportunities
+///
+/// Problems that stop the file from being read at all are reported as diagnostics too, as are
+/// images whose data is not decoded because it exceeds the `limits`.
+#[must_use]
+pub fn lint(data: &[u8], limits: &Limits) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    if !data.get(0..8).map_or(false, file_header_is_valid) {
+        diagnostics.push(Diagnostic::new(
+            Severity::Error,
+            "not-png",
+            None,
+            "Invalid signature; not a PNG file".to_owned(),
+        ));
+        return diagnostics;
+    }
+
+    let mut chunks = Vec::new();
+    let mut offset = 8;
+    loop {
+        let start = offset;
+        match parse_next_header(data, &mut offset, true) {
+            Ok(Some(chunk)) => chunks.push(chunk),
+            Ok(None) => {
+                if offset < data.len() {
+                    diagnostics.push(Diagnostic::new(
+                        Severity::Warning,
+                        "trailing-data",
+                        None,
+                        format!("{} bytes of data after IEND", data.len() - offset),
+                    ));
+                }
+                break;
+            }
+            Err(_) => {
+                diagnostics.push(if start == data.len() {
+                    Diagnostic::new(
+                        Severity::Error,
+                        "missing-iend",
+                        None,
+                        "File ends without an IEND chunk".to_owned(),
+                    )
+                } else {
+                    Diagnostic::new(
+                        Severity::Error,
+                        "truncated",
+                        None,
+                        format!("File is truncated or corrupt at offset {}", start),
+                    )
+                });
+                break;
+            }
+        }
+    }
+
+    for chunk in chunks.iter().filter(|c| !c.crc_valid) {
+        diagnostics.push(Diagnostic::new(
+            Severity::Error,
+            "crc-mismatch",
+            Some(chunk.name),
+            format!("{} chunk has an incorrect CRC", name(&chunk.name)),
+        ));
+    }
+    check_chunk_order(&chunks, &mut diagnostics);
+    let ihdr = check_ihdr(&chunks, &mut diagnostics);
+    if let Some(ref header) = ihdr {
+        check_palette(header, &chunks, &mut diagnostics);
+    }
+    check_text_keywords(&chunks, &mut diagnostics);
+
+    if ihdr.is_some() {
+        let opts = Options {
+            c2pa: C2PAPolicy::Keep,
+            limits: *limits,
+            ..Options::default()
+        };
+        match PngData::from_slice_with_options(&data[..offset], &opts) {
+            Ok(png) => check_image_data(&png.raw, &mut diagnostics),
+            // Broken chunks stop the file from being decoded, and have been reported already
+            Err(_)
+                if diagnostics
+                    .iter()
+                    .any(|d| matches!(d.code, "crc-mismatch" | "truncated" | "missing-iend")) => {}
+            Err(e) => diagnostics.push(Diagnostic::new(
+                Severity::Error,
+                "invalid-image-data",
+                Some(*b"IDAT"),
+                e.to_string(),
+            )),
+        }
+    }
+    diagnostics
+}
+
+fn name(name: &[u8; 4]) -> std::borrow::Cow<'_, str> {
+    String::from_utf8_lossy(name)
+}
+
+fn position(chunks: &[RawHeader<'_>], name: &[u8; 4]) -> Option<usize> {
+    chunks.iter().position(|c| &c.name == name)
+}
+
+fn check_chunk_order(chunks: &[RawHeader<'_>], diagnostics: &mut Vec<Diagnostic>) {
+    let mut order_error = |severity, chunk: [u8; 4], message: String| {
+        diagnostics.push(Diagnostic::new(
+            severity,
+            "chunk-order",
+            Some(chunk),
+            message,
+        ));
+    };
+    if chunks.first().map_or(true, |c| &c.name != b"IHDR") {
+        order_error(
+            Severity::Error,
+            *b"IHDR",
+            "IHDR must be the first chunk".to_owned(),
+        );
+    }
+    let plte = position(chunks, b"PLTE");
+    let first_idat = position(chunks, b"IDAT");
+    if let (Some(plte), Some(idat)) = (plte, first_idat) {
+        if plte > idat {
+            order_error(
+                Severity::Error,
+                *b"PLTE",
+                "PLTE must come before IDAT".to_owned(),
+            );
+        }
+    }
+    if let Some(first_idat) = first_idat {
+        let idats = chunks.iter().filter(|c| &c.name == b"IDAT").count();
+        if chunks[first_idat..first_idat + idats]
+            .iter()
+            .any(|c| &c.name != b"IDAT")
+        {
+            order_error(
+                Severity::Error,
+                *b"IDAT",
+                "IDAT chunks must be consecutive".to_owned(),
+            );
+        }
+    }
+
+    for (i, chunk) in chunks.iter().enumerate() {
+        let after_plte = plte.map_or(false, |p| i > p);
+        let after_idat = first_idat.map_or(false, |f| i > f);
+        if BEFORE_PLTE_CHUNKS.contains(&chunk.name) && (after_plte || after_idat) {
+            order_error(
+                Severity::Warning,
+                chunk.name,
+                format!("{} must come before PLTE and IDAT", name(&chunk.name)),
+            );
+        } else if BEFORE_IDAT_CHUNKS.contains(&chunk.name) && after_idat {
+            order_error(
+                Severity::Warning,
+                chunk.name,
+                format!("{} must come before IDAT", name(&chunk.name)),
+            );
+        }
+        if AFTER_PLTE_CHUNKS.contains(&chunk.name) && plte.map_or(false, |p| i < p) {
+            order_error(
+                Severity::Warning,
+                chunk.name,
+                format!("{} must come after PLTE", name(&chunk.name)),
+            );
+        }
+    }
+
+    for single in &SINGLE_CHUNKS {
+        let count = chunks.iter().filter(|c| &c.name == single).count();
+        if count > 1 {
+            diagnostics.push(Diagnostic::new(
+                if single == b"IHDR" || single == b"PLTE" {
+                    Severity::Error
+                } else {
+                    Severity::Warning
+                },
+                "duplicate-chunk",
+                Some(*single),
+                format!("{} chunk appears {} times", name(single), count),
+            ));
+        }
+    }
+}
+
+/// Check the IHDR chunk, returning it if it is usable for further checks
+fn check_ihdr(chunks: &[RawHeader<'_>], diagnostics: &mut Vec<Diagnostic>) -> Option<IhdrData> {
+    let mut ihdr_error = |code, message: &str| {
+        diagnostics.push(Diagnostic::new(
+            Severity::Error,
+            code,
+            Some(*b"IHDR"),
+            message.to_owned(),
+        ));
+    };
+    let ihdr = match chunks.iter().find(|c| &c.name == b"IHDR") {
+        Some(ihdr) => ihdr.data,
+        None => {
+            ihdr_error("missing-chunk", "IHDR chunk is missing");
+            return None;
+        }
+    };
+    if ihdr.len() != 13 {
+        ihdr_error("ihdr-length", "IHDR chunk must be 13 bytes long");
+        return None;
+    }
+    let header = match parse_ihdr_header(ihdr) {
+        Ok(header) => header,
+        Err(e) => {
+            ihdr_error("ihdr-invalid", &e.to_string());
+            return None;
+        }
+    };
+    if header.width == 0 || header.height == 0 {
+        ihdr_error("ihdr-dimensions", "Image width and height must not be zero");
+    }
+    let valid_depth = match header.color_type {
+        ColorType::Grayscale => true,
+        ColorType::Indexed => header.bit_depth.as_u8() <= 8,
+        _ => header.bit_depth.as_u8() >= 8,
+    };
+    if !valid_depth {
+        ihdr_error(
+            "ihdr-bit-depth",
+            &format!(
+                "Bit depth {} is not allowed for {} images",
+                header.bit_depth, header.color_type
+            ),
+        );
+    }
+    if header.compression != 0 {
+        ihdr_error(
+            "ihdr-compression-method",
+            &format!("Unknown compression method {}", header.compression),
+        );
+    }
+    if header.filter != 0 {
+        ihdr_error(
+            "ihdr-filter-method",
+            &format!("Unknown filter method {}", header.filter),
+        );
+    }
+    if !chunks.iter().any(|c| &c.name == b"IDAT") {
+        ihdr_error("missing-chunk", "IDAT chunk is missing");
+    }
+    Some(header)
+}
+
+fn check_palette(header: &IhdrData, chunks: &[RawHeader<'_>], diagnostics: &mut Vec<Diagnostic>) {
+    let plte = chunks.iter().find(|c| &c.name == b"PLTE").map(|c| c.data);
+    let trns = chunks.iter().find(|c| &c.name == b"tRNS").map(|c| c.data);
+    let mut error = |code, chunk: &[u8; 4], message: String| {
+        diagnostics.push(Diagnostic::new(
+            Severity::Error,
+            code,
+            Some(*chunk),
+            message,
+        ));
+    };
+
+    match (header.color_type, plte) {
+        (ColorType::Indexed, None) => error(
+            "missing-chunk",
+            b"PLTE",
+            "PLTE chunk is missing from an indexed image".to_owned(),
+        ),
+        (ColorType::Grayscale | ColorType::GrayscaleAlpha, Some(_)) => error(
+            "unexpected-chunk",
+            b"PLTE",
+            format!("PLTE chunk is not allowed in {} images", header.color_type),
+        ),
+        (_, Some(plte)) if plte.is_empty() || plte.len() % 3 != 0 || plte.len() > 768 => error(
+            "plte-length",
+            b"PLTE",
+            format!("PLTE chunk has an invalid length of {} bytes", plte.len()),
+        ),
+        (ColorType::Indexed, Some(plte)) if plte.len() / 3 > 1 << header.bit_depth.as_u8() => {
+            error(
+                "plte-too-long",
+                b"PLTE",
+                format!(
+                    "PLTE chunk has {} entries, more than a {}-bit image can use",
+                    plte.len() / 3,
+                    header.bit_depth
+                ),
+            );
+        }
+        _ => (),
+    }
+
+    if let Some(trns) = trns {
+        match header.color_type {
+            ColorType::GrayscaleAlpha | ColorType::RGBA => error(
+                "unexpected-chunk",
+                b"tRNS",
+                format!("tRNS chunk is not allowed in {} images", header.color_type),
+            ),
+            ColorType::Indexed => {
+                let entries = plte.map_or(0, |p| p.len() / 3);
+                if trns.len() > entries {
+                    error(
+                        "trns-length",
+                        b"tRNS",
+                        format!(
+                            "tRNS chunk has {} entries, more than the {} in the palette",
+                            trns.len(),
+                            entries
+                        ),
+                    );
+                }
+            }
+            ColorType::Grayscale | ColorType::RGB => {
+                let expected = if header.color_type == ColorType::RGB {
+                    6
+                } else {
+                    2
+                };
+                if trns.len() != expected {
+                    error(
+                        "trns-length",
+                        b"tRNS",
+                        format!(
+                            "tRNS chunk must be {} bytes long for {} images",
+                            expected, header.color_type
+                        ),
+                    );
+                }
+            }
+        }
+    }
+}
+
+fn check_text_keywords(chunks: &[RawHeader<'_>], diagnostics: &mut Vec<Diagnostic>) {
+    for chunk in chunks {
+        if !matches!(&chunk.name, b"tEXt" | b"zTXt" | b"iTXt") {
+            continue;
+        }
+        let problem = match chunk.data.iter().position(|&b| b == 0) {
+            None => Some("has no null separator after the keyword".to_owned()),
+            Some(len) => keyword_problem(&chunk.data[..len]),
+        };
+        if let Some(problem) = problem {
+            diagnostics.push(Diagnostic::new(
+                Severity::Warning,
+                "text-keyword",
+                Some(chunk.name),
+                format!("{} chunk {}", name(&chunk.name), problem),
+            ));
+        }
+    }
+}
+
+/// Describe what is wrong with a text chunk keyword, if anything
+fn keyword_problem(keyword: &[u8]) -> Option<String> {
+    let shown = String::from_utf8_lossy(keyword);
+    if keyword.is_empty() || keyword.len() > 79 {
+        Some(format!(
+            "keyword must be 1 to 79 bytes long, not {}",
+            keyword.len()
+        ))
+    } else if keyword.iter().any(|&b| !matches!(b, 32..=126 | 161..=255)) {
+        Some(format!("keyword {:?} contains invalid characters", shown))
+    } else if keyword.starts_with(b" ") || keyword.ends_with(b" ") {
+        Some(format!(
+            "keyword {:?} has leading or trailing spaces",
+            shown
+        ))
+    } else if keyword.windows(2).any(|w| w == b"  ") {
+        Some(format!("keyword {:?} has consecutive spaces", shown))
+    } else {
+        None
+    }
+}
+
+/// Check the decoded image for invalid palette indices and optimization opportunities
+fn check_image_data(png: &PngImage, diagnostics: &mut Vec<Diagnostic>) {
+    let bit_depth = reduce_bit_depth(png, 1);
+    let reduced = bit_depth.as_ref().unwrap_or(png);
+    let color_type = reduce_color_type(reduced, true, false);
+    let reduced = color_type.as_ref().unwrap_or(reduced);
+    if reduced.ihdr.color_type != png.ihdr.color_type
+        || reduced.ihdr.bit_depth != png.ihdr.bit_depth
+    {
+        diagnostics.push(Diagnostic::new(
+            Severity::Info,
+            "reducible-color-type",
+            None,
+            format!(
+                "Image can be stored as {}-bit {} instead of {}-bit {}",
+                reduced.ihdr.bit_depth,
+                reduced.ihdr.color_type,
+                png.ihdr.bit_depth,
+                png.ihdr.color_type
+            ),
+        ));
+    }
+
+    if let Some(palette) = &png.palette {
+        let bits = png.ihdr.bit_depth.as_u8() as usize;
+        let mask = ((1_u16 << bits) - 1) as u8;
+        let mut used = [false; 256];
+        // Only the pixels of each line, the padding bits at the end are not indices
+        for line in png.scan_lines(false) {
+            for pixel in 0..line.num_pixels {
+                let byte = line.data[pixel * bits / 8];
+                // The first pixel is in the most significant bits
+                let shift = 8 - bits - pixel * bits % 8;
+                used[((byte >> shift) & mask) as usize] = true;
+            }
+        }
+        let unused = palette
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| !used[i])
+            .count();
+        let out_of_range = used[palette.len().min(256)..]
+            .iter()
+            .filter(|&&u| u)
+            .count();
+        if out_of_range > 0 {
+            diagnostics.push(Diagnostic::new(
+                Severity::Error,
+                "palette-index",
+                Some(*b"IDAT"),
+                format!(
+                    "Image uses {} palette indices outside the {}-entry palette",
+                    out_of_range,
+                    palette.len()
+                ),
+            ));
+        }
+        if unused > 0 {
+            diagnostics.push(Diagnostic::new(
+                Severity::Info,
+                "unused-palette-entries",
+                Some(*b"PLTE"),
+                format!("{} of {} palette entries are unused", unused, palette.len()),
+            ));
+        }
+    }
+
+    for (chunk, data) in png.aux_headers.iter() {
+        if !SAFE_HEADERS.contains(chunk) && !is_c2pa_manifest(chunk, data) {
+            diagnostics.push(Diagnostic::new(
+                Severity::Info,
+                "strippable-chunk",
+                Some(*chunk),
+                format!(
+                    "{} chunk ({} bytes) can be stripped safely",
+                    name(chunk),
+                    data.len()
+                ),
+            ));
+        }
+    }
+}
@@ -13,20 +13,31 @@
 #![warn(clippy::range_plus_one)]
 #![allow(clippy::cognitive_complexity)]
 
+mod cli;
+
+use crate::cli::cache::Cache;
+use crate::cli::config::{Chunks, Config, Preset, Settings};
+use crate::cli::files::{
+    parse_modified_since, parse_size, read_file_list, remove_duplicate_outputs, FileFilter,
+};
+use crate::cli::logger;
+use crate::cli::outputs::{Outputs, Template};
+use crate::cli::summary::{Summary, EXIT_ALL_FAILED, EXIT_INTERRUPTED, EXIT_USAGE};
+use crate::cli::watch;
 use clap::{AppSettings, Arg, ArgMatches, Command};
 use indexmap::IndexSet;
-use log::{error, warn};
-use oxipng::Deflaters;
-use oxipng::Headers;
-use oxipng::Options;
+use log::{error, info, warn};
+use oxipng::PngError;
 use oxipng::RowFilter;
-use oxipng::{InFile, OutFile};
-use std::fs::DirBuilder;
-#[cfg(feature = "zopfli")]
-use std::num::NonZeroU8;
+use oxipng::Severity;
+use oxipng::{InFile, OutFile, Outcome};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+use std::cmp::Reverse;
+use std::fs::{self, DirBuilder};
 use std::path::PathBuf;
 use std::process::exit;
-use std::time::Duration;
+use std::sync::Arc;
 
 fn main() {
     let matches = Command::new("oxipng")
@@ -40,7 +51,44 @@ fn main() {
                 .index(1)
                 .multiple_values(true)
                 .use_value_delimiter(false)
-                .required(true),
+                .required_unless_present("files-from"),
+        )
+        .arg(
+            Arg::new("files-from")
+                .help("Also compress the files listed in <file>, one per line (use \"-\" for stdin)")
+                .long("files-from")
+                .takes_value(true)
+                .value_name("file")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("null")
+                .help("Paths in the --files-from list are separated by NUL characters, as from find -print0")
+                .short('0')
+                .long("null")
+                .requires("files-from"),
+        )
+        .arg(
+            Arg::new("config")
+                .help("Read settings from <file>, instead of the oxipng.toml found in the current directory or its parents")
+                .long("config")
+                .takes_value(true)
+                .value_name("file")
+                .value_parser(clap::value_parser!(PathBuf))
+                .conflicts_with("no-config"),
+        )
+        .arg(
+            Arg::new("profile")
+                .help("Use the settings of the profile <name> from the configuration file")
+                .long("profile")
+                .takes_value(true)
+                .value_name("name")
+                .conflicts_with("no-config"),
+        )
+        .arg(
+            Arg::new("no-config")
+                .help("Do not read settings from an oxipng.toml file")
+                .long("no-config"),
         )
         .arg(
             Arg::new("optimization")
@@ -60,19 +108,117 @@ fn main() {
         )
         .arg(
             Arg::new("backup")
-                .help("Back up modified files")
+                .help("Back up modified files (<file>.bak, older versions wrote <file>.bak.png)")
                 .short('b')
                 .long("backup"),
         )
+        .arg(
+            Arg::new("backup-dir")
+                .help("Keep backups in <directory>, under their paths relative to the current directory (implies -b)")
+                .long("backup-dir")
+                .takes_value(true)
+                .value_name("directory")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("backup-suffix")
+                .help("Name backups by appending <suffix> to the file name - Default: .bak (implies -b)")
+                .long("backup-suffix")
+                .takes_value(true)
+                .value_name("suffix")
+                .allow_hyphen_values(true),
+        )
+        .arg(
+            Arg::new("backup-keep")
+                .help("Keep <num> backups of each file, older ones numbered <file>.bak.1 and so on - Default: 1 (implies -b)")
+                .long("backup-keep")
+                .takes_value(true)
+                .value_name("num")
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("restore")
+                .help("Put the newest backup of each file back in its place, without optimizing")
+                .long("restore")
+                .conflicts_with_all(&["output_dir", "output_file", "stdout", "restore-meta", "backup"]),
+        )
         .arg(
             Arg::new("recursive")
                 .help("Recurse into subdirectories")
                 .short('r')
                 .long("recursive"),
         )
+        .arg(
+            Arg::new("ext")
+                .help("Only use files with these comma-separated extensions when recursing, or '*' for any - Default: png,apng")
+                .long("ext")
+                .takes_value(true)
+                .value_name("list")
+                .requires("recursive"),
+        )
+        .arg(
+            Arg::new("include")
+                .help("Only use files matching <glob> when recursing, may be repeated")
+                .long("include")
+                .takes_value(true)
+                .value_name("glob")
+                .multiple_occurrences(true)
+                .requires("recursive"),
+        )
+        .arg(
+            Arg::new("exclude")
+                .help("Skip files and directories matching <glob> when recursing, may be repeated")
+                .long("exclude")
+                .takes_value(true)
+                .value_name("glob")
+                .multiple_occurrences(true)
+                .requires("recursive"),
+        )
+        .arg(
+            Arg::new("ignore-files")
+                .help("Skip files listed in .gitignore and .ignore files when recursing")
+                .long("ignore-files")
+                .requires("recursive"),
+        )
+        .arg(
+            Arg::new("follow-symlinks")
+                .help("Recurse into symlinked directories and use symlinked files")
+                .long("follow-symlinks")
+                .requires("recursive"),
+        )
+        .arg(
+            Arg::new("max-depth")
+                .help("Recurse at most <num> directories deep, 1 only uses the files directly in the directories given")
+                .long("max-depth")
+                .takes_value(true)
+                .value_name("num")
+                .value_parser(clap::value_parser!(usize))
+                .requires("recursive"),
+        )
+        .arg(
+            Arg::new("min-size")
+                .help("Skip files smaller than <bytes>, e.g. 500, 64k or 2M")
+                .long("min-size")
+                .takes_value(true)
+                .value_name("bytes"),
+        )
+        .arg(
+            Arg::new("max-size")
+                .help("Skip files larger than <bytes>, e.g. 500, 64k or 2M")
+                .long("max-size")
+                .takes_value(true)
+                .value_name("bytes"),
+        )
+        .arg(
+            Arg::new("modified-since")
+                .help("Skip files not modified within <time> (e.g. 30min, 12h or 7d), or since <file> was")
+                .long("modified-since")
+                .takes_value(true)
+                .value_name("time|file"),
+        )
         .arg(
             Arg::new("output_dir")
-                .help("Write output file(s) to <directory>")
+                .help("Write output file(s) to <directory>, keeping the structure of directories given with -r")
                 .long("dir")
                 .takes_value(true)
                 .value_name("directory")
@@ -88,6 +234,15 @@ fn main() {
                 .conflicts_with("output_dir")
                 .conflicts_with("stdout"),
         )
+        .arg(
+            Arg::new("name-template")
+                .help("Name output files with <template>, using {dir}, {name}, {stem} and {ext} of the input. Relative to the output directory unless it contains {dir}, e.g. {stem}.min.{ext} or {dir}/opt/{name}")
+                .long("name-template")
+                .takes_value(true)
+                .value_name("template")
+                .conflicts_with("output_file")
+                .conflicts_with("stdout"),
+        )
         .arg(
             Arg::new("stdout")
                 .help("Write output to stdout")
@@ -97,16 +252,53 @@ fn main() {
         )
         .arg(
             Arg::new("preserve")
-                .help("Preserve file attributes if possible")
+                .help("Preserve file attributes if possible: permissions, times, owner and group, and on Linux the SELinux label, ACL and user xattrs")
                 .short('p')
                 .long("preserve"),
         )
+        .arg(
+            Arg::new("symlinks")
+                .help("When the file to replace is a symlink: write through to the file it points to, replace the link, or skip the file - Default: write-through")
+                .long("symlinks")
+                .takes_value(true)
+                .value_name("policy")
+                .possible_value("write-through")
+                .possible_value("replace")
+                .possible_value("skip"),
+        )
+        .arg(
+            Arg::new("hardlinks")
+                .help("When the file to replace has several hardlinks: write into the file shared by all of them, replace only this link, or skip the file - Default: write-through")
+                .long("hardlinks")
+                .takes_value(true)
+                .value_name("policy")
+                .possible_value("write-through")
+                .possible_value("replace")
+                .possible_value("skip"),
+        )
         .arg(
             Arg::new("check")
                 .help("Do not run any optimization passes")
                 .short('c')
                 .long("check"),
         )
+        .arg(
+            Arg::new("lint")
+                .help("Report spec violations and optimization opportunities, without optimizing")
+                .long("lint"),
+        )
+        .arg(
+            Arg::new("inspect")
+                .help("Print the chunks, image header, row filters and DEFLATE blocks of the files, without optimizing")
+                .long("inspect")
+                .conflicts_with("lint"),
+        )
+        .arg(
+            Arg::new("json")
+                .help("Print the lint report as JSON")
+                .long("json")
+                .requires("lint"),
+        )
         .arg(
             Arg::new("pretend")
                 .help("Do not write any files, only calculate compression gains")
@@ -136,6 +328,42 @@ fn main() {
                 .conflicts_with("strip")
                 .conflicts_with("strip-safe"),
         )
+        .arg(
+            Arg::new("convert-profiles")
+                .help("Convert ImageMagick raw profiles to native EXIF, XMP and ICC chunks")
+                .long("convert-profiles"),
+        )
+        .arg(
+            Arg::new("sidecar")
+                .help("Save stripped metadata to a sidecar file (<output>.meta)")
+                .long("sidecar"),
+        )
+        .arg(
+            Arg::new("restore-meta")
+                .help("Put metadata from sidecar files back into the files, without optimizing")
+                .long("restore-meta")
+                .conflicts_with("sidecar"),
+        )
+        .arg(
+            Arg::new("c2pa")
+                .help("What to do with files carrying C2PA content credentials - Default: skip")
+                .long("c2pa")
+                .takes_value(true)
+                .value_name("policy")
+                .possible_value("skip")
+                .possible_value("strip")
+                .possible_value("keep"),
+        )
+        .arg(
+            Arg::new("trailing-data")
+                .help("What to do with data after the end of the image - Default: strip")
+                .long("trailing-data")
+                .takes_value(true)
+                .value_name("mode")
+                .possible_value("keep")
+                .possible_value("strip")
+                .possible_value("error"),
+        )
         .arg(
             Arg::new("alpha")
                 .help("Perform additional alpha optimizations")
@@ -221,18 +449,81 @@ fn main() {
                 .help("No IDAT recoding unless necessary")
                 .long("nz"),
         )
-        .arg(Arg::new("fix").help("Enable error recovery").long("fix"))
+        .arg(
+            Arg::new("fix")
+                .help("Enable error recovery, salvaging as much of a damaged file as possible")
+                .long("fix"),
+        )
         .arg(
             Arg::new("force")
                 .help("Write the output even if it is larger than the input")
                 .long("force"),
         )
+        .arg(
+            Arg::new("min-savings")
+                .help("Leave the file untouched unless it is made at least this much smaller")
+                .long_help("\
+Leave the file untouched unless it is made at least this much smaller, in bytes (with an \
+optional k or M suffix) or as a percentage of the file size, such as 1k or 2%. Files are \
+still written when --strip removed chunks or -i changed the interlacing.")
+                .long("min-savings")
+                .takes_value(true)
+                .value_name("bytes|percent")
+                .conflicts_with("force"),
+        )
         .arg(
             Arg::new("zopfli")
                 .help("Use the slower but better compressing Zopfli algorithm")
                 .short('Z')
                 .long("zopfli"),
         )
+        .arg(
+            Arg::new("verify")
+                .help("Check that the output has the same pixels as the input ['visible' or 'strict']")
+                .long("verify")
+                .takes_value(true)
+                .value_name("mode")
+                .possible_value("visible")
+                .possible_value("strict"),
+        )
+        .arg(
+            Arg::new("verify-fallback")
+                .help("Keep the original image instead of failing when verification fails")
+                .long("verify-fallback")
+                .requires("verify"),
+        )
+        .arg(
+            Arg::new("max-pixels")
+                .help("Reject images with more than <num> pixels")
+                .long("max-pixels")
+                .takes_value(true)
+                .value_name("num")
+                .value_parser(clap::value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("max-decoded-size")
+                .help("Reject images whose decoded data is larger than <bytes>")
+                .long("max-decoded-size")
+                .takes_value(true)
+                .value_name("bytes")
+                .value_parser(clap::value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("max-chunk-size")
+                .help("Reject images containing a chunk larger than <bytes>")
+                .long("max-chunk-size")
+                .takes_value(true)
+                .value_name("bytes")
+                .value_parser(clap::value_parser!(u32)),
+        )
+        .arg(
+            Arg::new("max-memory")
+                .help("Reject images that would need more than about <bytes> of memory")
+                .long("max-memory")
+                .takes_value(true)
+                .value_name("bytes")
+                .value_parser(clap::value_parser!(u64)),
+        )
         .arg(
             Arg::new("timeout")
                 .help("Maximum amount of time, in seconds, to spend on optimizations")
@@ -240,9 +531,36 @@ fn main() {
                 .value_name("secs")
                 .long("timeout"),
         )
+        .arg(
+            Arg::new("cache")
+                .help("Record optimized files in <file>, and skip files already optimized with the same or stronger settings")
+                .long("cache")
+                .takes_value(true)
+                .value_name("file")
+                .value_parser(clap::value_parser!(PathBuf))
+                .conflicts_with_all(&["restore-meta", "stdout"]),
+        )
+        .arg(
+            Arg::new("clear-cache")
+                .help("Discard the entries in the cache file before starting")
+                .long("clear-cache")
+                .requires("cache"),
+        )
+        .arg(
+            Arg::new("fail-fast")
+                .help("Stop starting new files after the first one fails")
+                .long("fail-fast")
+                .conflicts_with("watch"),
+        )
+        .arg(
+            Arg::new("watch")
+                .help("Keep running, and optimize files as they are added to or changed in the given paths (use -r for directories)")
+                .long("watch")
+                .conflicts_with_all(&["stdout", "lint", "inspect", "restore-meta", "restore"]),
+        )
         .arg(
             Arg::new("threads")
-                .help("Set number of threads to use - default 1.5x CPU cores")
+                .help("Set number of threads to use, shared by all files - default 1.5x CPU cores")
                 .long("threads")
                 .short('t')
                 .takes_value(true)
@@ -283,131 +601,351 @@ Heuristic filter selection strategies:
     6  =>  Entropy   Highest Shannon entropy
     7  =>  Bigrams   Lowest count of distinct bigrams
     8  =>  BigEnt    Highest Shannon entropy of bigrams
-    9  =>  Brute     Smallest compressed size (slow)",
+    9  =>  Brute     Smallest compressed size (slow)
+
+Exit codes:
+    0    =>  All files were optimized, or were already optimized
+    1    =>  All files failed, or there were no files to process
+    2    =>  Invalid flags, options or configuration
+    3    =>  Some files failed
+    4    =>  No files failed, but some timed out and kept the best result found in time
+    130  =>  Interrupted",
         )
         .get_matches_from(wild::args());
 
-    let (out_file, out_dir, opts) = match parse_opts_into_struct(&matches) {
+    let (outputs, filter, settings) = match parse_opts_into_struct(&matches) {
         Ok(x) => x,
         Err(x) => {
             error!("{}", x);
-            exit(1)
+            exit(EXIT_USAGE)
         }
     };
 
-    let files = collect_files(
-        matches
-            .values_of("files")
-            .unwrap()
-            .map(PathBuf::from)
-            .collect(),
-        &out_dir,
-        &out_file,
-        matches.is_present("recursive"),
-        true,
-    );
-
-    let mut success = false;
-    for (input, output) in files {
-        match oxipng::optimize(&input, &output, &opts) {
-            // For optimizing single files, this will return the correct exit code always.
-            // For recursive optimization, the correct choice is a bit subjective.
-            // We're choosing to return a 0 exit code if ANY file in the set
-            // runs correctly.
-            // The reason for this is that recursion may pick up files that are not
-            // PNG files, and return an error for them.
-            // We don't really want to return an error code for those files.
-            Ok(_) => {
-                success = true;
+    let config = if matches.is_present("no-config") {
+        Config::without_file(settings)
+    } else {
+        Config::load(
+            matches.get_one::<PathBuf>("config").map(PathBuf::as_path),
+            matches.value_of("profile"),
+            settings,
+        )
+    };
+    let config = match config {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{}", e);
+            exit(EXIT_USAGE)
+        }
+    };
+
+    // Stop cleanly on the first Ctrl-C, leaving no partial output, and at once on the second
+    if let Err(e) = ctrlc::set_handler(|| {
+        if oxipng::is_interrupted() {
+            oxipng::remove_temp_files();
+            exit(EXIT_INTERRUPTED);
+        }
+        warn!("Interrupted, stopping after cleaning up");
+        oxipng::interrupt();
+    }) {
+        warn!("Unable to handle Ctrl-C: {}", e);
+    }
+
+    let mut paths: Vec<PathBuf> = matches
+        .values_of("files")
+        .map_or_else(Vec::new, |files| files.map(PathBuf::from).collect());
+    if let Some(list) = matches.get_one::<PathBuf>("files-from") {
+        if list.to_str() == Some("-") && paths.iter().any(|p| p.to_str() == Some("-")) {
+            error!("stdin can't be used for both the file list and an image");
+            exit(EXIT_USAGE);
+        }
+        match read_file_list(list, matches.is_present("null")) {
+            Ok(list) => paths.extend(list),
+            Err(e) => {
+                error!("{}", e);
+                exit(EXIT_ALL_FAILED);
             }
+        }
+    }
+    let files = filter.collect(paths.clone(), &outputs);
+
+    if matches.is_present("lint") {
+        exit(lint_files(&files, matches.is_present("json"), &config));
+    }
+
+    if matches.is_present("inspect") {
+        exit(inspect_files(&files, &config));
+    }
+
+    let mode = if matches.is_present("restore-meta") {
+        Mode::RestoreMetadata
+    } else if matches.is_present("restore") {
+        Mode::RestoreBackup
+    } else {
+        Mode::Optimize
+    };
+    let cache = match matches.get_one::<PathBuf>("cache") {
+        Some(path) => match Cache::load(path.clone(), matches.is_present("clear-cache")) {
+            Ok(cache) => Some(cache),
             Err(e) => {
                 error!("{}", e);
+                exit(EXIT_ALL_FAILED)
             }
-        };
+        },
+        None => None,
+    };
+    let fail_fast = matches.is_present("fail-fast");
+    let summary = Summary::default();
+    if matches.is_present("watch") {
+        for backup in config.backups() {
+            if let Err(e) = watch::check_backup(&paths, &filter, &backup) {
+                error!("{}", e);
+                exit(EXIT_USAGE);
+            }
+        }
+        let result = watch::watch(&paths, &filter, &outputs, |files| {
+            let batch = Summary::default();
+            process_files(files, &config, cache.as_ref(), mode, false, &batch);
+            batch.print(files.len());
+            // Keep the cache current, a long running watch is likely to be killed
+            if let Some(ref cache) = cache {
+                if let Err(e) = cache.save() {
+                    error!("{}", e);
+                }
+            }
+        });
+        if let Err(e) = result {
+            error!("{}", e);
+            exit(EXIT_ALL_FAILED);
+        }
+    } else {
+        let mut files = files;
+        if files.is_empty() {
+            error!("No files to process");
+            exit(EXIT_ALL_FAILED);
+        }
+        process_files(
+            &mut files,
+            &config,
+            cache.as_ref(),
+            mode,
+            fail_fast,
+            &summary,
+        );
+        summary.print(files.len());
     }
 
-    if !success {
-        exit(1);
+    if let Some(cache) = cache {
+        if let Err(e) = cache.save() {
+            error!("{}", e);
+        }
     }
+    if oxipng::is_interrupted() {
+        exit(EXIT_INTERRUPTED);
+    }
+    exit(summary.exit_code());
+}
+
+/// What to do with each file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Optimize,
+    /// Put the metadata from the sidecar files back
+    RestoreMetadata,
+    /// Put the newest backups back
+    RestoreBackup,
 }
 
-fn collect_files(
-    files: Vec<PathBuf>,
-    out_dir: &Option<PathBuf>,
-    out_file: &OutFile,
-    recursive: bool,
-    allow_stdin: bool,
-) -> Vec<(InFile, OutFile)> {
-    let mut in_out_pairs = Vec::new();
-    let allow_stdin = allow_stdin && files.len() == 1;
-    for input in files {
-        let using_stdin = allow_stdin && input.to_str().map_or(false, |p| p == "-");
-        if !using_stdin && input.is_dir() {
-            if recursive {
-                match input.read_dir() {
-                    Ok(dir) => {
-                        let files = dir.filter_map(|x| x.ok().map(|x| x.path())).collect();
-                        in_out_pairs
-                            .extend(collect_files(files, out_dir, out_file, recursive, false));
+/// Optimize the files, dropping those with clashing outputs, and record the results in `summary`
+///
+/// With `fail_fast`, no more files are started once one has failed.
+fn process_files(
+    files: &mut Vec<(InFile, OutFile)>,
+    config: &Config,
+    cache: Option<&Cache>,
+    mode: Mode,
+    fail_fast: bool,
+    summary: &Summary,
+) {
+    remove_duplicate_outputs(files);
+    // Start with the largest files, so the small ones fill in the gaps at the end
+    files.sort_by_cached_key(|(input, _)| {
+        Reverse(
+            input
+                .path()
+                .and_then(|p| p.metadata().ok())
+                .map_or(0, |m| m.len()),
+        )
+    });
+    // Bridge the sorted iterator, so the files are started in order
+    #[cfg(feature = "parallel")]
+    let files_iter = files.iter().par_bridge();
+    #[cfg(not(feature = "parallel"))]
+    let files_iter = files.iter();
+    files_iter.for_each(|(input, output)| {
+        if oxipng::is_interrupted() || (fail_fast && summary.has_failures()) {
+            return;
+        }
+        logger::grouped(&input.to_string(), || {
+            let opts = match config.options_for(input) {
+                Ok(opts) => opts,
+                Err(e) => {
+                    error!("{}: {}", input, e);
+                    summary.failed(input, PngError::InvalidConfig(e.into()));
+                    return;
+                }
+            };
+            let paths = match (input, output) {
+                (InFile::Path(input), OutFile::Path(output)) => {
+                    Some((input, output.as_ref().unwrap_or(input)))
+                }
+                _ => None,
+            };
+            let cache = cache.filter(|_| mode == Mode::Optimize);
+            if let (Some(cache), Some((input_path, output_path))) = (cache, paths) {
+                if cache.is_optimized(input_path, output_path, &opts) {
+                    info!("{}: already optimized (cached), skipping", input);
+                    summary.succeeded();
+                    return;
+                }
+            }
+            if let OutFile::Path(Some(path)) = output {
+                let parent = path.parent().filter(|p| !p.as_os_str().is_empty());
+                if let Some(parent) = parent.filter(|_| !opts.pretend && !opts.check) {
+                    if let Err(e) = fs::create_dir_all(parent) {
+                        error!(
+                            "Could not create output directory {}: {}",
+                            parent.display(),
+                            e
+                        );
+                        let error = PngError::Io {
+                            path: Some(parent.to_owned()),
+                            source: Arc::new(e),
+                        };
+                        summary.failed(input, error);
+                        return;
                     }
-                    Err(_) => {
-                        return Vec::new();
+                }
+            }
+            let result = match mode {
+                Mode::Optimize => oxipng::optimize_with_outcome(input, output, &opts),
+                Mode::RestoreMetadata => oxipng::restore_metadata(input, output, &opts.limits)
+                    .map(|_| Outcome::Optimized),
+                Mode::RestoreBackup => {
+                    oxipng::restore_backup(input, &opts.backup_config).map(|_| Outcome::Optimized)
+                }
+            };
+            match result {
+                Ok(Outcome::Skipped) => summary.skipped(),
+                // The library keeps the best result found when it runs out of time, which is not
+                // cached as a longer run may do better
+                Ok(Outcome::TimedOut) => summary.timed_out(input),
+                Ok(_) => {
+                    summary.succeeded();
+                    if let (Some(cache), Some((_, output_path))) = (cache, paths) {
+                        if !opts.pretend && !opts.check {
+                            cache.record(output_path, &opts);
+                        }
                     }
                 }
-            } else {
-                warn!("{} is a directory, skipping", input.display());
+                Err(PngError::Interrupted) => (),
+                Err(e) => {
+                    error!("{}", e);
+                    summary.failed(input, e);
+                }
+            };
+        });
+    });
+}
+
+/// Print lint results for each file, returning the exit code
+fn lint_files(files: &[(InFile, OutFile)], json: bool, config: &Config) -> i32 {
+    let mut code = 0;
+    let mut reports = Vec::new();
+    for (input, _) in files {
+        let result = config
+            .options_for(input)
+            .map_err(|e| PngError::InvalidConfig(e.into()))
+            .and_then(|opts| oxipng::lint_file(input, &opts.limits));
+        let diagnostics = match result {
+            Ok(diagnostics) => diagnostics,
+            Err(e) => {
+                error!("{}", e);
+                code = 1;
+                continue;
             }
-            continue;
-        };
-        let out_file = if let Some(ref out_dir) = *out_dir {
-            let out_path = Some(out_dir.join(input.file_name().unwrap()));
-            OutFile::Path(out_path)
-        } else {
-            (*out_file).clone()
         };
-        let in_file = if using_stdin {
-            InFile::StdIn
+        if diagnostics.iter().any(|d| d.severity == Severity::Error) {
+            code = 1;
+        }
+        if json {
+            reports.push(oxipng::diagnostics_to_json(
+                &input.to_string(),
+                &diagnostics,
+            ));
         } else {
-            InFile::Path(input)
-        };
-        in_out_pairs.push((in_file, out_file));
+            for diagnostic in diagnostics {
+                println!("{}: {}", input, diagnostic);
+            }
+        }
     }
-    in_out_pairs
+    if json {
+        println!("[{}]", reports.join(","));
+    }
+    code
 }
 
-fn parse_opts_into_struct(
-    matches: &ArgMatches,
-) -> Result<(OutFile, Option<PathBuf>, Options), String> {
-    stderrlog::new()
-        .module(module_path!())
-        .quiet(matches.is_present("quiet"))
-        .verbosity(if matches.is_present("verbose") { 3 } else { 2 })
-        .show_level(false)
-        .init()
-        .unwrap();
-
-    let mut opts = match matches.value_of("optimization") {
-        None => Options::default(),
-        Some("max") => Options::max_compression(),
-        Some(level) => Options::from_preset(level.parse::<u8>().unwrap()),
+/// Print the structure of each file, returning the exit code
+fn inspect_files(files: &[(InFile, OutFile)], config: &Config) -> i32 {
+    let mut code = 0;
+    for (input, _) in files {
+        let result = config
+            .options_for(input)
+            .map_err(|e| PngError::InvalidConfig(e.into()))
+            .and_then(|opts| oxipng::inspect_file(input, &opts.limits));
+        match result {
+            Ok(inspection) => {
+                if !inspection.errors.is_empty() {
+                    code = 1;
+                }
+                println!("{}:", input);
+                print!("{}", inspection);
+            }
+            Err(e) => {
+                error!("{}", e);
+                code = 1;
+            }
+        }
+    }
+    code
+}
+
+fn parse_opts_into_struct(matches: &ArgMatches) -> Result<(Outputs, FileFilter, Settings), String> {
+    logger::init(matches.is_present("quiet"), matches.is_present("verbose")).unwrap();
+
+    let mut settings = Settings {
+        opt: matches
+            .value_of("optimization")
+            .map(|level| match level.parse() {
+                Ok(level) => Preset::Level(level),
+                Err(_) => Preset::Name(level.to_owned()),
+            }),
+        ..Settings::default()
     };
 
     if let Some(x) = matches.value_of("interlace") {
-        opts.interlace = x.parse::<u8>().unwrap().try_into().ok();
+        settings.interlace = Some(x.parse::<u8>().unwrap());
     }
 
     if let Some(x) = matches.value_of("filters") {
-        opts.filter.clear();
-        for f in parse_numeric_range_opts(x, 0, RowFilter::LAST).unwrap() {
-            opts.filter.insert(f.try_into().unwrap());
-        }
+        let filters = parse_numeric_range_opts(x, 0, RowFilter::LAST).unwrap();
+        settings.filters = Some(filters.into_iter().collect());
     }
 
     if let Some(x) = matches.value_of("timeout") {
         let num = x
             .parse()
             .map_err(|_| "Timeout must be a number".to_owned())?;
-        opts.timeout = Some(Duration::from_secs(num));
+        settings.timeout = Some(num);
     }
 
     let out_dir = if let Some(x) = matches.value_of("output_dir") {
@@ -436,112 +974,69 @@ fn parse_opts_into_struct(
         OutFile::Path(None)
     };
 
-    if matches.is_present("alpha") {
-        opts.optimize_alpha = true;
-    }
-
-    if matches.is_present("fast") {
-        opts.fast_evaluation = true;
-    }
-
-    if matches.is_present("backup") {
-        opts.backup = true;
-    }
-
-    if matches.is_present("force") {
-        opts.force = true;
-    }
-
-    if matches.is_present("fix") {
-        opts.fix_errors = true;
-    }
-
-    if matches.is_present("check") {
-        opts.check = true;
-    }
-
-    if matches.is_present("pretend") {
-        opts.pretend = true;
-    }
-
-    if matches.is_present("preserve") {
-        opts.preserve_attrs = true;
-    }
-
-    if matches.is_present("no-bit-reduction") {
-        opts.bit_depth_reduction = false;
-    }
-
-    if matches.is_present("no-color-reduction") {
-        opts.color_type_reduction = false;
-    }
-
-    if matches.is_present("no-palette-reduction") {
-        opts.palette_reduction = false;
-    }
-
-    if matches.is_present("no-grayscale-reduction") {
-        opts.grayscale_reduction = false;
-    }
-
-    if matches.is_present("no-reductions") {
-        opts.bit_depth_reduction = false;
-        opts.color_type_reduction = false;
-        opts.palette_reduction = false;
-        opts.grayscale_reduction = false;
-    }
-
-    if matches.is_present("no-recoding") {
-        opts.idat_recoding = false;
-    }
-
-    if let Some(hdrs) = matches.value_of("keep") {
-        opts.strip = Headers::Keep(hdrs.split(',').map(|x| x.trim().to_owned()).collect())
+    let flags = [
+        ("alpha", &mut settings.alpha),
+        ("fast", &mut settings.fast),
+        ("backup", &mut settings.backup),
+        ("force", &mut settings.force),
+        ("fix", &mut settings.fix),
+        ("check", &mut settings.check),
+        ("pretend", &mut settings.pretend),
+        ("preserve", &mut settings.preserve),
+        ("convert-profiles", &mut settings.convert_profiles),
+        ("sidecar", &mut settings.sidecar),
+        ("verify-fallback", &mut settings.verify_fallback),
+        ("zopfli", &mut settings.zopfli),
+    ];
+    for (flag, setting) in flags {
+        if matches.is_present(flag) {
+            *setting = Some(true);
+        }
     }
 
-    if let Some(hdrs) = matches.value_of("strip") {
-        let hdrs = hdrs
-            .split(',')
-            .map(|x| x.trim().to_owned())
-            .collect::<Vec<String>>();
-        if hdrs.contains(&"safe".to_owned()) || hdrs.contains(&"all".to_owned()) {
-            if hdrs.len() > 1 {
-                return Err(
-                    "'safe' or 'all' presets for --strip should be used by themselves".to_owned(),
-                );
-            }
-            if hdrs[0] == "safe" {
-                opts.strip = Headers::Safe;
-            } else {
-                opts.strip = Headers::All;
-            }
-        } else {
-            const FORBIDDEN_CHUNKS: [[u8; 4]; 5] =
-                [*b"IHDR", *b"IDAT", *b"tRNS", *b"PLTE", *b"IEND"];
-            for i in &hdrs {
-                if FORBIDDEN_CHUNKS.iter().any(|chunk| chunk == i.as_bytes()) {
-                    return Err(format!("{} chunk is not allowed to be stripped", i));
-                }
-            }
-            opts.strip = Headers::Strip(hdrs);
+    let negated_flags = [
+        ("no-bit-reduction", &mut settings.bit_depth_reduction),
+        ("no-color-reduction", &mut settings.color_type_reduction),
+        ("no-palette-reduction", &mut settings.palette_reduction),
+        ("no-grayscale-reduction", &mut settings.grayscale_reduction),
+        ("no-reductions", &mut settings.reductions),
+        ("no-recoding", &mut settings.idat_recoding),
+    ];
+    for (flag, setting) in negated_flags {
+        if matches.is_present(flag) {
+            *setting = Some(false);
         }
     }
 
+    let value = |name| matches.value_of(name).map(str::to_owned);
+    settings.keep = value("keep").map(Chunks::Joined);
+    settings.strip = value("strip").map(Chunks::Joined);
     if matches.is_present("strip-safe") {
-        opts.strip = Headers::Safe;
+        settings.strip = Some(Chunks::Joined("safe".to_owned()));
     }
+    settings.c2pa = value("c2pa");
+    settings.verify = value("verify");
+    settings.trailing_data = value("trailing-data");
+    settings.min_savings = value("min-savings");
+    settings.symlinks = value("symlinks");
+    settings.hardlinks = value("hardlinks");
 
-    if matches.is_present("zopfli") {
-        #[cfg(feature = "zopfli")]
-        if let Some(iterations) = NonZeroU8::new(15) {
-            opts.deflate = Deflaters::Zopfli { iterations };
-        }
-    } else if let Deflaters::Libdeflater { compression } = &mut opts.deflate {
-        if let Some(x) = matches.get_one::<i64>("compression") {
-            *compression = *x as u8;
-        }
+    settings.backup_dir = matches.get_one::<PathBuf>("backup-dir").cloned();
+    settings.backup_suffix = value("backup-suffix");
+    settings.backup_keep = matches.get_one::<usize>("backup-keep").copied();
+    if ["backup-dir", "backup-suffix", "backup-keep"]
+        .iter()
+        .any(|&flag| matches.is_present(flag))
+    {
+        settings.backup = Some(true);
     }
 
+    settings.max_pixels = matches.get_one::<u64>("max-pixels").copied();
+    settings.max_decoded_size = matches.get_one::<u64>("max-decoded-size").copied();
+    settings.max_chunk_size = matches.get_one::<u32>("max-chunk-size").copied();
+    settings.max_memory = matches.get_one::<u64>("max-memory").copied();
+    settings.zc = matches.get_one::<i64>("compression").map(|&x| x as u8);
+
     #[cfg(feature = "parallel")]
     if let Some(x) = matches.value_of("threads") {
         let threads = x.parse::<usize>().unwrap();
@@ -552,7 +1047,45 @@ fn parse_opts_into_struct(
             .map_err(|err| err.to_string())?;
     }
 
-    Ok((out_file, out_dir, opts))
+    let template = matches
+        .value_of("name-template")
+        .map(Template::parse)
+        .transpose()?;
+    let outputs = Outputs {
+        dir: out_dir,
+        file: out_file,
+        template,
+    };
+
+    let mut filter = FileFilter {
+        recursive: matches.is_present("recursive"),
+        ignore_files: matches.is_present("ignore-files"),
+        follow_symlinks: matches.is_present("follow-symlinks"),
+        max_depth: matches.get_one::<usize>("max-depth").copied(),
+        ..FileFilter::default()
+    };
+    if let Some(list) = matches.value_of("ext") {
+        filter.extensions = list
+            .split(',')
+            .map(|ext| ext.trim().trim_start_matches('.').to_ascii_lowercase())
+            .filter(|ext| ext != "*")
+            .collect();
+    }
+    if let Some(globs) = matches.values_of("include") {
+        filter.include = globs.map(str::to_owned).collect();
+    }
+    if let Some(globs) = matches.values_of("exclude") {
+        filter.exclude = globs.map(str::to_owned).collect();
+    }
+    filter.validate()?;
+    filter.min_size = matches.value_of("min-size").map(parse_size).transpose()?;
+    filter.max_size = matches.value_of("max-size").map(parse_size).transpose()?;
+    filter.modified_since = matches
+        .value_of("modified-since")
+        .map(parse_modified_since)
+        .transpose()?;
+
+    Ok((outputs, filter, settings))
 }
 
 fn parse_numeric_range_opts(
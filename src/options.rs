@@ -0,0 +1,330 @@
+use crate::deflate::Deflaters;
+use crate::filters::RowFilter;
+use crate::headers::{C2PAPolicy, Headers, TrailingData};
+use crate::interlace::Interlacing;
+use crate::limits::Limits;
+use crate::{Backup, IndexSet, LinkPolicy, MinSavings, Options, VerifyMode};
+use std::error::Error;
+use std::fmt;
+#[cfg(feature = "zopfli")]
+use std::num::NonZeroU8;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+/// A problem with the values or the combination of optimization options
+pub enum OptionsError {
+    /// The optimization preset is not between 0 and 6
+    PresetLevel(u8),
+    /// The libdeflater compression level is not between 1 and 12
+    CompressionLevel(u8),
+    /// No filters are given, but fast evaluation is disabled
+    ///
+    /// An empty filter set means the filter is picked heuristically, which is only
+    /// done alongside fast evaluation (as in presets 0 and 1).
+    EmptyFilterSet,
+    /// Falling back to the original image is enabled, but verification is not
+    VerifyFallbackWithoutVerify,
+    /// The minimum savings are not a percentage between 0 and 100
+    MinSavingsPercent,
+    /// The backup configuration keeps no backups
+    NoBackupsKept,
+    /// The backup suffix contains a path separator, or is empty without a backup directory
+    BackupSuffix,
+}
+
+impl Error for OptionsError {}
+
+impl fmt::Display for OptionsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            OptionsError::PresetLevel(level) => write!(
+                f,
+                "Optimization preset {} does not exist, expected 0-6",
+                level
+            ),
+            OptionsError::CompressionLevel(level) => write!(
+                f,
+                "Compression level {} is out of range, expected 1-12",
+                level
+            ),
+            OptionsError::EmptyFilterSet => {
+                f.write_str("An empty filter set can only be used with fast evaluation")
+            }
+            OptionsError::VerifyFallbackWithoutVerify => {
+                f.write_str("Verification fallback requires verification to be enabled")
+            }
+            OptionsError::MinSavingsPercent => {
+                f.write_str("Minimum savings must be a percentage between 0 and 100")
+            }
+            OptionsError::NoBackupsKept => f.write_str("At least one backup must be kept"),
+            OptionsError::BackupSuffix => f.write_str(
+                "The backup suffix must not contain a path separator, or be empty without a backup directory",
+            ),
+        }
+    }
+}
+
+impl Options {
+    /// Check that the options are in range and can be used together
+    pub fn validate(&self) -> Result<(), OptionsError> {
+        if let Deflaters::Libdeflater { compression } = self.deflate {
+            if !(1..=12).contains(&compression) {
+                return Err(OptionsError::CompressionLevel(compression));
+            }
+        }
+        if self.filter.is_empty() && !self.fast_evaluation {
+            return Err(OptionsError::EmptyFilterSet);
+        }
+        if self.verify_fallback && self.verify == VerifyMode::Off {
+            return Err(OptionsError::VerifyFallbackWithoutVerify);
+        }
+        if let Some(MinSavings::Percent(percent)) = self.min_savings {
+            if !(0.0..=100.0).contains(&percent) {
+                return Err(OptionsError::MinSavingsPercent);
+            }
+        }
+        // Also checked with backups disabled, as restoring backups uses the same configuration
+        let backup = &self.backup_config;
+        if backup.keep == 0 {
+            return Err(OptionsError::NoBackupsKept);
+        }
+        if backup.suffix.contains(std::path::is_separator)
+            || (backup.suffix.is_empty() && backup.dir.is_none())
+        {
+            return Err(OptionsError::BackupSuffix);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+/// Builds validated `Options`
+///
+/// The optimization preset sets the filters, the compression level and fast evaluation.
+/// Setting any of these explicitly overrides the preset, regardless of the order the
+/// methods are called in, as with the command line:
+///
+/// ```text
+/// preset 0   =>  compression 5, filters heuristic, fast evaluation
+/// preset 1   =>  compression 10, filters heuristic, fast evaluation
+/// preset 2   =>  compression 11, filters 0,1,6,7, fast evaluation (default)
+/// preset 3   =>  compression 11, filters 0,7,8,9
+/// preset 4   =>  compression 12, filters 0,7,8,9
+/// preset 5   =>  compression 12, filters 0,1,2,5,6,7,8,9
+/// preset 6   =>  compression 12, filters 0-9
+/// ```
+///
+/// Presets only change the compression level when libdeflater is used.
+/// All other settings start from the defaults of `Options`.
+pub struct OptionsBuilder {
+    preset: Option<u8>,
+    filter: Option<IndexSet<RowFilter>>,
+    deflate: Option<Deflaters>,
+    fast_evaluation: Option<bool>,
+    options: Options,
+}
+
+impl OptionsBuilder {
+    pub fn new() -> OptionsBuilder {
+        OptionsBuilder::default()
+    }
+
+    /// Optimization preset, from 0 to 6
+    ///
+    /// Default: `2`
+    pub fn preset(mut self, level: u8) -> Self {
+        self.preset = Some(level);
+        self
+    }
+
+    /// Use the preset with the maximum compression
+    pub fn max_compression(self) -> Self {
+        self.preset(6)
+    }
+
+    /// Which RowFilters to try on the file, overriding the preset
+    ///
+    /// An empty set picks a filter heuristically and requires fast evaluation.
+    pub fn filters<I: IntoIterator<Item = RowFilter>>(mut self, filters: I) -> Self {
+        self.filter = Some(filters.into_iter().collect());
+        self
+    }
+
+    /// Use libdeflater with this compression level (1-12), overriding the preset
+    pub fn compression(mut self, level: u8) -> Self {
+        self.deflate = Some(Deflaters::Libdeflater { compression: level });
+        self
+    }
+
+    /// Use Zopfli with this number of iterations, overriding the preset
+    #[cfg(feature = "zopfli")]
+    pub fn zopfli(mut self, iterations: NonZeroU8) -> Self {
+        self.deflate = Some(Deflaters::Zopfli { iterations });
+        self
+    }
+
+    /// Whether to use fast evaluation to pick the best filter, overriding the preset
+    pub fn fast_evaluation(mut self, enabled: bool) -> Self {
+        self.fast_evaluation = Some(enabled);
+        self
+    }
+
+    pub fn backup(mut self, enabled: bool) -> Self {
+        self.options.backup = enabled;
+        self
+    }
+
+    /// Where to keep backups and how many, also used to find them when restoring
+    pub fn backup_config(mut self, backup: Backup) -> Self {
+        self.options.backup_config = backup;
+        self
+    }
+
+    pub fn fix_errors(mut self, enabled: bool) -> Self {
+        self.options.fix_errors = enabled;
+        self
+    }
+
+    pub fn check(mut self, enabled: bool) -> Self {
+        self.options.check = enabled;
+        self
+    }
+
+    pub fn pretend(mut self, enabled: bool) -> Self {
+        self.options.pretend = enabled;
+        self
+    }
+
+    pub fn force(mut self, enabled: bool) -> Self {
+        self.options.force = enabled;
+        self
+    }
+
+    /// Percentages must be between 0 and 100
+    pub fn min_savings(mut self, min_savings: Option<MinSavings>) -> Self {
+        self.options.min_savings = min_savings;
+        self
+    }
+
+    pub fn preserve_attrs(mut self, enabled: bool) -> Self {
+        self.options.preserve_attrs = enabled;
+        self
+    }
+
+    pub fn symlinks(mut self, policy: LinkPolicy) -> Self {
+        self.options.symlinks = policy;
+        self
+    }
+
+    pub fn hardlinks(mut self, policy: LinkPolicy) -> Self {
+        self.options.hardlinks = policy;
+        self
+    }
+
+    pub fn interlace(mut self, interlace: Option<Interlacing>) -> Self {
+        self.options.interlace = interlace;
+        self
+    }
+
+    pub fn optimize_alpha(mut self, enabled: bool) -> Self {
+        self.options.optimize_alpha = enabled;
+        self
+    }
+
+    pub fn bit_depth_reduction(mut self, enabled: bool) -> Self {
+        self.options.bit_depth_reduction = enabled;
+        self
+    }
+
+    pub fn color_type_reduction(mut self, enabled: bool) -> Self {
+        self.options.color_type_reduction = enabled;
+        self
+    }
+
+    pub fn palette_reduction(mut self, enabled: bool) -> Self {
+        self.options.palette_reduction = enabled;
+        self
+    }
+
+    pub fn grayscale_reduction(mut self, enabled: bool) -> Self {
+        self.options.grayscale_reduction = enabled;
+        self
+    }
+
+    /// Enable or disable all of the bit depth, color type, palette and grayscale reductions
+    pub fn reductions(self, enabled: bool) -> Self {
+        self.bit_depth_reduction(enabled)
+            .color_type_reduction(enabled)
+            .palette_reduction(enabled)
+            .grayscale_reduction(enabled)
+    }
+
+    pub fn idat_recoding(mut self, enabled: bool) -> Self {
+        self.options.idat_recoding = enabled;
+        self
+    }
+
+    pub fn strip(mut self, strip: Headers) -> Self {
+        self.options.strip = strip;
+        self
+    }
+
+    pub fn convert_raw_profiles(mut self, enabled: bool) -> Self {
+        self.options.convert_raw_profiles = enabled;
+        self
+    }
+
+    pub fn sidecar(mut self, enabled: bool) -> Self {
+        self.options.sidecar = enabled;
+        self
+    }
+
+    pub fn c2pa(mut self, policy: C2PAPolicy) -> Self {
+        self.options.c2pa = policy;
+        self
+    }
+
+    pub fn trailing_data(mut self, policy: TrailingData) -> Self {
+        self.options.trailing_data = policy;
+        self
+    }
+
+    pub fn verify(mut self, mode: VerifyMode) -> Self {
+        self.options.verify = mode;
+        self
+    }
+
+    /// Requires `verify` to be enabled
+    pub fn verify_fallback(mut self, enabled: bool) -> Self {
+        self.options.verify_fallback = enabled;
+        self
+    }
+
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.options.limits = limits;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.options.timeout = timeout;
+        self
+    }
+
+    /// Apply the preset and the explicit settings, and validate the result
+    pub fn build(self) -> Result<Options, OptionsError> {
+        let level = self.preset.unwrap_or(2);
+        if level > 6 {
+            return Err(OptionsError::PresetLevel(level));
+        }
+        let preset = Options::from_preset(level);
+        let opts = Options {
+            filter: self.filter.unwrap_or(preset.filter),
+            deflate: self.deflate.unwrap_or(preset.deflate),
+            fast_evaluation: self.fast_evaluation.unwrap_or(preset.fast_evaluation),
+            ..self.options
+        };
+        opts.validate()?;
+        Ok(opts)
+    }
+}
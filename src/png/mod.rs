@@ -1,23 +1,27 @@
+use crate::atomicmin::AtomicMin;
 use crate::colors::ColorType;
 use crate::deflate;
 use crate::error::PngError;
 use crate::filters::*;
 use crate::headers::*;
 use crate::interlace::{deinterlace_image, interlace_image, Interlacing};
+use crate::Options;
 use bitvec::bitarr;
-use indexmap::IndexMap;
 use libdeflater::{CompressionLvl, Compressor};
+use log::{info, warn};
 use rgb::ComponentSlice;
 use rgb::RGBA8;
 use rustc_hash::FxHashMap;
 use std::fs::File;
-use std::io::{BufReader, Read, Write};
+use std::io::{BufReader, ErrorKind, Read, Write};
 use std::iter::Iterator;
 use std::path::Path;
 use std::sync::Arc;
 
+pub(crate) mod recovery;
 pub(crate) mod scan_lines;
 
+pub use self::recovery::Repair;
 use self::scan_lines::ScanLines;
 
 /// Compression level to use for the Brute filter strategy
@@ -37,7 +41,7 @@ pub struct PngImage {
     /// The pixel value that should be rendered as transparent
     pub transparency_pixel: Option<Vec<u8>>,
     /// All non-critical headers from the PNG are stored here
-    pub aux_headers: IndexMap<[u8; 4], Vec<u8>>,
+    pub aux_headers: AuxHeaders,
 }
 
 /// Contains all data relevant to a PNG image
@@ -49,6 +53,10 @@ pub struct PngData {
     pub idat_data: Vec<u8>,
     /// The filtered, uncompressed data of the IDAT chunk
     pub filtered: Vec<u8>,
+    /// Any data following the IEND chunk that should be kept
+    pub trailing_data: Vec<u8>,
+    /// Problems in the input that were worked around when fixing errors
+    pub repairs: Vec<Repair>,
 }
 
 type PaletteWithTrns = (Option<Vec<RGBA8>>, Option<Vec<u8>>);
@@ -57,38 +65,60 @@ impl PngData {
     /// Create a new `PngData` struct by opening a file
     #[inline]
     pub fn new(filepath: &Path, fix_errors: bool) -> Result<Self, PngError> {
+        Self::new_with_options(filepath, &Self::parse_options(fix_errors))
+    }
+
+    /// Create a new `PngData` struct by opening a file, applying the error fixing, C2PA,
+    /// trailing data and resource limit options
+    #[inline]
+    pub fn new_with_options(filepath: &Path, opts: &Options) -> Result<Self, PngError> {
         let byte_data = Self::read_file(filepath)?;
 
-        Self::from_slice(&byte_data, fix_errors)
+        Self::from_slice_with_options(&byte_data, opts)
+    }
+
+    /// Options that parse the file as it is, keeping any C2PA manifest
+    fn parse_options(fix_errors: bool) -> Options {
+        Options {
+            fix_errors,
+            c2pa: C2PAPolicy::Keep,
+            ..Options::default()
+        }
     }
 
     pub fn read_file(filepath: &Path) -> Result<Vec<u8>, PngError> {
-        let file = match File::open(filepath) {
-            Ok(f) => f,
-            Err(_) => return Err(PngError::new("Failed to open file for reading")),
-        };
+        let file = File::open(filepath).map_err(|e| PngError::io(Some(filepath), e))?;
         let file_len = file.metadata().map(|m| m.len() as usize).unwrap_or(0);
         let mut reader = BufReader::new(file);
         // Check file for PNG header
         let mut header = [0; 8];
-        if reader.read_exact(&mut header).is_err() {
-            return Err(PngError::new("Not a PNG file: too small"));
-        }
-        if !file_header_is_valid(&header) {
-            return Err(PngError::new("Invalid PNG header detected"));
+        match reader.read_exact(&mut header) {
+            Ok(()) if file_header_is_valid(&header) => (),
+            Ok(()) => return Err(PngError::NotPNG),
+            // Too small to be a PNG file
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Err(PngError::NotPNG),
+            Err(e) => return Err(PngError::io(Some(filepath), e)),
         }
         // Read raw png data into memory
         let mut byte_data: Vec<u8> = Vec::with_capacity(file_len);
         byte_data.extend_from_slice(&header);
-        match reader.read_to_end(&mut byte_data) {
-            Ok(_) => (),
-            Err(_) => return Err(PngError::new("Failed to read from file")),
-        }
+        reader
+            .read_to_end(&mut byte_data)
+            .map_err(|e| PngError::io(Some(filepath), e))?;
         Ok(byte_data)
     }
 
     /// Create a new `PngData` struct by reading a slice
+    #[inline]
     pub fn from_slice(byte_data: &[u8], fix_errors: bool) -> Result<Self, PngError> {
+        Self::from_slice_with_options(byte_data, &Self::parse_options(fix_errors))
+    }
+
+    /// Create a new `PngData` struct by reading a slice, applying the error fixing, C2PA,
+    /// trailing data and resource limit options
+    ///
+    /// A C2PA manifest is kept with `C2PAPolicy::Strip`, it is removed when optimizing.
+    pub fn from_slice_with_options(byte_data: &[u8], opts: &Options) -> Result<Self, PngError> {
         let mut byte_offset: usize = 0;
         // Test that png header is valid
         let header = byte_data.get(0..8).ok_or(PngError::TruncatedData)?;
@@ -97,15 +127,96 @@ impl PngData {
         }
         byte_offset += 8;
         // Read the data headers
-        let mut aux_headers: IndexMap<[u8; 4], Vec<u8>> = IndexMap::new();
+        let mut aux_headers = AuxHeaders::new();
         let mut idat_headers: Vec<u8> = Vec::new();
-        while let Some(header) = parse_next_header(byte_data, &mut byte_offset, fix_errors)? {
+        let mut c2pa_found = false;
+        let mut repairs = Vec::new();
+        let mut last_name = [0; 4];
+        // Bounds the work spent looking for intact chunks in a damaged file
+        let mut resync_budget = byte_data.len().saturating_mul(4);
+        loop {
+            let chunk_start = byte_offset;
+            let header = match parse_next_header(byte_data, &mut byte_offset, opts.fix_errors) {
+                Ok(Some(header)) => header,
+                Ok(None) => break,
+                Err(e) if !opts.fix_errors => return Err(e),
+                Err(_) => {
+                    // Skip ahead to the next intact chunk, or treat the rest as lost
+                    if let Some(next) =
+                        recovery::find_next_chunk(byte_data, chunk_start + 1, &mut resync_budget)
+                    {
+                        repairs.push(Repair::SkippedBytes(chunk_start, next - chunk_start));
+                        byte_offset = next;
+                        continue;
+                    }
+                    if let Some(data) = recovery::truncated_idat(byte_data, chunk_start) {
+                        repairs.push(Repair::TruncatedChunk);
+                        idat_headers.extend_from_slice(data);
+                    } else if chunk_start < byte_data.len() {
+                        repairs.push(Repair::SkippedBytes(
+                            chunk_start,
+                            byte_data.len() - chunk_start,
+                        ));
+                    }
+                    repairs.push(Repair::MissingIend);
+                    byte_offset = byte_data.len();
+                    break;
+                }
+            };
+            opts.limits.check_chunk(&header.name, header.data.len())?;
+            if !header.crc_valid {
+                repairs.push(Repair::CrcMismatch(header.name));
+            }
             match &header.name {
-                b"IDAT" => idat_headers.extend_from_slice(header.data),
+                b"IDAT" => {
+                    if opts.fix_errors
+                        && &last_name != b"IDAT"
+                        && !idat_headers.is_empty()
+                        && !repairs.contains(&Repair::SplitImageData)
+                    {
+                        repairs.push(Repair::SplitImageData);
+                    }
+                    idat_headers.extend_from_slice(header.data);
+                }
                 b"acTL" => return Err(PngError::APNGNotSupported),
+                name if is_c2pa_manifest(name, header.data) => {
+                    c2pa_found = true;
+                    match opts.c2pa {
+                        C2PAPolicy::Skip => return Err(PngError::C2PAProtected),
+                        // Removed when optimizing, after the sidecar has kept a copy
+                        C2PAPolicy::Strip | C2PAPolicy::Keep => {
+                            aux_headers.push(header.name, header.data.to_owned());
+                        }
+                    }
+                }
                 _ => {
-                    aux_headers.insert(header.name, header.data.to_owned());
+                    aux_headers.push(header.name, header.data.to_owned());
+                }
+            }
+            last_name = header.name;
+        }
+        let trailing_data = &byte_data[byte_offset..];
+        if !trailing_data.is_empty() {
+            match opts.trailing_data {
+                TrailingData::Keep => info!(
+                    "Keeping {} bytes of trailing data after IEND",
+                    trailing_data.len()
+                ),
+                TrailingData::Strip => info!(
+                    "Stripping {} bytes of trailing data after IEND",
+                    trailing_data.len()
+                ),
+                TrailingData::Error => {
+                    return Err(PngError::TrailingData(trailing_data.len()));
+                }
+            }
+        }
+        if c2pa_found {
+            match opts.c2pa {
+                C2PAPolicy::Strip => {
+                    warn!("Removing C2PA content credentials; the image provenance will be lost")
                 }
+                _ => warn!("C2PA content credentials will be invalidated by optimizing this file"),
             }
         }
         // Parse the headers into our PngData
@@ -117,12 +228,26 @@ impl PngData {
             None => return Err(PngError::ChunkMissing("IHDR")),
         };
         let ihdr_header = parse_ihdr_header(&ihdr)?;
-        let raw_data = deflate::inflate(idat_headers.as_ref(), ihdr_header.raw_data_size())?;
+        let raw_data_size =
+            opts.limits
+                .check_image(&ihdr_header, byte_data.len(), opts.filter.len())?;
+        let mut raw_data = match deflate::inflate(idat_headers.as_ref(), raw_data_size) {
+            Ok(raw_data) => raw_data,
+            Err(_) if opts.fix_errors => {
+                recovery::inflate_partial(&idat_headers, raw_data_size, &mut repairs)
+            }
+            Err(e) => return Err(e),
+        };
 
         // Reject files with incorrect width/height or truncated data
-        if raw_data.len() != ihdr_header.raw_data_size() {
-            return Err(PngError::TruncatedData);
+        if raw_data.len() != raw_data_size {
+            if !opts.fix_errors {
+                return Err(PngError::TruncatedData);
+            }
+            repairs.push(Repair::TruncatedImageData(raw_data.len(), raw_data_size));
         }
+        let recovered = raw_data.len();
+        raw_data.resize(raw_data_size, 0);
 
         let (palette, transparency_pixel) = Self::palette_to_rgba(
             ihdr_header.color_type,
@@ -137,17 +262,42 @@ impl PngData {
             transparency_pixel,
             aux_headers,
         };
+        if recovered < raw_data_size {
+            // The missing rows repeat the last row that could be recovered
+            recovery::pad_image_data(&mut raw, recovered);
+        }
         let unfiltered = raw.unfilter_image()?;
+        let mut filtered = std::mem::replace(&mut raw.data, unfiltered);
+        if opts.fix_errors && raw.ihdr.color_type == ColorType::Indexed {
+            let clamped = recovery::clamp_palette_indices(&mut raw);
+            if clamped > 0 {
+                repairs.push(Repair::PaletteIndicesClamped(clamped));
+                filtered = raw.filter_image(RowFilter::None, false);
+            }
+        }
+        let mut idat_data = idat_headers;
+        if repairs.iter().any(Repair::affects_image_data) {
+            // The original data can't be kept as a fallback, so compress the repaired data
+            idat_data = deflate::deflate(&filtered, 1, &AtomicMin::new(None))?;
+        }
+        for repair in &repairs {
+            warn!("Repaired: {}", repair);
+        }
         // Return the PngData
         Ok(Self {
-            idat_data: idat_headers,
-            filtered: std::mem::replace(&mut raw.data, unfiltered),
+            idat_data,
+            filtered,
             raw: Arc::new(raw),
+            trailing_data: match opts.trailing_data {
+                TrailingData::Keep => trailing_data.to_owned(),
+                _ => Vec::new(),
+            },
+            repairs,
         })
     }
 
     /// Handle transparency header
-    fn palette_to_rgba(
+    pub(crate) fn palette_to_rgba(
         color_type: ColorType,
         palette_data: Option<Vec<u8>>,
         trns_data: Option<Vec<u8>>,
@@ -190,6 +340,7 @@ impl PngData {
         ihdr_data.write_all(&[self.raw.ihdr.interlaced as u8]).ok();
         write_png_block(b"IHDR", &ihdr_data, &mut output);
         // Ancillary headers
+        // Colour space chunks (cHRM, gAMA, iCCP, sRGB, sBIT, cICP, mDCv, cLLi) must come before PLTE and IDAT
         for (key, header) in self
             .raw
             .aux_headers
@@ -246,6 +397,8 @@ impl PngData {
         write_png_block(b"IDAT", &self.idat_data, &mut output);
         // Stream end
         write_png_block(b"IEND", &[], &mut output);
+        // Trailing data
+        output.extend_from_slice(&self.trailing_data);
 
         output
     }
@@ -465,7 +618,7 @@ impl PngImage {
         filtered
     }
 }
-fn write_png_block(key: &[u8], header: &[u8], output: &mut Vec<u8>) {
+pub(crate) fn write_png_block(key: &[u8], header: &[u8], output: &mut Vec<u8>) {
     let mut header_data = Vec::with_capacity(header.len() + 4);
     header_data.extend_from_slice(key);
     header_data.extend_from_slice(header);
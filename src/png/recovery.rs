@@ -0,0 +1,218 @@
+use crate::deflate::crc32;
+use crate::filters::RowFilter;
+use crate::png::PngImage;
+use miniz_oxide::inflate::core::inflate_flags::{
+    TINFL_FLAG_PARSE_ZLIB_HEADER, TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF,
+};
+use miniz_oxide::inflate::core::{decompress, DecompressorOxide};
+use miniz_oxide::inflate::TINFLStatus;
+use std::fmt;
+
+/// A problem in the input that was worked around when fixing errors
+///
+/// Any image with repairs may be missing data or have been damaged,
+/// and should be checked before replacing the original.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Repair {
+    /// A chunk had an incorrect CRC and was used anyway
+    CrcMismatch([u8; 4]),
+    /// Unreadable bytes at an offset were skipped to find the next valid chunk
+    SkippedBytes(usize, usize),
+    /// The last chunk of image data was cut off, the data that was present was used
+    TruncatedChunk,
+    /// The file ended without an IEND chunk
+    MissingIend,
+    /// The IDAT chunks were not consecutive
+    SplitImageData,
+    /// The image data had an incorrect Adler-32 checksum
+    BadAdler32,
+    /// The image data contained more than the image needs, the rest was ignored
+    ExcessImageData,
+    /// Only part of the image data (bytes recovered, bytes expected) could be decompressed,
+    /// the missing rows were filled in with copies of the last row
+    TruncatedImageData(usize, usize),
+    /// A number of palette indices pointed outside the palette and were clamped to the last entry
+    PaletteIndicesClamped(usize),
+}
+
+impl Repair {
+    /// Whether the repair changed the image data, so the original IDAT data can't be reused
+    pub(crate) fn affects_image_data(&self) -> bool {
+        matches!(
+            self,
+            Repair::BadAdler32
+                | Repair::ExcessImageData
+                | Repair::TruncatedImageData(..)
+                | Repair::PaletteIndicesClamped(_)
+        )
+    }
+
+    /// Whether pixel data was salvaged from damaged image data, so the original can't be kept
+    /// even if it is smaller
+    pub(crate) fn salvages_image_data(&self) -> bool {
+        self.affects_image_data() || matches!(self, Repair::TruncatedChunk | Repair::SplitImageData)
+    }
+}
+
+impl fmt::Display for Repair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Repair::CrcMismatch(name) => write!(
+                f,
+                "ignored CRC mismatch in {} chunk",
+                String::from_utf8_lossy(&name)
+            ),
+            Repair::SkippedBytes(offset, len) => {
+                write!(f, "skipped {} unreadable bytes at offset {}", len, offset)
+            }
+            Repair::TruncatedChunk => f.write_str("used the data of a truncated IDAT chunk"),
+            Repair::MissingIend => f.write_str("file has no IEND chunk"),
+            Repair::SplitImageData => f.write_str("joined IDAT chunks that were not consecutive"),
+            Repair::BadAdler32 => f.write_str("ignored Adler-32 mismatch in image data"),
+            Repair::ExcessImageData => f.write_str("ignored excess image data"),
+            Repair::TruncatedImageData(recovered, expected) => write!(
+                f,
+                "recovered {} of {} bytes of image data, padded the missing rows",
+                recovered, expected
+            ),
+            Repair::PaletteIndicesClamped(count) => {
+                write!(f, "clamped {} palette indices outside the palette", count)
+            }
+        }
+    }
+}
+
+/// Whether a chunk name consists of ASCII letters, as required by the spec
+fn is_chunk_name(name: &[u8]) -> bool {
+    name.iter().all(u8::is_ascii_alphabetic)
+}
+
+/// Find the start of the next intact chunk at or after an offset
+///
+/// A chunk is only accepted if its length, name and CRC are all valid,
+/// except for IEND which has no data and may be cut off.
+///
+/// Each offset tried and each byte checked against a CRC is taken from `budget`, the search
+/// gives up once it runs out. Sharing the budget between the searches in a file keeps a
+/// badly damaged file from taking quadratic time.
+pub(crate) fn find_next_chunk(byte_data: &[u8], from: usize, budget: &mut usize) -> Option<usize> {
+    for offset in from..byte_data.len().saturating_sub(8) {
+        *budget = budget.checked_sub(1)?;
+        let name = &byte_data[offset + 4..offset + 8];
+        if !is_chunk_name(name) {
+            continue;
+        }
+        let length = u32::from_be_bytes(byte_data[offset..offset + 4].try_into().unwrap());
+        if name == b"IEND" {
+            if length == 0 {
+                return Some(offset);
+            }
+            continue;
+        }
+        let end = match (offset + 8).checked_add(length as usize) {
+            Some(end) => end,
+            None => continue,
+        };
+        let crc = match end
+            .checked_add(4)
+            .and_then(|crc_end| byte_data.get(end..crc_end))
+        {
+            Some(crc) => crc,
+            None => continue,
+        };
+        *budget = budget.checked_sub(end - offset)?;
+        if crc32(&byte_data[offset + 4..end]).to_be_bytes() == crc {
+            return Some(offset);
+        }
+    }
+    None
+}
+
+/// Get the data of a truncated IDAT chunk at an offset, if that is what is there
+pub(crate) fn truncated_idat(byte_data: &[u8], offset: usize) -> Option<&[u8]> {
+    match byte_data.get(offset + 4..offset + 8) {
+        Some(b"IDAT") => byte_data.get(offset + 8..),
+        _ => None,
+    }
+}
+
+/// Inflate as much of the image data as possible, ignoring an incorrect checksum
+///
+/// Returns the data that could be decompressed, which may be shorter than `out_size`.
+/// Nothing is inflated beyond `out_size`, which must have been checked against the limits.
+pub(crate) fn inflate_partial(data: &[u8], out_size: usize, repairs: &mut Vec<Repair>) -> Vec<u8> {
+    let mut decompressor = DecompressorOxide::new();
+    let mut output = vec![0; out_size];
+    let (status, _, written) = decompress(
+        &mut decompressor,
+        data,
+        &mut output,
+        0,
+        TINFL_FLAG_PARSE_ZLIB_HEADER | TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF,
+    );
+    match status {
+        // The data is decompressed before the checksum is checked
+        TINFLStatus::Adler32Mismatch => repairs.push(Repair::BadAdler32),
+        TINFLStatus::HasMoreOutput => repairs.push(Repair::ExcessImageData),
+        // Anything else means the data is incomplete, which the caller reports
+        _ => (),
+    }
+    output.truncate(written);
+    output
+}
+
+/// Fill in the image data after the `recovered` bytes, which has already been padded with zeroes
+///
+/// Each missing row is given the Up filter, so it unfilters to a copy of the row above it: the
+/// last row that could be recovered. In interlaced images, a pass that is missing entirely
+/// starts with a row of zeroes instead, as Up has no row above it to copy.
+pub(crate) fn pad_image_data(png: &mut PngImage, recovered: usize) {
+    let lines: Vec<_> = png
+        .scan_lines(true)
+        .map(|line| line.data.len() + 1)
+        .collect();
+    let mut offset = 0;
+    for len in lines {
+        if offset >= recovered {
+            png.data[offset] = RowFilter::Up as u8;
+        }
+        offset += len;
+    }
+}
+
+/// Replace palette indices that are out of range with the last palette entry
+///
+/// Returns the number of indices that were changed. The padding bits at the end of rows with
+/// less than 8 bits per pixel are not indices and are left alone.
+pub(crate) fn clamp_palette_indices(png: &mut PngImage) -> usize {
+    let last = match &png.palette {
+        Some(palette) if !palette.is_empty() => palette.len() - 1,
+        _ => return 0,
+    };
+    let bits = png.ihdr.bit_depth.as_u8() as usize;
+    if last >= (1 << bits) - 1 {
+        return 0;
+    }
+    let lines: Vec<_> = png
+        .scan_lines(false)
+        .map(|line| (line.data.len(), line.num_pixels))
+        .collect();
+    let mask = ((1_u16 << bits) - 1) as u8;
+    let mut clamped = 0;
+    let mut rest = &mut png.data[..];
+    for (len, pixels) in lines {
+        let (line, next) = std::mem::take(&mut rest).split_at_mut(len);
+        rest = next;
+        for pixel in 0..pixels {
+            let byte = &mut line[pixel * bits / 8];
+            // The first pixel is in the most significant bits
+            let shift = 8 - bits - pixel * bits % 8;
+            if usize::from((*byte >> shift) & mask) > last {
+                *byte = (*byte & !(mask << shift)) | ((last as u8) << shift);
+                clamped += 1;
+            }
+        }
+    }
+    clamped
+}
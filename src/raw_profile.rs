@@ -0,0 +1,206 @@
+use crate::atomicmin::AtomicMin;
+use crate::deflate::{deflate, inflate};
+use crate::error::PngError;
+use crate::headers::Chunk;
+use crate::png::PngImage;
+use crate::{Limits, PngResult};
+use log::{debug, info};
+use std::mem::take;
+
+/// Keyword prefix ImageMagick uses for text chunks holding hex-encoded profiles
+const RAW_PROFILE_PREFIX: &[u8] = b"Raw profile type ";
+/// Keyword of the iTXt chunk holding XMP data, as defined by the XMP specification
+const XMP_KEYWORD: &[u8] = b"XML:com.adobe.xmp";
+/// Header of an APP1 segment containing EXIF data
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+/// Header of an APP1 segment containing XMP data
+const XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+/// Largest hex text of a compressed profile to inflate, about twice the size of the profile
+const MAX_PROFILE_TEXT_SIZE: usize = 32 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Native {
+    Exif,
+    Xmp,
+    Icc,
+}
+
+/// Replace ImageMagick raw profile text chunks with the native chunk for their contents
+///
+/// Profiles are dropped instead if the image already has the native chunk.
+/// Profiles of other types, or ones that can't be decoded within the limits, are left untouched.
+pub(crate) fn convert_raw_profiles(png: &mut PngImage, limits: &Limits) {
+    let chunks = take(&mut png.aux_headers).into_chunks();
+    let mut present: Vec<Native> = chunks.iter().filter_map(native_kind).collect();
+
+    let mut converted = Vec::with_capacity(chunks.len());
+    for chunk in chunks {
+        let profile = match raw_profile(&chunk, limits) {
+            Some(profile) => profile,
+            None => {
+                converted.push(chunk);
+                continue;
+            }
+        };
+        let (kind, data) = match profile {
+            Ok(profile) => profile,
+            Err(e) => {
+                debug!("Ignoring raw profile: {}", e);
+                converted.push(chunk);
+                continue;
+            }
+        };
+        if present.contains(&kind) {
+            info!(
+                "Dropping raw {:?} profile, the native chunk is already present",
+                kind
+            );
+            continue;
+        }
+        let native = match kind {
+            Native::Exif => Chunk {
+                name: *b"eXIf",
+                data,
+            },
+            Native::Xmp => {
+                // Uncompressed, with empty language tag and translated keyword
+                let mut itxt = XMP_KEYWORD.to_vec();
+                itxt.extend_from_slice(&[0, 0, 0, 0, 0]);
+                itxt.extend_from_slice(&data);
+                Chunk {
+                    name: *b"iTXt",
+                    data: itxt,
+                }
+            }
+            Native::Icc => {
+                let compressed = match deflate(&data, 12, &AtomicMin::new(None)) {
+                    Ok(compressed) => compressed,
+                    Err(_) => {
+                        converted.push(chunk);
+                        continue;
+                    }
+                };
+                let mut iccp = b"ICC profile\0\0".to_vec();
+                iccp.extend_from_slice(&compressed);
+                Chunk {
+                    name: *b"iCCP",
+                    data: iccp,
+                }
+            }
+        };
+        info!(
+            "Converted raw {:?} profile to {} ({} bytes to {} bytes)",
+            kind,
+            String::from_utf8_lossy(&native.name),
+            chunk.data.len(),
+            native.data.len()
+        );
+        present.push(kind);
+        converted.push(native);
+    }
+    png.aux_headers = converted.into();
+}
+
+/// Which native profile a chunk holds, if any
+fn native_kind(chunk: &Chunk) -> Option<Native> {
+    match &chunk.name {
+        b"eXIf" => Some(Native::Exif),
+        // Files aren't supposed to have both chunks, and sRGB takes precedence
+        b"iCCP" | b"sRGB" => Some(Native::Icc),
+        b"iTXt" if split_keyword(&chunk.data).0 == XMP_KEYWORD => Some(Native::Xmp),
+        _ => None,
+    }
+}
+
+/// Split text chunk data into its keyword and the rest of the data
+fn split_keyword(data: &[u8]) -> (&[u8], &[u8]) {
+    match data.iter().position(|&b| b == 0) {
+        Some(i) => (&data[..i], &data[i + 1..]),
+        None => (data, &[]),
+    }
+}
+
+/// Decode a raw profile chunk
+///
+/// Returns `None` if the chunk isn't a raw profile of a type that can be converted
+fn raw_profile(chunk: &Chunk, limits: &Limits) -> Option<PngResult<(Native, Vec<u8>)>> {
+    let (keyword, rest) = split_keyword(&chunk.data);
+    let profile_type = keyword.strip_prefix(RAW_PROFILE_PREFIX)?;
+    let kind = match profile_type {
+        // Older versions store both EXIF and XMP as APP1, the contents are checked below
+        b"exif" | b"APP1" => Native::Exif,
+        b"xmp" => Native::Xmp,
+        b"icc" | b"icm" => Native::Icc,
+        _ => return None,
+    };
+    let invalid = |reason| PngError::InvalidRawProfile {
+        keyword: String::from_utf8_lossy(keyword).into(),
+        reason,
+    };
+    let text = match &chunk.name {
+        b"tEXt" => Ok(rest.to_owned()),
+        b"zTXt" => match rest.split_first() {
+            Some((0, compressed)) => {
+                inflate_unknown_size(compressed, limits.max_inflated_size(MAX_PROFILE_TEXT_SIZE))
+                    .map_err(|e| match e {
+                        PngError::InvalidData => invalid("corrupt compressed text"),
+                        e => e,
+                    })
+            }
+            _ => Err(invalid("unknown compression method")),
+        },
+        _ => return None,
+    };
+    Some(text.and_then(|text| {
+        let data = decode_hex_profile(&text).ok_or_else(|| invalid("malformed hex data"))?;
+        if let Some(exif) = data.strip_prefix(EXIF_HEADER) {
+            Ok((Native::Exif, exif.to_owned()))
+        } else if let Some(xmp) = data.strip_prefix(XMP_HEADER) {
+            Ok((Native::Xmp, xmp.to_owned()))
+        } else if profile_type == b"APP1" {
+            Err(invalid("unknown APP1 profile contents"))
+        } else {
+            Ok((kind, data))
+        }
+    }))
+}
+
+/// Inflate zlib data when the decompressed size is not known ahead of time, up to `limit` bytes
+fn inflate_unknown_size(data: &[u8], limit: usize) -> PngResult<Vec<u8>> {
+    let mut max_size = data.len().saturating_mul(4).max(4096).min(limit);
+    loop {
+        match inflate(data, max_size) {
+            Err(PngError::InvalidData) => return Err(PngError::InvalidData),
+            Err(_) if max_size < limit => max_size = max_size.saturating_mul(2).min(limit),
+            Err(_) => return Err(PngError::InflatedSizeLimitExceeded(limit)),
+            result => return result,
+        }
+    }
+}
+
+/// Decode the text of a raw profile: a line with the profile type,
+/// a line with the length in bytes, then the data as hex digits split over many lines
+///
+/// Returns `None` if the text is malformed.
+fn decode_hex_profile(text: &[u8]) -> Option<Vec<u8>> {
+    let mut lines = text
+        .split(|&b| b == b'\n')
+        .filter(|line| !line.iter().all(u8::is_ascii_whitespace));
+    let _profile_type = lines.next()?;
+    let length: usize = std::str::from_utf8(lines.next()?)
+        .ok()
+        .and_then(|len| len.trim().parse().ok())?;
+
+    let mut data = Vec::with_capacity(length.min(text.len() / 2));
+    let mut digits = lines
+        .flatten()
+        .filter(|b| !b.is_ascii_whitespace())
+        .map(|&b| (b as char).to_digit(16));
+    while data.len() < length {
+        match (digits.next(), digits.next()) {
+            (Some(Some(hi)), Some(Some(lo))) => data.push(((hi << 4) | lo) as u8),
+            _ => return None,
+        }
+    }
+    Some(data)
+}
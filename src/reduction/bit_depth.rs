@@ -1,5 +1,5 @@
 use crate::colors::{BitDepth, ColorType};
-use crate::headers::IhdrData;
+use crate::headers::{cicp_is_narrow_range_hdr, IhdrData};
 use crate::png::PngImage;
 
 /// Attempt to reduce the bit depth of the image
@@ -15,7 +15,19 @@ pub fn reduce_bit_depth(png: &PngImage, minimum_bits: usize) -> Option<PngImage>
     }
 
     // Reduce from 16 to 8 bits per channel per pixel
-    if png.data.chunks(2).any(|pair| pair[0] != pair[1]) {
+    // Narrow range HDR samples are scaled by shifting rather than bit replication, so only
+    // zero samples keep their value either way
+    let narrow_range_hdr = png
+        .aux_headers
+        .get(b"cICP")
+        .map_or(false, |cicp| cicp_is_narrow_range_hdr(cicp));
+    if png.data.chunks(2).any(|pair| {
+        if narrow_range_hdr {
+            pair != [0, 0]
+        } else {
+            pair[0] != pair[1]
+        }
+    }) {
         // Can't reduce
         return None;
     }
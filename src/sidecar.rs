@@ -0,0 +1,238 @@
+use crate::atomicwrite::write_file;
+use crate::deflate::crc32;
+use crate::error::PngError;
+use crate::headers::{parse_next_header, AuxHeaders, C2PAPolicy, Chunk, TrailingData};
+use crate::limits::Limits;
+use crate::png::{write_png_block, PngData, PngImage};
+use crate::{InFile, Options, OutFile, PngResult};
+use log::{info, warn};
+use std::fs;
+use std::io::{stdout, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Signature at the start of every sidecar file, modelled on the PNG signature
+const SIDECAR_SIGNATURE: [u8; 8] = [0x89, b'O', b'X', b'M', b'\r', b'\n', 0x1A, b'\n'];
+const SIDECAR_VERSION: u8 = 1;
+
+/// Headers whose contents depend on the color type, bit depth or palette of the image
+const FORMAT_DEPENDENT_HEADERS: [[u8; 4]; 3] = [*b"bKGD", *b"hIST", *b"sBIT"];
+
+/// What happened to each ancillary header of the original image
+#[derive(Debug, Clone)]
+enum Entry {
+    /// Still present in the stripped image
+    Kept([u8; 4]),
+    /// Removed from the stripped image, stored in the sidecar
+    Removed(Chunk),
+    /// Added to the stripped image (e.g. sRGB replacing iCCP)
+    Added([u8; 4]),
+}
+
+/// Headers removed from an image by stripping, along with their position
+#[derive(Debug, Clone)]
+pub(crate) struct Sidecar {
+    bit_depth: u8,
+    color_type: u8,
+    palette_crc: u32,
+    entries: Vec<Entry>,
+}
+
+impl Sidecar {
+    /// Compare the original headers of an image with the ones left after stripping
+    ///
+    /// Returns `None` if nothing was removed.
+    pub(crate) fn new(stripped: &PngImage, original: &AuxHeaders) -> Option<Self> {
+        let mut kept = stripped.aux_headers.chunks().iter().peekable();
+        let mut entries = Vec::with_capacity(original.len());
+        for chunk in original.chunks() {
+            if kept.peek() == Some(&chunk) {
+                kept.next();
+                entries.push(Entry::Kept(chunk.name));
+            } else {
+                entries.push(Entry::Removed(chunk.clone()));
+            }
+        }
+        entries.extend(kept.map(|chunk| Entry::Added(chunk.name)));
+
+        if !entries.iter().any(|e| matches!(e, Entry::Removed(_))) {
+            return None;
+        }
+        Some(Self {
+            bit_depth: stripped.ihdr.bit_depth.as_u8(),
+            color_type: stripped.ihdr.color_type.png_header_code(),
+            palette_crc: palette_crc(stripped),
+            entries,
+        })
+    }
+
+    /// Encode the sidecar as a PNG-like stream of records
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut output = SIDECAR_SIGNATURE.to_vec();
+        let mut head = vec![SIDECAR_VERSION, self.bit_depth, self.color_type];
+        head.extend_from_slice(&self.palette_crc.to_be_bytes());
+        write_png_block(b"HEAD", &head, &mut output);
+        for entry in &self.entries {
+            match entry {
+                Entry::Kept(name) => write_png_block(b"KEEP", name, &mut output),
+                Entry::Removed(chunk) => {
+                    let mut data = chunk.name.to_vec();
+                    data.extend_from_slice(&chunk.data);
+                    write_png_block(b"DROP", &data, &mut output);
+                }
+                Entry::Added(name) => write_png_block(b"ADDS", name, &mut output),
+            }
+        }
+        write_png_block(b"IEND", &[], &mut output);
+        output
+    }
+
+    fn from_bytes(byte_data: &[u8], limits: &Limits) -> PngResult<Self> {
+        if byte_data.get(0..8) != Some(&SIDECAR_SIGNATURE) {
+            return Err(PngError::InvalidSidecar);
+        }
+        let mut byte_offset = 8;
+        let head = match parse_next_header(byte_data, &mut byte_offset, false)? {
+            Some(header) if &header.name == b"HEAD" && header.data.len() == 7 => header.data,
+            _ => return Err(PngError::ChunkMissing("HEAD")),
+        };
+        if head[0] != SIDECAR_VERSION {
+            return Err(PngError::UnsupportedSidecarVersion(head[0]));
+        }
+
+        let mut entries = Vec::new();
+        while let Some(header) = parse_next_header(byte_data, &mut byte_offset, false)? {
+            let (name, data) = match header.data.get(0..4) {
+                Some(name) => (name.try_into().unwrap(), &header.data[4..]),
+                None => return Err(PngError::InvalidData),
+            };
+            limits.check_chunk(&name, data.len())?;
+            entries.push(match &header.name {
+                b"KEEP" => Entry::Kept(name),
+                b"DROP" => Entry::Removed(Chunk {
+                    name,
+                    data: data.to_owned(),
+                }),
+                b"ADDS" => Entry::Added(name),
+                _ => return Err(PngError::InvalidData),
+            });
+        }
+
+        Ok(Self {
+            bit_depth: head[1],
+            color_type: head[2],
+            palette_crc: u32::from_be_bytes(head[3..7].try_into().unwrap()),
+            entries,
+        })
+    }
+}
+
+fn palette_crc(png: &PngImage) -> u32 {
+    png.palette.as_ref().map_or(0, |palette| {
+        let bytes: Vec<u8> = palette.iter().flat_map(|c| [c.r, c.g, c.b, c.a]).collect();
+        crc32(&bytes)
+    })
+}
+
+/// Path of the sidecar file belonging to a PNG file
+#[must_use]
+pub fn sidecar_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".meta");
+    PathBuf::from(name)
+}
+
+/// Write the sidecar for an output file, replacing any existing one
+pub(crate) fn write_sidecar(output_path: &Path, sidecar: &Sidecar) -> PngResult<()> {
+    let path = sidecar_path(output_path);
+    write_file(&path, &sidecar.to_bytes())?;
+    info!("Metadata: {}", path.display());
+    Ok(())
+}
+
+/// Put the headers stored in a sidecar file back into the image at the input path
+///
+/// The sidecar is looked up next to the input file, as written by `optimize`
+/// when `Options::sidecar` is set.
+pub fn restore_metadata(input: &InFile, output: &OutFile, limits: &Limits) -> PngResult<()> {
+    let input_path = input
+        .path()
+        .ok_or(PngError::FileRequired("Restoring metadata"))?;
+    info!("Restoring: {}", input);
+
+    let sidecar_file = sidecar_path(input_path);
+    let sidecar = fs::read(&sidecar_file).map_err(|e| PngError::io(Some(&sidecar_file), e))?;
+    let in_data = PngData::read_file(input_path)?;
+    let restored = restore_metadata_from_memory(&in_data, &sidecar, limits)?;
+
+    match output {
+        OutFile::StdOut => stdout()
+            .write_all(&restored)
+            .map_err(|e| PngError::io(None, e))?,
+        OutFile::Path(path) => {
+            let output_path = path.as_deref().unwrap_or(input_path);
+            write_file(output_path, &restored)?;
+            info!("Output: {}", output_path.display());
+        }
+    }
+    Ok(())
+}
+
+/// Put the headers stored in a sidecar back into an image, where both are already loaded in-memory
+///
+/// The image data itself is not recompressed. Headers that depend on the image format
+/// (such as bKGD) can only be restored if the format was not changed by optimization.
+pub fn restore_metadata_from_memory(
+    data: &[u8],
+    sidecar: &[u8],
+    limits: &Limits,
+) -> PngResult<Vec<u8>> {
+    let sidecar = Sidecar::from_bytes(sidecar, limits)?;
+    let opts = Options {
+        c2pa: C2PAPolicy::Keep,
+        trailing_data: TrailingData::Keep,
+        limits: *limits,
+        ..Options::default()
+    };
+    let mut png = PngData::from_slice_with_options(data, &opts)?;
+    let raw = Arc::make_mut(&mut png.raw);
+
+    let same_format = raw.ihdr.bit_depth.as_u8() == sidecar.bit_depth
+        && raw.ihdr.color_type.png_header_code() == sidecar.color_type
+        && palette_crc(raw) == sidecar.palette_crc;
+
+    let mut current = raw.aux_headers.chunks().to_vec();
+    for entry in &sidecar.entries {
+        if let Entry::Added(name) = entry {
+            if let Some(index) = current.iter().position(|c| &c.name == name) {
+                current.remove(index);
+            }
+        }
+    }
+
+    let mut current = current.into_iter();
+    let mut restored = Vec::with_capacity(sidecar.entries.len());
+    for entry in sidecar.entries {
+        match entry {
+            Entry::Kept(name) => match current.next() {
+                Some(chunk) if chunk.name == name => restored.push(chunk),
+                _ => return Err(PngError::SidecarMismatch),
+            },
+            Entry::Removed(chunk) => {
+                if same_format || !FORMAT_DEPENDENT_HEADERS.contains(&chunk.name) {
+                    restored.push(chunk);
+                } else {
+                    warn!(
+                        "Not restoring {} header: the image format has changed",
+                        String::from_utf8_lossy(&chunk.name)
+                    );
+                }
+            }
+            Entry::Added(_) => (),
+        }
+    }
+    restored.extend(current);
+    raw.aux_headers = restored.into();
+
+    Ok(png.output())
+}
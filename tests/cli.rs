@@ -0,0 +1,652 @@
+#![cfg(feature = "binary")]
+
+use oxipng::internal_tests::crc32;
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, Command, Output, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+#[cfg(feature = "filetime")]
+use std::time::SystemTime;
+use std::time::{Duration, Instant};
+
+/// A directory for the files of one test, removed again even if the test fails
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> TempDir {
+        let dir = env::temp_dir().join(format!("oxipng-cli-{}-{}", name, process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+
+    /// Copy a file from `tests/files` into the directory under `name`
+    fn copy(&self, fixture: &str, name: &str) -> PathBuf {
+        let path = self.join(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).unwrap();
+        }
+        fs::copy(Path::new("tests/files").join(fixture), &path).unwrap();
+        path
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.0).ok();
+    }
+}
+
+/// Run oxipng in `dir`, without a configuration file unless `--config` is given
+fn oxipng<S: AsRef<OsStr>>(dir: &Path, args: &[S]) -> Output {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_oxipng"));
+    if !args.iter().any(|arg| arg.as_ref() == "--config") {
+        command.arg("--no-config");
+    }
+    command.args(args).current_dir(dir).output().unwrap()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+/// The names of the chunks in the PNG file at `path`
+fn chunks(path: &Path) -> Vec<String> {
+    let data = fs::read(path).unwrap();
+    let mut names = Vec::new();
+    let mut offset = 8;
+    while offset + 8 <= data.len() {
+        let len = u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap()) as usize;
+        names.push(String::from_utf8_lossy(&data[offset + 4..offset + 8]).into_owned());
+        offset += 12 + len;
+    }
+    names
+}
+
+/// A running `oxipng --watch`, stopped when dropped
+struct Watch {
+    child: Child,
+    lines: Receiver<String>,
+}
+
+impl Watch {
+    /// Start watching in `dir`, once the watcher is ready
+    fn start(dir: &Path, args: &[&str]) -> Watch {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_oxipng"))
+            .args(["--no-config", "--watch"])
+            .args(args)
+            .current_dir(dir)
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let stderr = BufReader::new(child.stderr.take().unwrap());
+        let (tx, lines) = mpsc::channel();
+        thread::spawn(move || {
+            for line in stderr.lines() {
+                if tx.send(line.unwrap()).is_err() {
+                    break;
+                }
+            }
+        });
+        let watch = Watch { child, lines };
+        let ready = watch.lines_until(Duration::from_secs(10), |line| {
+            line.starts_with("Watching for changes")
+        });
+        assert!(ready
+            .last()
+            .map_or(false, |line| line.starts_with("Watching")));
+        watch
+    }
+
+    /// The lines written until one matches `stop`, or `timeout` passes
+    fn lines_until(&self, timeout: Duration, stop: impl Fn(&str) -> bool) -> Vec<String> {
+        let end = Instant::now() + timeout;
+        let mut lines = Vec::new();
+        while let Some(left) = end.checked_duration_since(Instant::now()) {
+            match self.lines.recv_timeout(left) {
+                Ok(line) => {
+                    let done = stop(&line);
+                    lines.push(line);
+                    if done {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+        lines
+    }
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        self.child.kill().ok();
+        self.child.wait().ok();
+    }
+}
+
+/// The files oxipng reported processing, sorted as they may be processed in any order
+fn processed(output: &Output) -> Vec<String> {
+    let mut files: Vec<_> = stderr(output)
+        .lines()
+        .filter_map(|line| line.strip_prefix("Processing: "))
+        .map(|path| path.replace('\\', "/"))
+        .collect();
+    files.sort();
+    files
+}
+
+#[test]
+fn cache_skips_optimized_files() {
+    let dir = TempDir::new("cache-skip");
+    dir.copy("fully_optimized.png", "image.png");
+
+    let output = oxipng(&dir, &["--cache", "cache", "image.png"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(!stderr(&output).contains("(cached)"));
+
+    let output = oxipng(&dir, &["--cache", "cache", "image.png"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("image.png: already optimized (cached), skipping"));
+
+    // A lower effort is covered by the earlier result
+    let output = oxipng(&dir, &["--cache", "cache", "-o", "1", "image.png"]);
+    assert!(stderr(&output).contains("(cached)"));
+
+    // Options that change the output miss the cache
+    let output = oxipng(&dir, &["--cache", "cache", "--strip", "safe", "image.png"]);
+    assert!(!stderr(&output).contains("(cached)"));
+
+    let output = oxipng(&dir, &["--cache", "cache", "--clear-cache", "image.png"]);
+    assert!(!stderr(&output).contains("(cached)"));
+}
+
+#[test]
+fn cache_file_is_stable() {
+    let dir = TempDir::new("cache-stable");
+    dir.copy("fully_optimized.png", "image.png");
+
+    let output = oxipng(&dir, &["--cache", "cache", "image.png"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    // The hashes must not change between builds or platforms, or every cache is invalidated
+    let contents = fs::read_to_string(dir.join("cache")).unwrap();
+    let mut lines = contents.lines();
+    assert_eq!(
+        lines.next(),
+        Some(concat!(
+            "oxipng-cache ",
+            env!("CARGO_PKG_VERSION"),
+            " sha256"
+        ))
+    );
+    let fields: Vec<&str> = lines.next().unwrap().split(' ').collect();
+    assert_eq!(
+        fields[..3],
+        [
+            // SHA-256 of tests/files/fully_optimized.png
+            "ebf4f635a17d10d6eb46ba680b70142419aa3220f228001a036d311a22ee9d2a",
+            "67",
+            // SHA-256 of the default options, "interlace=keep strip=none c2pa=skip ..."
+            "debd22d04cfc68f8307988e0b3150eed1d3aa17121a8485fe4493b694da4a9d7",
+        ]
+    );
+    assert_eq!(lines.next(), None);
+}
+
+#[test]
+fn cache_invalid_entries() {
+    let dir = TempDir::new("cache-invalid");
+    dir.copy("fully_optimized.png", "image.png");
+    let header = concat!("oxipng-cache ", env!("CARGO_PKG_VERSION"), " sha256");
+    fs::write(dir.join("cache"), format!("{}\nnot an entry\n", header)).unwrap();
+
+    let output = oxipng(&dir, &["--cache", "cache", "image.png"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("ignoring invalid cache entry"));
+
+    // Including entries with an empty or non-ASCII compression field
+    let hash = "0".repeat(64);
+    let entries = format!("{0}\n{1} 1 {1} \n{1} 1 {1} \u{e9}0:1f:fast\n", header, hash);
+    fs::write(dir.join("cache"), entries).unwrap();
+    let output = oxipng(&dir, &["--cache", "cache", "image.png"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        stderr(&output)
+            .matches("ignoring invalid cache entry")
+            .count(),
+        2
+    );
+
+    // A cache from another version is discarded
+    fs::write(dir.join("cache"), "oxipng-cache 0.0.0\n").unwrap();
+    let output = oxipng(&dir, &["--cache", "cache", "image.png"]);
+    assert!(stderr(&output).contains("cache is from another version of oxipng"));
+    assert!(!stderr(&output).contains("(cached)"));
+}
+
+#[test]
+fn name_template_placeholders_in_name() {
+    let dir = TempDir::new("template-braces");
+    dir.copy("fully_optimized.png", "a{ext}.png");
+
+    let output = oxipng(&dir, &["--name-template", "{stem}.min.{ext}", "a{ext}.png"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(dir.join("a{ext}.min.png").is_file());
+    assert!(!dir.join("apng.min.png").exists());
+}
+
+#[test]
+fn name_template_dir() {
+    let dir = TempDir::new("template-dir");
+    dir.copy("fully_optimized.png", "in/image.png");
+
+    let output = oxipng(
+        &dir,
+        &["--name-template", "{dir}/opt/{name}", "in/image.png"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(dir.join("in/opt/image.png").is_file());
+
+    let output = oxipng(&dir, &["--name-template", "{nope}.png", "in/image.png"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("Unknown placeholder {nope}"));
+}
+
+#[cfg(unix)]
+#[test]
+fn name_template_non_utf8() {
+    use std::os::unix::ffi::OsStrExt;
+
+    // Found in a directory, as the command line only takes UTF-8
+    let dir = TempDir::new("template-non-utf8");
+    fs::create_dir(dir.join("in")).unwrap();
+    let input = dir.join("in").join(OsStr::from_bytes(b"caf\xe9.png"));
+    fs::copy("tests/files/fully_optimized.png", input).unwrap();
+
+    let output = oxipng(&dir, &["-r", "--name-template", "{stem}.min.{ext}", "in"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let output = dir.join("in").join(OsStr::from_bytes(b"caf\xe9.min.png"));
+    assert!(output.is_file());
+}
+
+#[test]
+fn duplicate_outputs_are_skipped() {
+    let dir = TempDir::new("duplicate-outputs");
+    dir.copy("fully_optimized.png", "a.png");
+    dir.copy("fully_optimized.png", "a.apng");
+
+    let output = oxipng(
+        &dir,
+        &["--name-template", "{stem}.min.png", "a.png", "a.apng"],
+    );
+    assert!(stderr(&output)
+        .contains("a.apng: output ./a.min.png is already written by another file, skipping"));
+    assert!(dir.join("a.min.png").is_file());
+
+    dir.copy("fully_optimized.png", "sub/a.png");
+    let output = oxipng(&dir, &["--dir", "out", "a.png", "sub/a.png"]);
+    assert!(stderr(&output)
+        .contains("sub/a.png: output out/a.png is already written by another file, skipping"));
+    assert_eq!(fs::read_dir(dir.join("out")).unwrap().count(), 1);
+}
+
+#[test]
+fn no_files_fails() {
+    let dir = TempDir::new("no-files");
+    fs::create_dir(dir.join("empty")).unwrap();
+
+    let output = oxipng(&dir, &["-r", "empty"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("No files to process"));
+}
+
+#[test]
+fn timeout_exit_code() {
+    let dir = TempDir::new("timeout");
+    dir.copy("rgb_16_should_be_grayscale_8.png", "image.png");
+
+    let output = oxipng(&dir, &["--timeout", "0", "--cache", "cache", "image.png"]);
+    assert_eq!(output.status.code(), Some(4), "{}", stderr(&output));
+    assert!(stderr(&output).contains("Timed out"));
+
+    // A result found in a hurry is not cached
+    let output = oxipng(&dir, &["--cache", "cache", "image.png"]);
+    assert!(!stderr(&output).contains("(cached)"));
+}
+
+#[test]
+fn watch_checks_override_backups() {
+    let dir = TempDir::new("watch-backup");
+    dir.copy("fully_optimized.png", "images/a.png");
+    fs::write(
+        dir.join("oxipng.toml"),
+        "[overrides.\"images/**\"]\nbackup = true\nbackup-suffix = \".old.png\"\n",
+    )
+    .unwrap();
+
+    // Exits before watching, as the backups of the override would be optimized again
+    let output = oxipng(&dir, &["--config", "oxipng.toml", "--watch", "images"]);
+    assert_eq!(output.status.code(), Some(2), "{}", stderr(&output));
+    assert!(stderr(&output).contains("Backups ending in .old.png"));
+}
+
+#[test]
+fn filter_sizes() {
+    let dir = TempDir::new("filter-sizes");
+    dir.copy("fully_optimized.png", "in/small.png");
+    dir.copy("rgba_8_should_be_rgba_8.png", "in/medium.png");
+    dir.copy("rgb_16_should_be_grayscale_8.png", "in/large.png");
+
+    let output = oxipng(&dir, &["-P", "-r", "--min-size", "100", "in"]);
+    assert_eq!(processed(&output), ["in/large.png", "in/medium.png"]);
+
+    // Suffixes are powers of 1024, in either case
+    let output = oxipng(&dir, &["-P", "-r", "--max-size", "32k", "in"]);
+    assert_eq!(processed(&output), ["in/small.png"]);
+    let output = oxipng(
+        &dir,
+        &["-P", "-r", "--min-size", "1K", "--max-size", "1m", "in"],
+    );
+    assert_eq!(processed(&output), ["in/large.png", "in/medium.png"]);
+    let output = oxipng(&dir, &["-P", "-r", "--min-size", "1G", "in"]);
+    assert!(processed(&output).is_empty());
+
+    // Files given directly are filtered too
+    let output = oxipng(
+        &dir,
+        &["-P", "--max-size", "100", "in/small.png", "in/large.png"],
+    );
+    assert_eq!(processed(&output), ["in/small.png"]);
+
+    for size in ["2x", "k", "1.5M", "99999999999999999999"] {
+        let output = oxipng(&dir, &["-P", "-r", "--min-size", size, "in"]);
+        assert_eq!(output.status.code(), Some(2), "{}", size);
+        assert!(stderr(&output).contains(&format!("Invalid size {}", size)));
+    }
+}
+
+#[test]
+fn filter_globs_and_extensions() {
+    let dir = TempDir::new("filter-globs");
+    dir.copy("fully_optimized.png", "in/a.png");
+    dir.copy("fully_optimized.png", "in/b.PNG");
+    dir.copy("fully_optimized.png", "in/c.apng");
+    dir.copy("fully_optimized.png", "in/d.img");
+    dir.copy("fully_optimized.png", "in/sub/e.png");
+
+    let output = oxipng(&dir, &["-P", "-r", "in"]);
+    assert_eq!(
+        processed(&output),
+        ["in/a.png", "in/b.PNG", "in/c.apng", "in/sub/e.png"]
+    );
+
+    let output = oxipng(&dir, &["-P", "-r", "--ext", ".img,png", "in"]);
+    assert_eq!(
+        processed(&output),
+        ["in/a.png", "in/b.PNG", "in/d.img", "in/sub/e.png"]
+    );
+
+    let output = oxipng(&dir, &["-P", "-r", "--exclude", "sub/**", "in"]);
+    assert_eq!(processed(&output), ["in/a.png", "in/b.PNG", "in/c.apng"]);
+
+    let output = oxipng(&dir, &["-P", "-r", "--include", "sub/**", "in"]);
+    assert_eq!(processed(&output), ["in/sub/e.png"]);
+
+    let output = oxipng(&dir, &["-P", "-r", "--max-depth", "1", "in"]);
+    assert_eq!(processed(&output), ["in/a.png", "in/b.PNG", "in/c.apng"]);
+
+    let output = oxipng(&dir, &["-P", "-r", "--exclude", "[", "in"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[cfg(feature = "filetime")]
+#[test]
+fn filter_modified_since() {
+    let dir = TempDir::new("filter-modified");
+    let old = dir.copy("fully_optimized.png", "in/old.png");
+    dir.copy("fully_optimized.png", "in/new.png");
+    let two_hours_ago = SystemTime::now() - Duration::from_secs(2 * 60 * 60);
+    filetime::set_file_mtime(&old, filetime::FileTime::from_system_time(two_hours_ago)).unwrap();
+
+    let output = oxipng(&dir, &["-P", "-r", "--modified-since", "1h", "in"]);
+    assert_eq!(processed(&output), ["in/new.png"]);
+    let output = oxipng(&dir, &["-P", "-r", "--modified-since", "90min", "in"]);
+    assert_eq!(processed(&output), ["in/new.png"]);
+    let output = oxipng(&dir, &["-P", "-r", "--modified-since", "3h", "in"]);
+    assert_eq!(processed(&output), ["in/new.png", "in/old.png"]);
+    for age in ["10800s", "1d", "1w"] {
+        let output = oxipng(&dir, &["-P", "-r", "--modified-since", age, "in"]);
+        assert_eq!(processed(&output).len(), 2, "{}", age);
+    }
+
+    // A file is taken as the time it was last modified
+    let output = oxipng(&dir, &["-P", "-r", "--modified-since", "in/new.png", "in"]);
+    assert_eq!(processed(&output), ["in/new.png"]);
+
+    // Minutes are `min`, as `m` would be confused with MiB in sizes
+    for time in ["90m", "1y", "missing.png", "99999999999999999999w"] {
+        let output = oxipng(&dir, &["-P", "-r", "--modified-since", time, "in"]);
+        assert_eq!(output.status.code(), Some(2), "{}", time);
+    }
+}
+
+#[test]
+fn watch_optimizes_changed_files() {
+    let dir = TempDir::new("watch");
+    fs::create_dir_all(dir.join("in/sub")).unwrap();
+    let watch = Watch::start(&dir, &["-r", "--exclude", "sub/**", "in"]);
+
+    dir.copy("rgb_16_should_be_grayscale_8.png", "in/sub/skipped.png");
+    dir.copy("rgb_16_should_be_grayscale_8.png", "in/skipped.txt");
+    let image = dir.copy("rgb_16_should_be_grayscale_8.png", "in/image.png");
+    let original = fs::read(&image).unwrap();
+    let done = |line: &str| line.starts_with("Output: ") || line.starts_with("Error");
+    let lines = watch.lines_until(Duration::from_secs(30), done);
+    assert!(
+        lines.iter().any(|line| line == "Processing: in/image.png"),
+        "{:?}",
+        lines
+    );
+    assert!(fs::read(&image).unwrap().len() < original.len());
+
+    // Writing the output is not taken as another change, and the other files are left alone
+    let lines = watch.lines_until(Duration::from_secs(3), |_| false);
+    assert!(
+        !lines.iter().any(|line| line.starts_with("Processing: ")),
+        "{:?}",
+        lines
+    );
+    assert_eq!(fs::read(dir.join("in/sub/skipped.png")).unwrap(), original);
+
+    // Changing the file again is picked up
+    dir.copy("rgb_16_should_be_grayscale_8.png", "in/image.png");
+    let lines = watch.lines_until(Duration::from_secs(30), done);
+    assert!(
+        lines.iter().any(|line| line == "Processing: in/image.png"),
+        "{:?}",
+        lines
+    );
+}
+
+#[test]
+fn watch_needs_recursion_for_directories() {
+    let dir = TempDir::new("watch-dir");
+    fs::create_dir(dir.join("in")).unwrap();
+
+    let output = oxipng(&dir, &["--watch", "in"]);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr(&output));
+    assert!(stderr(&output).contains("in is a directory, use -r to watch it"));
+}
+
+/// The configuration file for the `config_*` tests
+const CONFIG: &str = r#"
+[options]
+strip = "all"
+opt = 1
+
+[profiles.text]
+keep = ["tEXt", "iTXt"]
+
+[overrides."**"]
+strip = "safe"
+
+[overrides."text/**"]
+keep = ["tEXt"]
+"#;
+
+#[test]
+fn config_precedence() {
+    let dir = TempDir::new("config");
+    fs::write(dir.join("oxipng.toml"), CONFIG).unwrap();
+    dir.copy("sidecar.png", "image.png");
+    dir.copy("sidecar.png", "text/image.png");
+    let has = |path: &str, chunk: &str| chunks(&dir.join(path)).iter().any(|c| c == chunk);
+
+    // The override for every file replaces the strip setting of [options], and the later
+    // override replaces both
+    let output = oxipng(
+        &dir,
+        &["--config", "oxipng.toml", "--dir", "out", "-r", "."],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(has("out/image.png", "pHYs") && !has("out/image.png", "tEXt"));
+    assert!(has("out/text/image.png", "tEXt") && !has("out/text/image.png", "iTXt"));
+
+    // The profile applies before the overrides
+    let args = [
+        "--config",
+        "oxipng.toml",
+        "--profile",
+        "text",
+        "--dir",
+        "out2",
+        "-r",
+        ".",
+    ];
+    let output = oxipng(&dir, &args);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(!has("out2/text/image.png", "iTXt"));
+
+    // The command line takes precedence over everything
+    let args = [
+        "--config",
+        "oxipng.toml",
+        "--strip",
+        "all",
+        "--dir",
+        "out3",
+        "-r",
+        "text",
+    ];
+    let output = oxipng(&dir, &args);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(!has("out3/image.png", "tEXt"));
+}
+
+#[test]
+fn config_profile_without_overrides() {
+    let dir = TempDir::new("config-profile");
+    let config = CONFIG.split("[overrides").next().unwrap();
+    fs::write(dir.join("oxipng.toml"), config).unwrap();
+    dir.copy("sidecar.png", "image.png");
+
+    let output = oxipng(
+        &dir,
+        &["--config", "oxipng.toml", "--out", "plain.png", "image.png"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(!chunks(&dir.join("plain.png")).contains(&"tEXt".to_owned()));
+
+    let args = [
+        "--config",
+        "oxipng.toml",
+        "--profile",
+        "text",
+        "--out",
+        "text.png",
+        "image.png",
+    ];
+    let output = oxipng(&dir, &args);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let chunks = chunks(&dir.join("text.png"));
+    assert!(chunks.contains(&"tEXt".to_owned()) && chunks.contains(&"iTXt".to_owned()));
+}
+
+#[test]
+fn config_errors() {
+    let dir = TempDir::new("config-errors");
+    dir.copy("fully_optimized.png", "image.png");
+    let run = |config: &str, args: &[&str]| {
+        fs::write(dir.join("oxipng.toml"), config).unwrap();
+        let mut all = vec!["--config", "oxipng.toml", "-P"];
+        all.extend_from_slice(args);
+        all.push("image.png");
+        let output = oxipng(&dir, &all);
+        assert_eq!(output.status.code(), Some(2), "{}", stderr(&output));
+        stderr(&output)
+    };
+
+    assert!(run(CONFIG, &["--profile", "missing"]).contains("no profile named missing"));
+    assert!(run("[options]\nstrip = \"all\"\nkeep = [\"tEXt\"]\n", &[])
+        .contains("strip and keep can't be used together"));
+    assert!(run("[profiles.fast]\nzc = 5\nzopfli = true\n", &[])
+        .contains("profile fast: zc and zopfli can't be used together"));
+    assert!(run("[overrides.\"**\"]\nopt = 9\n", &[]).contains("override **: "));
+    assert!(run("[options]\nunknown = 1\n", &[]).contains("unknown"));
+}
+
+#[test]
+fn inspect_applies_limits() {
+    let dir = TempDir::new("inspect-limits");
+    dir.copy("decompression_bomb.png", "bomb.png");
+
+    let output = oxipng(&dir, &["--inspect", "--max-memory", "1000000", "bomb.png"]);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr(&output));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Image data: "));
+}
+
+#[test]
+fn lint_applies_limits() {
+    let dir = TempDir::new("lint-limits");
+    dir.copy("decompression_bomb.png", "bomb.png");
+
+    let output = oxipng(&dir, &["--lint", "--max-memory", "1000000", "bomb.png"]);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr(&output));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("invalid-image-data"));
+}
+
+#[test]
+fn convert_profiles_reports_invalid() {
+    let dir = TempDir::new("convert-invalid");
+    let mut data = fs::read("tests/files/fully_optimized.png").unwrap();
+    let iend = data.len() - 12;
+    let text = b"Raw profile type exif\0\nexif\n      4\n45786xyz\n";
+    let mut chunk = (text.len() as u32).to_be_bytes().to_vec();
+    chunk.extend_from_slice(b"tEXt");
+    chunk.extend_from_slice(text);
+    let crc = crc32(&chunk[4..]);
+    chunk.extend_from_slice(&crc.to_be_bytes());
+    data.splice(iend..iend, chunk);
+    fs::write(dir.join("image.png"), data).unwrap();
+
+    // The profile is left as it is, and the problem is reported with its keyword
+    let output = oxipng(&dir, &["-v", "--convert-profiles", "image.png"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output)
+        .contains("Raw profile \"Raw profile type exif\" is invalid: malformed hex data"));
+    assert!(chunks(&dir.join("image.png")).contains(&"tEXt".to_owned()));
+}
@@ -1,14 +1,17 @@
 use indexmap::IndexSet;
-use oxipng::{internal_tests::*, Interlacing, RowFilter};
-use oxipng::{InFile, OutFile};
+use oxipng::{
+    internal_tests::*, Backup, C2PAPolicy, Interlacing, LinkPolicy, MinSavings, PngError,
+    RowFilter, TrailingData, VerifyMode,
+};
+use oxipng::{InFile, Limits, OutFile, Outcome};
 #[cfg(feature = "filetime")]
 use std::cell::RefCell;
-use std::fs::remove_file;
+use std::fs::{self, remove_file};
 use std::num::NonZeroU8;
-#[cfg(feature = "filetime")]
 use std::ops::Deref;
 use std::path::Path;
 use std::path::PathBuf;
+use std::time::Duration;
 
 fn get_opts(input: &Path) -> (OutFile, oxipng::Options) {
     let mut options = oxipng::Options {
@@ -25,6 +28,42 @@ fn get_opts(input: &Path) -> (OutFile, oxipng::Options) {
     )
 }
 
+/// A directory for the files of one test, removed again even if the test fails
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> TempDir {
+        let dir = std::env::temp_dir().join(format!("oxipng-{}-{}", name, std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+
+    /// Copy a file from `tests/files` into the directory under `name`
+    fn copy(&self, fixture: &str, name: &str) -> PathBuf {
+        let path = self.join(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).unwrap();
+        }
+        fs::copy(Path::new("tests/files").join(fixture), &path).unwrap();
+        path
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.0).ok();
+    }
+}
+
 /// Add callback to allow checks before the output file is deleted again
 fn test_it_converts_callbacks<CBPRE, CBPOST>(
     input: PathBuf,
@@ -218,6 +257,29 @@ fn strip_headers_list() {
     remove_file(output).ok();
 }
 
+#[test]
+fn strip_headers_min_savings() {
+    let input = PathBuf::from("tests/files/strip_headers_list.png");
+    let output = OutFile::Path(Some(input.with_extension("min_savings.out.png")));
+    let (_, mut opts) = get_opts(&input);
+    opts.force = false;
+    opts.min_savings = Some(MinSavings::Percent(100.0));
+    opts.strip = Headers::Strip(vec!["tEXt".to_owned()]);
+
+    match oxipng::optimize(&InFile::Path(input), &output, &opts) {
+        Ok(_) => (),
+        Err(x) => panic!("{}", x),
+    };
+    let output = output.path().unwrap();
+    let png = PngData::new(output, opts.fix_errors);
+    remove_file(output).ok();
+
+    // The requested stripping is done however little it saves
+    let png = png.unwrap();
+    assert!(!png.raw.aux_headers.contains_key(b"tEXt"));
+    assert!(png.raw.aux_headers.contains_key(b"iTXt"));
+}
+
 #[test]
 fn strip_headers_safe() {
     let input = PathBuf::from("tests/files/strip_headers_safe.png");
@@ -252,6 +314,36 @@ fn strip_headers_safe() {
     remove_file(output).ok();
 }
 
+#[test]
+fn strip_headers_safe_hdr() {
+    let input = PathBuf::from("tests/files/strip_headers_safe_hdr.png");
+    let (output, mut opts) = get_opts(&input);
+    opts.strip = Headers::Safe;
+
+    match oxipng::optimize(&InFile::Path(input), &output, &opts) {
+        Ok(_) => (),
+        Err(x) => panic!("{}", x),
+    };
+    let output = output.path().unwrap();
+    assert!(output.exists());
+
+    let data = std::fs::read(output).unwrap();
+    remove_file(output).ok();
+
+    let mut names = Vec::new();
+    let mut byte_offset = 8;
+    while let Some(header) = parse_next_header(&data, &mut byte_offset, false).unwrap() {
+        names.push(header.name);
+    }
+    let position = |name: &[u8; 4]| names.iter().position(|n| n == name);
+
+    assert_eq!(position(b"tEXt"), None);
+    let plte = position(b"PLTE").unwrap();
+    for name in [b"cICP", b"mDCv", b"cLLi"] {
+        assert!(position(name).unwrap() < plte);
+    }
+}
+
 #[test]
 fn strip_headers_all() {
     let input = PathBuf::from("tests/files/strip_headers_all.png");
@@ -350,6 +442,28 @@ fn interlacing_0_to_1() {
     remove_file(output).ok();
 }
 
+#[test]
+fn interlacing_0_to_1_larger() {
+    let input = PathBuf::from("tests/files/fully_optimized.png");
+    let output = OutFile::Path(Some(input.with_extension("interlaced.out.png")));
+    let (_, mut opts) = get_opts(&input);
+    opts.force = false;
+    opts.interlace = Some(Interlacing::Adam7);
+
+    match oxipng::optimize(&InFile::Path(input.clone()), &output, &opts) {
+        Ok(_) => (),
+        Err(x) => panic!("{}", x),
+    };
+    let output = output.path().unwrap();
+    let png = PngData::new(output, opts.fix_errors);
+    let output_len = fs::metadata(output).map(|m| m.len());
+    remove_file(output).ok();
+
+    // The interlaced image is larger, but was requested
+    assert_eq!(png.unwrap().raw.ihdr.interlaced, Interlacing::Adam7);
+    assert!(output_len.unwrap() > fs::metadata(&input).unwrap().len());
+}
+
 #[test]
 fn interlacing_1_to_0() {
     let input = PathBuf::from("tests/files/interlacing_1_to_0.png");
@@ -545,6 +659,49 @@ fn preserve_attrs() {
     // TODO: Actually check permissions
 }
 
+#[cfg(target_os = "linux")]
+#[test]
+fn preserve_xattrs() {
+    let dir = TempDir::new("xattrs");
+    let path = dir.copy("rgba_8_should_be_rgba_8.png", "image.png");
+    let url: &[u8] = b"https://example.com/image.png";
+    if let Err(e) = xattr::set(&path, "user.xdg.origin.url", url) {
+        // Not all file systems support user attributes
+        eprintln!("skipping: {}", e);
+        return;
+    }
+
+    let (_, mut opts) = get_opts(&path);
+    opts.preserve_attrs = true;
+    let result = oxipng::optimize(&InFile::Path(path.clone()), &OutFile::Path(None), &opts);
+    let preserved = xattr::get(&path, "user.xdg.origin.url");
+
+    result.unwrap();
+    assert_eq!(preserved.unwrap().as_deref(), Some(url));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn replace_keeps_xattrs() {
+    let dir = TempDir::new("replace-xattrs");
+    let path = dir.copy("rgba_8_should_be_rgba_8.png", "image.png");
+    let url: &[u8] = b"https://example.com/image.png";
+    if let Err(e) = xattr::set(&path, "user.xdg.origin.url", url) {
+        // Not all file systems support user attributes
+        eprintln!("skipping: {}", e);
+        return;
+    }
+
+    // The file is replaced by a new one, which still gets the attributes of the old one
+    let (_, opts) = get_opts(&path);
+    assert!(!opts.preserve_attrs);
+    let result = oxipng::optimize(&InFile::Path(path.clone()), &OutFile::Path(None), &opts);
+    let preserved = xattr::get(&path, "user.xdg.origin.url");
+
+    result.unwrap();
+    assert_eq!(preserved.unwrap().as_deref(), Some(url));
+}
+
 #[test]
 fn fix_errors() {
     let input = PathBuf::from("tests/files/fix_errors.png");
@@ -578,6 +735,572 @@ fn fix_errors() {
     remove_file(output).ok();
 }
 
+#[test]
+fn fix_errors_truncated() {
+    let input = PathBuf::from("tests/files/fix_errors_truncated.png");
+    let (output, mut opts) = get_opts(&input);
+
+    assert!(PngData::new(&input, opts.fix_errors).is_err());
+
+    opts.fix_errors = true;
+    let png = PngData::new(&input, opts.fix_errors).unwrap();
+    assert_eq!(png.repairs.len(), 4);
+    assert!(matches!(png.repairs[0], Repair::SkippedBytes(_, 7)));
+    assert_eq!(png.repairs[1], Repair::TruncatedChunk);
+    assert_eq!(png.repairs[2], Repair::MissingIend);
+    assert!(matches!(png.repairs[3], Repair::TruncatedImageData(_, 784)));
+
+    // The rows after the recovered data repeat the last row, which may be partly recovered
+    let recovered = match png.repairs[3] {
+        Repair::TruncatedImageData(recovered, _) => recovered,
+        _ => unreachable!(),
+    };
+    let rows: Vec<_> = png.raw.data.chunks(48).collect();
+    assert_eq!(rows.len(), 16);
+    let last = recovered / 49;
+    assert!(last < 15);
+    for row in &rows[last + 1..] {
+        assert_eq!(row, &rows[last]);
+    }
+
+    match oxipng::optimize(&InFile::Path(input), &output, &opts) {
+        Ok(_) => (),
+        Err(x) => panic!("{}", x),
+    };
+    let output = output.path().unwrap();
+    let png = match PngData::new(output, false) {
+        Ok(x) => x,
+        Err(x) => {
+            remove_file(output).ok();
+            panic!("{}", x)
+        }
+    };
+
+    assert!(png.repairs.is_empty());
+    assert_eq!(png.raw.ihdr.width, 16);
+    assert_eq!(png.raw.ihdr.height, 16);
+    assert_eq!(
+        png.raw.aux_headers.get(b"tEXt").unwrap(),
+        b"Comment\0damaged"
+    );
+
+    remove_file(output).ok();
+}
+
+#[test]
+fn duplicate_headers_last_wins() {
+    let mut data = fs::read("tests/files/fully_optimized.png").unwrap();
+    let iend = data.len() - 12;
+    let mut chunks = Vec::new();
+    let last = 100000u32.to_be_bytes();
+    for (name, data) in [
+        (b"gAMA", &45455u32.to_be_bytes()[..]),
+        (b"tEXt", b"Comment\0first"),
+        (b"gAMA", &last),
+        (b"tEXt", b"Comment\0second"),
+    ] {
+        chunks.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        let start = chunks.len();
+        chunks.extend_from_slice(name);
+        chunks.extend_from_slice(data);
+        let crc = crc32(&chunks[start..]);
+        chunks.extend_from_slice(&crc.to_be_bytes());
+    }
+    data.splice(iend..iend, chunks);
+
+    let png = PngData::from_slice(&data, false).unwrap();
+    let gammas: Vec<_> = png
+        .raw
+        .aux_headers
+        .iter()
+        .filter(|(name, _)| *name == b"gAMA")
+        .collect();
+    assert_eq!(gammas.len(), 1);
+    assert_eq!(gammas[0].1, &last);
+
+    // Writing both gAMA headers would fail verification, as the decoder rejects them
+    let opts = oxipng::Options {
+        verify: VerifyMode::Strict,
+        ..oxipng::Options::from_preset(0)
+    };
+    let output = oxipng::optimize_from_memory(&data, &opts).unwrap();
+    let png = PngData::from_slice(&output, false).unwrap();
+    assert_eq!(png.raw.aux_headers.get(b"gAMA").unwrap(), &last);
+    // Text headers may be repeated, and are all kept
+    let texts: Vec<_> = png
+        .raw
+        .aux_headers
+        .iter()
+        .filter(|(name, _)| *name == b"tEXt")
+        .map(|(_, data)| data.as_slice())
+        .collect();
+    assert_eq!(texts, [&b"Comment\0first"[..], b"Comment\0second"]);
+}
+
+#[test]
+fn fix_errors_palette() {
+    let input = PathBuf::from("tests/files/fix_errors_palette.png");
+    let (output, mut opts) = get_opts(&input);
+
+    assert!(PngData::new(&input, opts.fix_errors).is_err());
+
+    opts.fix_errors = true;
+    let png = PngData::new(&input, opts.fix_errors).unwrap();
+    assert_eq!(
+        png.repairs,
+        vec![
+            Repair::SplitImageData,
+            Repair::BadAdler32,
+            Repair::PaletteIndicesClamped(3)
+        ]
+    );
+    assert!(png.raw.data.iter().all(|&i| i < 2));
+
+    match oxipng::optimize(&InFile::Path(input), &output, &opts) {
+        Ok(_) => (),
+        Err(x) => panic!("{}", x),
+    };
+    let output = output.path().unwrap();
+    let png = match PngData::new(output, false) {
+        Ok(x) => x,
+        Err(x) => {
+            remove_file(output).ok();
+            panic!("{}", x)
+        }
+    };
+
+    assert!(png.repairs.is_empty());
+
+    remove_file(output).ok();
+}
+
+#[test]
+fn timed_out_outcome() {
+    let input = PathBuf::from("tests/files/rgb_16_should_be_grayscale_8.png");
+    let (output, mut opts) = get_opts(&input);
+    opts.timeout = Some(Duration::ZERO);
+
+    let result = oxipng::optimize_with_outcome(&InFile::Path(input), &output, &opts);
+    remove_file(output.path().unwrap()).ok();
+    assert_eq!(result.unwrap(), Outcome::TimedOut);
+}
+
+#[test]
+fn fix_errors_verify() {
+    let input = PathBuf::from("tests/files/fix_errors_truncated.png");
+    let (output, mut opts) = get_opts(&input);
+    opts.fix_errors = true;
+    opts.verify = VerifyMode::Strict;
+
+    // The output is compared with the repaired image, as the damaged input can't be decoded
+    let result = oxipng::optimize(&InFile::Path(input), &output, &opts);
+    remove_file(output.path().unwrap()).ok();
+    result.unwrap();
+}
+
+#[test]
+fn fix_errors_palette_padding() {
+    let input = PathBuf::from("tests/files/fix_errors_palette_padding.png");
+
+    // Only one index is out of range, the set padding bits at the end of each row are not indices
+    let png = PngData::new(&input, true).unwrap();
+    assert_eq!(png.repairs, vec![Repair::PaletteIndicesClamped(1)]);
+    assert_eq!(png.raw.data, vec![0b0001_0111, 0b0000_0011]);
+}
+
+#[test]
+fn fix_errors_crc_only() {
+    let input = PathBuf::from("tests/files/fix_errors_crc.png");
+    let (output, mut opts) = get_opts(&input);
+    opts.fix_errors = true;
+    opts.force = false;
+
+    let png = PngData::new(&input, opts.fix_errors).unwrap();
+    assert_eq!(png.repairs, vec![Repair::CrcMismatch(*b"IDAT")]);
+
+    // No pixel data was salvaged, so the original is kept as it isn't larger
+    oxipng::optimize(&InFile::Path(input.clone()), &output, &opts).unwrap();
+    let output = output.path().unwrap();
+    let result = fs::read(output);
+    remove_file(output).ok();
+    assert_eq!(result.unwrap(), fs::read(&input).unwrap());
+}
+
+#[test]
+fn c2pa_skip() {
+    let input = PathBuf::from("tests/files/c2pa_manifest.png");
+    let (output, opts) = get_opts(&input);
+
+    assert!(matches!(
+        PngData::new_with_options(&input, &opts),
+        Err(PngError::C2PAProtected)
+    ));
+
+    let outcome = match oxipng::optimize_with_outcome(&InFile::Path(input.clone()), &output, &opts)
+    {
+        Ok(x) => x,
+        Err(x) => panic!("{}", x),
+    };
+    let output = output.path().unwrap();
+    let written = fs::read(output);
+    remove_file(output).ok();
+
+    // The separate output gets the image unchanged
+    assert_eq!(outcome, Outcome::Skipped);
+    assert_eq!(written.unwrap(), fs::read(&input).unwrap());
+}
+
+#[test]
+fn c2pa_strip() {
+    let input = PathBuf::from("tests/files/c2pa_manifest.png");
+    let (output, mut opts) = get_opts(&input);
+    opts.c2pa = C2PAPolicy::Strip;
+
+    // The manifest is only removed when optimizing, so a sidecar can keep a copy
+    let png = PngData::new_with_options(&input, &opts).unwrap();
+
+    assert!(png.raw.aux_headers.contains_key(b"caBX"));
+
+    match oxipng::optimize(&InFile::Path(input), &output, &opts) {
+        Ok(_) => (),
+        Err(x) => panic!("{}", x),
+    };
+    let output = output.path().unwrap();
+    assert!(output.exists());
+
+    let png = match PngData::new(output, opts.fix_errors) {
+        Ok(x) => x,
+        Err(x) => {
+            remove_file(output).ok();
+            panic!("{}", x)
+        }
+    };
+
+    assert!(!png.raw.aux_headers.contains_key(b"caBX"));
+
+    remove_file(output).ok();
+}
+
+#[test]
+fn c2pa_keep() {
+    let input = PathBuf::from("tests/files/c2pa_manifest.png");
+    let (output, mut opts) = get_opts(&input);
+    opts.c2pa = C2PAPolicy::Keep;
+
+    match oxipng::optimize(&InFile::Path(input), &output, &opts) {
+        Ok(_) => (),
+        Err(x) => panic!("{}", x),
+    };
+    let output = output.path().unwrap();
+    assert!(output.exists());
+
+    let png = match PngData::new(output, opts.fix_errors) {
+        Ok(x) => x,
+        Err(x) => {
+            remove_file(output).ok();
+            panic!("{}", x)
+        }
+    };
+
+    assert!(png.raw.aux_headers.contains_key(b"caBX"));
+
+    remove_file(output).ok();
+}
+
+#[test]
+fn trailing_data_keep() {
+    let input = PathBuf::from("tests/files/trailing_data.png");
+    let (output, mut opts) = get_opts(&input);
+    opts.trailing_data = TrailingData::Keep;
+
+    let trailing = b"trailing payload";
+    let png = PngData::new_with_options(&input, &opts).unwrap();
+    assert_eq!(png.trailing_data.len(), 38);
+    assert!(png.trailing_data.ends_with(trailing));
+
+    match oxipng::optimize(&InFile::Path(input), &output, &opts) {
+        Ok(_) => (),
+        Err(x) => panic!("{}", x),
+    };
+    let output = output.path().unwrap();
+    assert!(output.exists());
+
+    let data = std::fs::read(output).unwrap();
+    remove_file(output).ok();
+
+    assert!(data.ends_with(trailing));
+}
+
+#[test]
+fn trailing_data_strip() {
+    let input = PathBuf::from("tests/files/trailing_data.png");
+    let (output, opts) = get_opts(&input);
+
+    let png = PngData::new_with_options(&input, &opts).unwrap();
+    assert!(png.trailing_data.is_empty());
+
+    match oxipng::optimize(&InFile::Path(input), &output, &opts) {
+        Ok(_) => (),
+        Err(x) => panic!("{}", x),
+    };
+    let output = output.path().unwrap();
+    assert!(output.exists());
+
+    let data = std::fs::read(output).unwrap();
+    remove_file(output).ok();
+
+    assert!(data.ends_with(b"IEND\xAE\x42\x60\x82"));
+}
+
+#[test]
+fn trailing_data_error() {
+    let input = PathBuf::from("tests/files/trailing_data.png");
+    let (output, mut opts) = get_opts(&input);
+    opts.trailing_data = TrailingData::Error;
+
+    match oxipng::optimize(&InFile::Path(input), &output, &opts) {
+        Err(PngError::TrailingData(38)) => (),
+        Err(x) => panic!("{}", x),
+        Ok(_) => panic!("trailing data was not rejected"),
+    };
+    assert!(!output.path().unwrap().exists());
+}
+
+#[test]
+fn sidecar_round_trip() {
+    let input = PathBuf::from("tests/files/sidecar.png");
+    let (output, mut opts) = get_opts(&input);
+    opts.strip = Headers::Safe;
+    opts.sidecar = true;
+
+    let original = PngData::new(&input, opts.fix_errors).unwrap();
+
+    assert_eq!(original.raw.aux_headers.len(), 6);
+
+    match oxipng::optimize(&InFile::Path(input), &output, &opts) {
+        Ok(_) => (),
+        Err(x) => panic!("{}", x),
+    };
+    let output = output.path().unwrap();
+    let sidecar = oxipng::sidecar_path(output);
+    assert!(output.exists());
+    assert!(sidecar.exists());
+
+    let png = PngData::new(output, opts.fix_errors).unwrap();
+    assert!(!png.raw.aux_headers.contains_key(b"tEXt"));
+    assert!(png.raw.aux_headers.contains_key(b"pHYs"));
+
+    let restored = oxipng::restore_metadata(
+        &InFile::Path(output.to_owned()),
+        &OutFile::Path(None),
+        &opts.limits,
+    );
+    let png = PngData::new(output, opts.fix_errors);
+    remove_file(output).ok();
+    remove_file(sidecar).ok();
+    if let Err(x) = restored {
+        panic!("{}", x);
+    }
+
+    assert_eq!(png.unwrap().raw.aux_headers, original.raw.aux_headers);
+}
+
+#[test]
+fn sidecar_converted_headers() {
+    let dir = TempDir::new("sidecar-converted");
+    // Raw profiles converted to native chunks and a stripped C2PA manifest are restored too
+    for name in ["raw_profiles.png", "c2pa_manifest.png"] {
+        let input = Path::new("tests/files").join(name);
+        let output = dir.join(name);
+        let (_, mut opts) = get_opts(&input);
+        opts.convert_raw_profiles = true;
+        opts.c2pa = C2PAPolicy::Strip;
+        opts.sidecar = true;
+
+        let original = PngData::new(&input, opts.fix_errors).unwrap();
+        oxipng::optimize(
+            &InFile::Path(input),
+            &OutFile::Path(Some(output.clone())),
+            &opts,
+        )
+        .unwrap();
+        let png = PngData::new(&output, opts.fix_errors).unwrap();
+        assert_ne!(png.raw.aux_headers, original.raw.aux_headers, "{}", name);
+
+        oxipng::restore_metadata(
+            &InFile::Path(output.clone()),
+            &OutFile::Path(None),
+            &opts.limits,
+        )
+        .unwrap();
+        let png = PngData::new(&output, opts.fix_errors).unwrap();
+        assert_eq!(png.raw.aux_headers, original.raw.aux_headers, "{}", name);
+    }
+}
+
+#[test]
+fn sidecar_limits() {
+    let input = PathBuf::from("tests/files/sidecar.png");
+    let (output, mut opts) = get_opts(&input);
+    opts.strip = Headers::Safe;
+    opts.sidecar = true;
+
+    oxipng::optimize(&InFile::Path(input), &output, &opts).unwrap();
+    let output = output.path().unwrap();
+    let sidecar = oxipng::sidecar_path(output);
+    let data = fs::read(output);
+    let sidecar_data = fs::read(&sidecar);
+    remove_file(output).ok();
+    remove_file(sidecar).ok();
+
+    // The stored headers are checked against the limits, like those of the image
+    let limits = Limits {
+        max_chunk_size: Some(12),
+        ..Limits::default()
+    };
+    let restored =
+        oxipng::restore_metadata_from_memory(&data.unwrap(), &sidecar_data.unwrap(), &limits);
+    assert!(matches!(
+        restored,
+        Err(PngError::ChunkSizeLimitExceeded(..))
+    ));
+}
+
+#[test]
+fn convert_raw_profiles() {
+    let input = PathBuf::from("tests/files/raw_profiles.png");
+    let (output, mut opts) = get_opts(&input);
+    opts.convert_raw_profiles = true;
+
+    match oxipng::optimize(&InFile::Path(input), &output, &opts) {
+        Ok(_) => (),
+        Err(x) => panic!("{}", x),
+    };
+    let output = output.path().unwrap();
+    assert!(output.exists());
+
+    let png = match PngData::new(output, opts.fix_errors) {
+        Ok(x) => x,
+        Err(x) => {
+            remove_file(output).ok();
+            panic!("{}", x)
+        }
+    };
+    remove_file(output).ok();
+
+    let headers = &png.raw.aux_headers;
+    assert!(!headers.contains_key(b"zTXt"));
+    assert!(headers.get(b"eXIf").unwrap().starts_with(b"MM\0*"));
+    assert!(headers
+        .get(b"iTXt")
+        .unwrap()
+        .starts_with(b"XML:com.adobe.xmp\0\0\0\0\0<x:xmpmeta"));
+    assert!(headers.contains_key(b"iCCP"));
+    // Profiles without a native chunk are kept as they are
+    assert!(headers
+        .get(b"tEXt")
+        .unwrap()
+        .starts_with(b"Raw profile type 8bim\0"));
+}
+
+#[test]
+fn convert_raw_profiles_native_exists() {
+    let input = PathBuf::from("tests/files/raw_profiles_native.png");
+    let (output, mut opts) = get_opts(&input);
+    opts.convert_raw_profiles = true;
+
+    match oxipng::optimize(&InFile::Path(input), &output, &opts) {
+        Ok(_) => (),
+        Err(x) => panic!("{}", x),
+    };
+    let output = output.path().unwrap();
+    assert!(output.exists());
+
+    let png = match PngData::new(output, opts.fix_errors) {
+        Ok(x) => x,
+        Err(x) => {
+            remove_file(output).ok();
+            panic!("{}", x)
+        }
+    };
+    remove_file(output).ok();
+
+    assert!(!png.raw.aux_headers.contains_key(b"zTXt"));
+    assert_eq!(
+        png.raw.aux_headers.get(b"eXIf").unwrap(),
+        b"II*\0\x08\0\0\0\0\0"
+    );
+}
+
+#[test]
+fn convert_raw_profiles_limits() {
+    // The profile inflates to over 1 MiB of text
+    let input = PathBuf::from("tests/files/raw_profile_bomb.png");
+    let (output, mut opts) = get_opts(&input);
+    opts.convert_raw_profiles = true;
+    opts.limits.max_decoded_bytes = Some(64 * 1024);
+
+    match oxipng::optimize(&InFile::Path(input.clone()), &output, &opts) {
+        Ok(_) => (),
+        Err(x) => panic!("{}", x),
+    };
+    let output = output.path().unwrap();
+    let limited = PngData::new(output, opts.fix_errors);
+    opts.limits.max_decoded_bytes = None;
+    let result = oxipng::optimize(
+        &InFile::Path(input),
+        &OutFile::Path(Some(output.into())),
+        &opts,
+    );
+    let unlimited = PngData::new(output, opts.fix_errors);
+    remove_file(output).ok();
+
+    // Left as it is when it inflates to more than the limit
+    let limited = limited.unwrap();
+    assert!(limited.raw.aux_headers.contains_key(b"zTXt"));
+    assert!(!limited.raw.aux_headers.contains_key(b"eXIf"));
+    result.unwrap();
+    assert_eq!(
+        unlimited.unwrap().raw.aux_headers.get(b"eXIf").unwrap(),
+        b"MM\0*\0\0\0\x08"
+    );
+}
+
+#[test]
+fn verify_strict() {
+    let input = PathBuf::from("tests/files/rgba_16_should_be_rgba_8.png");
+    let (output, mut opts) = get_opts(&input);
+    opts.verify = VerifyMode::Strict;
+
+    test_it_converts(
+        input,
+        &output,
+        &opts,
+        ColorType::RGBA,
+        BitDepth::Sixteen,
+        ColorType::RGBA,
+        BitDepth::Eight,
+    );
+}
+
+#[test]
+fn verify_strict_optimize_alpha() {
+    let input = PathBuf::from("tests/files/grayscale_alpha_16_should_be_grayscale_trns_16.png");
+    let (output, mut opts) = get_opts(&input);
+    opts.optimize_alpha = true;
+    opts.verify = VerifyMode::Strict;
+
+    test_it_converts(
+        input,
+        &output,
+        &opts,
+        ColorType::GrayscaleAlpha,
+        BitDepth::Sixteen,
+        ColorType::Grayscale,
+        BitDepth::Sixteen,
+    );
+}
+
 #[test]
 #[cfg(feature = "zopfli")]
 fn zopfli_mode() {
@@ -597,3 +1320,222 @@ fn zopfli_mode() {
         BitDepth::Eight,
     );
 }
+
+#[test]
+fn atomic_output() {
+    let input = PathBuf::from("tests/files/fully_optimized.png");
+    let dir = TempDir::new("atomic");
+    let output_path = dir.join("output.png");
+    fs::write(&output_path, b"previous contents").unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&output_path, fs::Permissions::from_mode(0o600)).unwrap();
+    }
+    let link_path = dir.join("link.png");
+    #[cfg(unix)]
+    std::os::unix::fs::symlink("output.png", &link_path).unwrap();
+    #[cfg(not(unix))]
+    let link_path = output_path.clone();
+
+    let (_, opts) = get_opts(&input);
+    let result = oxipng::optimize(
+        &InFile::Path(input),
+        &OutFile::Path(Some(link_path.clone())),
+        &opts,
+    );
+
+    let entries: Vec<_> = fs::read_dir(&*dir)
+        .unwrap()
+        .map(|e| e.unwrap().file_name())
+        .collect();
+    let output = fs::read(&output_path).unwrap();
+    let link_meta = fs::symlink_metadata(&link_path).unwrap();
+    let output_meta = fs::metadata(&output_path).unwrap();
+
+    result.unwrap();
+    // The temporary file has been renamed over the symlink target
+    assert!(output.starts_with(b"\x89PNG"));
+    assert_eq!(entries.len(), if cfg!(unix) { 2 } else { 1 });
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        assert!(link_meta.file_type().is_symlink());
+        assert_eq!(output_meta.permissions().mode() & 0o777, 0o600);
+    }
+    #[cfg(not(unix))]
+    let _ = (link_meta, output_meta);
+}
+
+#[test]
+fn backup_without_extension() {
+    let dir = TempDir::new("backup-ext");
+    let path = dir.copy("rgba_8_should_be_rgba_8.png", "image");
+    let original = fs::read(&path).unwrap();
+
+    let (_, mut opts) = get_opts(&path);
+    opts.backup = true;
+    let result = oxipng::optimize(&InFile::Path(path.clone()), &OutFile::Path(None), &opts);
+    let backup = fs::read(dir.join("image.bak"));
+
+    result.unwrap();
+    assert_eq!(backup.unwrap(), original);
+}
+
+#[test]
+fn backup_keep_and_restore() {
+    let dir = TempDir::new("backup-keep");
+    let path = dir.copy("rgba_8_should_be_rgba_8.png", "image.png");
+    let original = fs::read(&path).unwrap();
+    let input = InFile::Path(path.clone());
+
+    let (_, mut opts) = get_opts(&path);
+    let backup = Backup {
+        suffix: ".orig".to_owned(),
+        keep: 2,
+        ..Backup::default()
+    };
+    opts.backup = true;
+    opts.backup_config = backup.clone();
+    oxipng::optimize(&input, &OutFile::Path(None), &opts).unwrap();
+    let optimized = fs::read(&path).unwrap();
+    oxipng::optimize(&input, &OutFile::Path(None), &opts).unwrap();
+    let newest = fs::read(dir.join("image.png.orig"));
+    let oldest = fs::read(dir.join("image.png.orig.1"));
+
+    // Each restore goes back one step
+    let first = oxipng::restore_backup(&input, &backup).map(|_| fs::read(&path).unwrap());
+    let second = oxipng::restore_backup(&input, &backup).map(|_| fs::read(&path).unwrap());
+    let third = oxipng::restore_backup(&input, &backup);
+    let entries = fs::read_dir(&*dir).unwrap().count();
+
+    assert_eq!(newest.unwrap(), optimized);
+    assert_eq!(oldest.unwrap(), original);
+    assert_eq!(first.unwrap(), optimized);
+    assert_eq!(second.unwrap(), original);
+    assert!(third.is_err());
+    assert_eq!(entries, 1);
+}
+
+#[test]
+fn restore_damaged_backup() {
+    let dir = TempDir::new("backup-damaged");
+    let path = dir.join("image.png");
+    fs::write(&path, b"current").unwrap();
+    let mut damaged = fs::read("tests/files/rgba_8_should_be_rgba_8.png").unwrap();
+    let last = damaged.len() - 20;
+    damaged[last] ^= 0xFF;
+    fs::write(dir.join("image.png.bak"), &damaged).unwrap();
+
+    let result = oxipng::restore_backup(&InFile::Path(path.clone()), &Backup::default());
+    let current = fs::read(&path).unwrap();
+    let backup_left = dir.join("image.png.bak").exists();
+
+    match result {
+        Err(PngError::BackupDamaged { ref source, .. }) => {
+            assert_eq!(source.kind(), "CrcMismatch");
+        }
+        other => panic!("expected a damaged backup, got {:?}", other.map(|_| ())),
+    }
+    assert_eq!(current, b"current");
+    assert!(backup_left);
+}
+
+#[test]
+fn restore_checks_checksum() {
+    let dir = TempDir::new("backup-checksum");
+    let path = dir.copy("rgba_8_should_be_rgba_8.png", "image.png");
+    let input = InFile::Path(path.clone());
+
+    let (_, mut opts) = get_opts(&path);
+    opts.backup = true;
+    oxipng::optimize(&input, &OutFile::Path(None), &opts).unwrap();
+    let checksum = dir.join("image.png.bak.crc32");
+    let written = fs::read_to_string(&checksum);
+    // A backup replaced by another valid PNG still fails the checksum
+    fs::copy("tests/files/fully_optimized.png", dir.join("image.png.bak")).unwrap();
+    let result = oxipng::restore_backup(&input, &Backup::default());
+
+    assert_eq!(written.unwrap().trim().len(), 8);
+    match result {
+        Err(e) => assert_eq!(e.kind(), "BackupChecksumMismatch"),
+        Ok(()) => panic!("restored a backup that does not match its checksum"),
+    }
+}
+
+#[test]
+fn backup_dir_keeps_tree() {
+    let dir = TempDir::new("backup-dir");
+    let path = dir.copy("rgba_8_should_be_rgba_8.png", "images/image.png");
+
+    let (_, mut opts) = get_opts(&path);
+    let backup = Backup {
+        dir: Some(dir.join("backups")),
+        ..Backup::default()
+    };
+    opts.backup = true;
+    opts.backup_config = backup.clone();
+    let result = oxipng::optimize(&InFile::Path(path.clone()), &OutFile::Path(None), &opts);
+    let backup_path = oxipng::backup_path(&path, &backup).unwrap();
+    let exists = backup_path.exists();
+
+    result.unwrap();
+    assert!(exists);
+    // The temporary directory is outside of the current directory, so its full path is kept
+    assert!(backup_path.starts_with(dir.join("backups")));
+    assert!(backup_path.ends_with("images/image.png.bak"));
+}
+
+#[cfg(unix)]
+#[test]
+fn hardlinks_write_through() {
+    let dir = TempDir::new("hardlinks");
+    let path = dir.copy("rgba_8_should_be_rgba_8.png", "image.png");
+    let link = dir.join("link.png");
+    let skipped = dir.copy("rgba_8_should_be_rgba_8.png", "skipped.png");
+    fs::hard_link(&path, &link).unwrap();
+    fs::hard_link(&skipped, dir.join("skipped_link.png")).unwrap();
+    let original = fs::read(&path).unwrap();
+
+    // Writing through is the default
+    let mut opts = oxipng::Options::default();
+    let result = oxipng::optimize(&InFile::Path(path.clone()), &OutFile::Path(None), &opts);
+    opts.hardlinks = LinkPolicy::Skip;
+    let skip_result =
+        oxipng::optimize_with_outcome(&InFile::Path(skipped.clone()), &OutFile::Path(None), &opts);
+    let optimized = fs::read(&path).unwrap();
+    let linked = fs::read(&link).unwrap();
+    let untouched = fs::read(&skipped).unwrap();
+
+    result.unwrap();
+    assert_eq!(skip_result.unwrap(), Outcome::Skipped);
+    // Both links still share the optimized file
+    assert!(optimized.len() < original.len());
+    assert_eq!(linked, optimized);
+    assert_eq!(untouched, original);
+}
+
+#[cfg(unix)]
+#[test]
+fn symlinks_replace() {
+    let dir = TempDir::new("symlinks");
+    let target = dir.copy("rgba_8_should_be_rgba_8.png", "target.png");
+    let link = dir.join("link.png");
+    std::os::unix::fs::symlink("target.png", &link).unwrap();
+    let original = fs::read(&target).unwrap();
+
+    let opts = oxipng::Options {
+        symlinks: LinkPolicy::Replace,
+        ..Default::default()
+    };
+    let result = oxipng::optimize(&InFile::Path(link.clone()), &OutFile::Path(None), &opts);
+    let link_meta = fs::symlink_metadata(&link).unwrap();
+    let optimized = fs::read(&link).unwrap();
+    let untouched = fs::read(&target).unwrap();
+
+    result.unwrap();
+    // The link is now a file of its own, and the file it pointed to is left alone
+    assert!(link_meta.file_type().is_file());
+    assert!(optimized.len() < original.len());
+    assert_eq!(untouched, original);
+}
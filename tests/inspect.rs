@@ -0,0 +1,115 @@
+use oxipng::internal_tests::ColorType;
+use oxipng::{inspect, inspect_file, BlockType, InFile, Inspection, Limits};
+use std::path::PathBuf;
+
+fn inspect_fixture(name: &str) -> Inspection {
+    let input = InFile::Path(PathBuf::from("tests/files").join(name));
+    inspect_file(&input, &Limits::default()).unwrap()
+}
+
+#[test]
+fn inspect_chunks() {
+    let inspection = inspect_fixture("inspect_stored.png");
+
+    let chunks: Vec<_> = inspection
+        .chunks
+        .iter()
+        .map(|c| (c.name, c.offset, c.length, c.crc_valid))
+        .collect();
+    assert_eq!(
+        chunks,
+        vec![
+            (*b"IHDR", 8, 13, true),
+            (*b"tRNS", 33, 2, true),
+            (*b"IDAT", 47, 101, true),
+            (*b"IEND", 160, 0, true),
+        ]
+    );
+    assert_eq!(inspection.trailing_data, 3);
+    assert!(inspection.errors.is_empty());
+
+    let ihdr = inspection.ihdr.unwrap();
+    assert_eq!((ihdr.width, ihdr.height), (8, 10));
+    assert_eq!(ihdr.color_type, ColorType::Grayscale);
+    assert_eq!(inspection.transparency_pixel, Some(vec![0, 7]));
+}
+
+#[test]
+fn inspect_row_filters() {
+    let inspection = inspect_fixture("inspect_stored.png");
+
+    assert_eq!(inspection.row_filters, vec![0, 1, 2, 3, 4, 0, 1, 2, 3, 4]);
+    let text = inspection.to_string();
+    assert!(text.contains("Row filters: 10 rows"));
+    assert!(text
+        .lines()
+        .any(|l| l.trim_start().starts_with("Paeth") && l.ends_with(" 2   20.0%")));
+}
+
+#[test]
+fn inspect_deflate_blocks() {
+    let inspection = inspect_fixture("inspect_stored.png");
+    let header = inspection.zlib_header.unwrap();
+    assert_eq!(header.method, 8);
+    assert_eq!(header.window_size(), 32768);
+    assert!(header.check_valid);
+    assert_eq!(inspection.deflate_blocks.len(), 1);
+    assert_eq!(inspection.deflate_blocks[0].block_type, BlockType::Stored);
+    assert_eq!(inspection.deflate_blocks[0].uncompressed_size, 90);
+
+    let inspection = inspect_fixture("lint_palette.png");
+    assert_eq!(inspection.deflate_blocks.len(), 1);
+    assert_eq!(inspection.deflate_blocks[0].block_type, BlockType::Fixed);
+    assert_eq!(inspection.palette.unwrap().len(), 4);
+
+    let inspection = inspect_fixture("rgb_16_should_be_grayscale_8.png");
+    let blocks = &inspection.deflate_blocks;
+    assert!(blocks.iter().all(|b| b.block_type == BlockType::Dynamic));
+    assert!(blocks.last().unwrap().is_final);
+    // The blocks account for the whole image data, and the whole stream apart from
+    // the zlib header and checksum
+    let uncompressed: usize = blocks.iter().map(|b| b.uncompressed_size).sum();
+    assert_eq!(uncompressed, 400 * (500 * 6 + 1));
+    let compressed: usize = blocks.iter().map(|b| b.compressed_bits).sum();
+    let idat = inspection
+        .chunks
+        .iter()
+        .find(|c| &c.name == b"IDAT")
+        .unwrap();
+    assert_eq!((compressed + 7) / 8, idat.length - 6);
+}
+
+#[test]
+fn inspect_damaged() {
+    let inspection = inspect_fixture("fix_errors.png");
+    assert!(inspection.chunks.iter().any(|c| !c.crc_valid));
+
+    // The image data is read as it is, without recovering what is left of it
+    let inspection = inspect_fixture("fix_errors_truncated.png");
+    assert_eq!(inspection.chunks.len(), 2);
+    assert_eq!(inspection.errors.len(), 3);
+    assert!(inspection.errors[2].starts_with("Image data: "));
+    assert!(inspection.row_filters.is_empty());
+    assert!(inspection.ihdr.is_some());
+
+    let inspection = inspect(b"not a png", &Limits::default());
+    assert!(inspection.chunks.is_empty());
+    assert_eq!(inspection.errors, vec!["Not a PNG file".to_owned()]);
+}
+
+#[test]
+fn inspect_limits() {
+    let input = InFile::Path(PathBuf::from("tests/files/decompression_bomb.png"));
+    let limits = Limits {
+        max_memory: Some(1_000_000),
+        ..Limits::default()
+    };
+    let inspection = inspect_file(&input, &limits).unwrap();
+
+    // The chunks are still listed, only the image data is not decoded
+    assert!(inspection.ihdr.is_some());
+    assert!(inspection.row_filters.is_empty());
+    let error = inspection.errors.last().unwrap();
+    assert!(error.starts_with("Image data: "), "{}", error);
+    assert!(error.contains("memory"), "{}", error);
+}
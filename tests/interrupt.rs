@@ -0,0 +1,28 @@
+use oxipng::{InFile, OutFile, PngError};
+use std::fs;
+use std::path::PathBuf;
+
+// Interrupting is global, so this has its own test binary
+#[test]
+fn interrupted() {
+    let input = PathBuf::from("tests/files/fully_optimized.png");
+    let output = input.with_extension("interrupted.png");
+    let opts = oxipng::Options {
+        force: true,
+        ..Default::default()
+    };
+
+    oxipng::interrupt();
+    assert!(oxipng::is_interrupted());
+    let result = oxipng::optimize(
+        &InFile::Path(input),
+        &OutFile::Path(Some(output.clone())),
+        &opts,
+    );
+    assert!(matches!(result, Err(PngError::Interrupted)));
+    assert!(!output.exists());
+
+    let data = fs::read("tests/files/fully_optimized.png").unwrap();
+    let result = oxipng::optimize_from_memory(&data, &opts);
+    assert!(matches!(result, Err(PngError::Interrupted)));
+}
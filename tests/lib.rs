@@ -1,9 +1,13 @@
-use oxipng::Headers;
 use oxipng::OutFile;
+use oxipng::PngError;
+use oxipng::{
+    indexset, Deflaters, Headers, MinSavings, Options, OptionsBuilder, OptionsError, RowFilter,
+};
 use std::default::Default;
 use std::fs;
 use std::fs::File;
 use std::io::prelude::*;
+use std::path::Path;
 
 #[test]
 fn optimize_from_memory() {
@@ -41,6 +45,68 @@ fn optimize_from_memory_apng() {
     assert!(result.is_err());
 }
 
+#[test]
+fn optimize_from_memory_c2pa() {
+    let mut in_file = File::open("tests/files/c2pa_manifest.png").unwrap();
+    let mut in_file_buf: Vec<u8> = Vec::new();
+    in_file.read_to_end(&mut in_file_buf).unwrap();
+
+    let opts: oxipng::Options = Default::default();
+
+    let result = oxipng::optimize_from_memory(&in_file_buf, &opts);
+    assert_eq!(result.unwrap(), in_file_buf);
+}
+
+#[test]
+fn optimize_from_memory_timeout() {
+    let in_file_buf = fs::read("tests/files/rgb_16_should_be_rgb_8.png").unwrap();
+
+    // Nothing is evaluated before the deadline, so the image is only reduced
+    let opts = oxipng::Options {
+        timeout: Some(std::time::Duration::ZERO),
+        ..Default::default()
+    };
+
+    let result = oxipng::optimize_from_memory(&in_file_buf, &opts);
+    assert!(result.is_ok());
+}
+
+#[test]
+fn optimize_from_memory_limits() {
+    let mut in_file = File::open("tests/files/decompression_bomb.png").unwrap();
+    let mut in_file_buf: Vec<u8> = Vec::new();
+    in_file.read_to_end(&mut in_file_buf).unwrap();
+
+    let mut opts: oxipng::Options = Default::default();
+    opts.limits.max_pixels = Some(1 << 24);
+    let result = oxipng::optimize_from_memory(&in_file_buf, &opts);
+    assert!(matches!(
+        result,
+        Err(PngError::PixelLimitExceeded(4_294_836_225))
+    ));
+
+    let mut opts: oxipng::Options = Default::default();
+    opts.limits.max_decoded_bytes = Some(1 << 26);
+    let result = oxipng::optimize_from_memory(&in_file_buf, &opts);
+    assert!(matches!(
+        result,
+        Err(PngError::DecodedSizeLimitExceeded(17_179_410_435))
+    ));
+
+    let mut opts: oxipng::Options = Default::default();
+    opts.limits.max_memory = Some(1 << 28);
+    let result = oxipng::optimize_from_memory(&in_file_buf, &opts);
+    assert!(matches!(result, Err(PngError::MemoryLimitExceeded(_))));
+
+    let mut opts: oxipng::Options = Default::default();
+    opts.limits.max_chunk_size = Some(1 << 10);
+    let result = oxipng::optimize_from_memory(&in_file_buf, &opts);
+    assert!(matches!(
+        result,
+        Err(PngError::ChunkSizeLimitExceeded(name, _)) if &name == b"IDAT"
+    ));
+}
+
 #[test]
 fn optimize() {
     let opts: oxipng::Options = Default::default();
@@ -65,6 +131,121 @@ fn optimize_corrupted() {
     assert!(result.is_err());
 }
 
+#[test]
+fn optimize_errors() {
+    let opts: oxipng::Options = Default::default();
+
+    let result = oxipng::optimize(
+        &"tests/files/does_not_exist.png".into(),
+        &OutFile::Path(None),
+        &opts,
+    );
+    match result {
+        Err(ref e @ PngError::Io { ref path, .. }) => {
+            assert_eq!(e.kind(), "Io");
+            assert_eq!(
+                path.as_deref(),
+                Some(Path::new("tests/files/does_not_exist.png"))
+            );
+            let source = std::error::Error::source(e).unwrap();
+            let source = source.downcast_ref::<std::io::Error>().unwrap();
+            assert_eq!(source.kind(), std::io::ErrorKind::NotFound);
+        }
+        _ => panic!("Expected an I/O error, got {:?}", result),
+    }
+
+    let result = oxipng::optimize(
+        &"tests/files/fix_errors.png".into(),
+        &OutFile::Path(None),
+        &opts,
+    );
+    match result {
+        Err(PngError::CrcMismatch { chunk, offset }) => {
+            assert_eq!(&chunk, b"zTXt");
+            assert!(offset > 8);
+        }
+        _ => panic!("Expected a CRC mismatch, got {:?}", result),
+    }
+}
+
+#[test]
+fn options_builder() {
+    let opts = OptionsBuilder::new()
+        .compression(9)
+        .preset(4)
+        .build()
+        .unwrap();
+    // The explicit compression level overrides the preset, the filters come from it
+    assert_eq!(opts.deflate, Deflaters::Libdeflater { compression: 9 });
+    assert_eq!(opts.filter, Options::from_preset(4).filter);
+    assert!(!opts.fast_evaluation);
+
+    let opts = OptionsBuilder::new()
+        .filters([RowFilter::Paeth])
+        .max_compression()
+        .reductions(false)
+        .build()
+        .unwrap();
+    assert_eq!(opts.filter, indexset! {RowFilter::Paeth});
+    assert_eq!(opts.deflate, Deflaters::Libdeflater { compression: 12 });
+    assert!(!opts.palette_reduction && !opts.bit_depth_reduction);
+
+    let result = OptionsBuilder::new().preset(7).build();
+    assert_eq!(result.unwrap_err(), OptionsError::PresetLevel(7));
+    let result = OptionsBuilder::new().compression(13).build();
+    assert_eq!(result.unwrap_err(), OptionsError::CompressionLevel(13));
+    let result = OptionsBuilder::new().preset(5).filters([]).build();
+    assert_eq!(result.unwrap_err(), OptionsError::EmptyFilterSet);
+    assert!(OptionsBuilder::new().preset(0).build().is_ok());
+    let result = OptionsBuilder::new().verify_fallback(true).build();
+    assert_eq!(
+        result.unwrap_err(),
+        OptionsError::VerifyFallbackWithoutVerify
+    );
+    let result = OptionsBuilder::new()
+        .min_savings(Some(MinSavings::Percent(101.0)))
+        .build();
+    assert_eq!(result.unwrap_err(), OptionsError::MinSavingsPercent);
+}
+
+#[test]
+fn optimize_from_memory_min_savings() {
+    let in_file_buf = fs::read("tests/files/rgba_8_should_be_rgba_8.png").unwrap();
+
+    let mut opts = Options {
+        min_savings: Some(MinSavings::Bytes(1)),
+        ..Default::default()
+    };
+    let result = oxipng::optimize_from_memory(&in_file_buf, &opts).unwrap();
+    assert!(result.len() < in_file_buf.len());
+
+    opts.min_savings = Some(MinSavings::Bytes(in_file_buf.len() as u64));
+    let result = oxipng::optimize_from_memory(&in_file_buf, &opts).unwrap();
+    assert_eq!(result, in_file_buf);
+
+    opts.min_savings = Some(MinSavings::Percent(50.0));
+    let result = oxipng::optimize_from_memory(&in_file_buf, &opts).unwrap();
+    assert_eq!(result, in_file_buf);
+}
+
+#[test]
+fn optimize_invalid_options() {
+    let opts = Options {
+        deflate: Deflaters::Libdeflater { compression: 0 },
+        ..Default::default()
+    };
+
+    let result = oxipng::optimize(
+        &"tests/files/fully_optimized.png".into(),
+        &OutFile::Path(None),
+        &opts,
+    );
+    assert!(matches!(
+        result,
+        Err(PngError::InvalidOptions(OptionsError::CompressionLevel(0)))
+    ));
+}
+
 #[test]
 fn optimize_apng() {
     let opts: oxipng::Options = Default::default();
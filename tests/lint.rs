@@ -0,0 +1,127 @@
+use oxipng::{lint, lint_file, Diagnostic, InFile, Limits, Severity};
+use std::fs;
+use std::path::PathBuf;
+
+fn lint_fixture(name: &str) -> Vec<Diagnostic> {
+    let input = InFile::Path(PathBuf::from("tests/files").join(name));
+    lint_file(&input, &Limits::default()).unwrap()
+}
+
+fn codes(diagnostics: &[Diagnostic], severity: Severity) -> Vec<&'static str> {
+    diagnostics
+        .iter()
+        .filter(|d| d.severity == severity)
+        .map(|d| d.code)
+        .collect()
+}
+
+#[test]
+fn lint_spec_violations() {
+    let diagnostics = lint_fixture("lint_violations.png");
+
+    assert_eq!(
+        codes(&diagnostics, Severity::Error),
+        vec!["ihdr-filter-method", "unexpected-chunk"]
+    );
+    assert_eq!(
+        codes(&diagnostics, Severity::Warning),
+        vec![
+            "chunk-order",
+            "chunk-order",
+            "duplicate-chunk",
+            "text-keyword"
+        ]
+    );
+    let trns = diagnostics
+        .iter()
+        .find(|d| d.code == "unexpected-chunk")
+        .unwrap();
+    assert_eq!(trns.chunk, Some(*b"tRNS"));
+}
+
+#[test]
+fn lint_optimizations() {
+    let diagnostics = lint_fixture("lint_palette.png");
+
+    assert_eq!(
+        codes(&diagnostics, Severity::Info),
+        vec!["reducible-color-type", "unused-palette-entries"]
+    );
+    assert!(codes(&diagnostics, Severity::Error).is_empty());
+    assert!(codes(&diagnostics, Severity::Warning).is_empty());
+}
+
+#[test]
+fn lint_strippable_chunks() {
+    let diagnostics = lint_fixture("sidecar.png");
+
+    let chunks: Vec<_> = diagnostics
+        .iter()
+        .filter(|d| d.code == "strippable-chunk")
+        .map(|d| d.chunk.unwrap())
+        .collect();
+    assert_eq!(
+        chunks,
+        vec![*b"gAMA", *b"tEXt", *b"tEXt", *b"tIME", *b"iTXt"]
+    );
+}
+
+#[test]
+fn lint_damaged_files() {
+    let diagnostics = lint_fixture("fix_errors.png");
+    assert_eq!(codes(&diagnostics, Severity::Error), vec!["crc-mismatch"]);
+
+    let diagnostics = lint_fixture("fix_errors_truncated.png");
+    assert!(codes(&diagnostics, Severity::Error).contains(&"truncated"));
+
+    let diagnostics = lint_fixture("fix_errors_palette.png");
+    assert_eq!(
+        codes(&diagnostics, Severity::Error),
+        vec!["chunk-order", "invalid-image-data"]
+    );
+
+    let diagnostics = lint(b"GIF89a", &Limits::default());
+    assert_eq!(codes(&diagnostics, Severity::Error), vec!["not-png"]);
+}
+
+#[test]
+fn lint_json() {
+    let data = fs::read("tests/files/lint_violations.png").unwrap();
+    let json = oxipng::diagnostics_to_json("dir/\"quoted\".png", &lint(&data, &Limits::default()));
+
+    assert!(json.starts_with(r#"{"file":"dir/\"quoted\".png","diagnostics":[{"#));
+    assert!(json.contains(
+        r#"{"severity":"warning","code":"text-keyword","chunk":"tEXt","message":"tEXt chunk keyword \" bad  key\" has leading or trailing spaces"}"#
+    ));
+    assert!(json.contains(r#""severity":"info","code":"reducible-color-type","chunk":null"#));
+    assert!(json.ends_with("}]}"));
+}
+
+#[test]
+fn lint_limits() {
+    let data = fs::read("tests/files/decompression_bomb.png").unwrap();
+    let limits = Limits {
+        max_memory: Some(1_000_000),
+        ..Limits::default()
+    };
+    let diagnostics = lint(&data, &limits);
+
+    let error = diagnostics
+        .iter()
+        .find(|d| d.code == "invalid-image-data")
+        .unwrap();
+    assert!(error.message.contains("memory"), "{}", error.message);
+}
+
+#[test]
+fn lint_palette_padding() {
+    // Both rows use entry 1 of 2, with the padding bits set to 3 in the first and 0 in the second
+    let diagnostics = lint_fixture("lint_palette_padding.png");
+
+    assert!(!diagnostics.iter().any(|d| d.code == "palette-index"));
+    let unused = diagnostics
+        .iter()
+        .find(|d| d.code == "unused-palette-entries")
+        .unwrap();
+    assert_eq!(unused.message, "1 of 2 palette entries are unused");
+}
@@ -706,6 +706,30 @@ fn grayscale_16_should_be_grayscale_8() {
     );
 }
 
+#[test]
+fn cicp_narrow_range_16_should_be_grayscale_16() {
+    test_it_converts(
+        "tests/files/cicp_narrow_range_16_should_be_grayscale_16.png",
+        false,
+        ColorType::Grayscale,
+        BitDepth::Sixteen,
+        ColorType::Grayscale,
+        BitDepth::Sixteen,
+    );
+}
+
+#[test]
+fn cicp_full_range_16_should_be_grayscale_8() {
+    test_it_converts(
+        "tests/files/cicp_full_range_16_should_be_grayscale_8.png",
+        false,
+        ColorType::Grayscale,
+        BitDepth::Sixteen,
+        ColorType::Grayscale,
+        BitDepth::Eight,
+    );
+}
+
 #[test]
 fn grayscale_8_should_be_grayscale_8() {
     test_it_converts(